@@ -33,6 +33,7 @@ use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
 
 use pstools::PSTool;
 
@@ -65,6 +66,39 @@ pub struct PinInstance {
     /// Index for the parent cell
     pub parent_net: usize,
     pub details: Vec<PinDetail>,
+    /// I, O, or B from the nets file
+    pub direction: PinDirection,
+}
+
+/// Pin directions, as given in the nets file.  Timing analysis needs
+/// them to find the driver of each net.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PinDirection {
+    Input,
+    Output,
+    Bidirectional,
+}
+
+impl fmt::Display for PinDirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PinDirection::Input => write!(f, "I"),
+            PinDirection::Output => write!(f, "O"),
+            PinDirection::Bidirectional => write!(f, "B"),
+        }
+    }
+}
+
+impl std::str::FromStr for PinDirection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<PinDirection, String> {
+        match s.to_uppercase().as_str() {
+            "I" => Ok(PinDirection::Input),
+            "O" => Ok(PinDirection::Output),
+            "B" => Ok(PinDirection::Bidirectional),
+            _ => Err(format!("Unrecognized pin direction {}", s)),
+        }
+    }
 }
 
 /// PinDetail has the original (non-rotated/translated)
@@ -75,6 +109,8 @@ pub struct PinInstance {
 pub struct PinDetail {
     pub dx: f32,
     pub dy: f32,
+    /// Pin shape, if known, in the same (unrotated) coordinates
+    pub rect: Option<BBox>,
 }
 
 // PinRefs are in the vector for the nets
@@ -112,28 +148,133 @@ impl fmt::Display for Orientation {
     }
 }
 
+/// Orientations follow the LEF/DEF conventions: E and W are rotations
+/// by 90 degrees (clockwise and counterclockwise), S is 180 degrees, and
+/// the F versions are mirrored in X after the rotation.  Each one is a
+/// 2x2 matrix applied about the cell center; `transform` maps a point
+/// on the unrotated cell to the point on the oriented cell, with the
+/// lower left corner still at the origin.
 impl Orientation {
+    pub const ALL: [Orientation; 8] = [
+        Orientation::N,
+        Orientation::S,
+        Orientation::E,
+        Orientation::W,
+        Orientation::FN,
+        Orientation::FS,
+        Orientation::FE,
+        Orientation::FW,
+    ];
+
     pub fn new() -> Orientation {
         Orientation::N
     }
-    pub fn from_string(s: &String) -> Orientation {
+
+    pub fn from_string(s: &String) -> Result<Orientation, String> {
         match s.to_uppercase().as_str() {
-            "N" => return Orientation::N,
-            "S" => return Orientation::S,
-            "E" => return Orientation::E,
-            "W" => return Orientation::W,
-            "FN" => return Orientation::FN,
-            "FS" => return Orientation::FS,
-            "FE" => return Orientation::FE,
-            "FW" => return Orientation::FW,
-            _ => {
-                println!("Unrecognized orientation {}", s);
+            "N" => Ok(Orientation::N),
+            "S" => Ok(Orientation::S),
+            "E" => Ok(Orientation::E),
+            "W" => Ok(Orientation::W),
+            "FN" => Ok(Orientation::FN),
+            "FS" => Ok(Orientation::FS),
+            "FE" => Ok(Orientation::FE),
+            "FW" => Ok(Orientation::FW),
+            _ => Err(format!("Unrecognized orientation {}", s)),
+        }
+    }
+
+    /// Rows of the matrix taking (x, y) relative to the cell center to
+    /// the new (x, y)
+    pub fn matrix(&self) -> [[i32; 2]; 2] {
+        match self {
+            Orientation::N => [[1, 0], [0, 1]],
+            Orientation::S => [[-1, 0], [0, -1]],
+            Orientation::E => [[0, 1], [-1, 0]],
+            Orientation::W => [[0, -1], [1, 0]],
+            Orientation::FN => [[-1, 0], [0, 1]],
+            Orientation::FS => [[1, 0], [0, -1]],
+            Orientation::FE => [[0, -1], [-1, 0]],
+            Orientation::FW => [[0, 1], [1, 0]],
+        }
+    }
+
+    pub fn from_matrix(m: [[i32; 2]; 2]) -> Option<Orientation> {
+        Orientation::ALL.iter().find(|o| o.matrix() == m).cloned()
+    }
+
+    /// This orientation, followed by another
+    pub fn compose(&self, then: Orientation) -> Orientation {
+        let a = then.matrix();
+        let b = self.matrix();
+        let mut m = [[0; 2]; 2];
+        for i in 0..2 {
+            for j in 0..2 {
+                m[i][j] = a[i][0] * b[0][j] + a[i][1] * b[1][j];
             }
         }
-        Orientation::N
+        Orientation::from_matrix(m).unwrap()
+    }
+
+    pub fn inverse(&self) -> Orientation {
+        let m = self.matrix();
+        Orientation::from_matrix([[m[0][0], m[1][0]], [m[0][1], m[1][1]]]).unwrap()
+    }
+
+    /// Turned a further 90 degrees counterclockwise
+    pub fn rotate90(&self) -> Orientation {
+        self.compose(Orientation::W)
+    }
+
+    /// Mirrored in X (left to right)
+    pub fn flip_x(&self) -> Orientation {
+        self.compose(Orientation::FN)
+    }
+
+    /// Mirrored in Y (top to bottom)
+    pub fn flip_y(&self) -> Orientation {
+        self.compose(Orientation::FS)
+    }
+
+    /// True if the width and height trade places
+    pub fn swaps(&self) -> bool {
+        self.matrix()[0][0] == 0
+    }
+
+    pub fn is_flipped(&self) -> bool {
+        let m = self.matrix();
+        m[0][0] * m[1][1] - m[0][1] * m[1][0] < 0
+    }
+
+    /// Maps a point on an unrotated w by h cell to the oriented cell.
+    pub fn transform(&self, x: f32, y: f32, w: f32, h: f32) -> (f32, f32) {
+        let m = self.matrix();
+        let (u, v) = (x - w * 0.5, y - h * 0.5);
+        let (nw, nh) = if self.swaps() { (h, w) } else { (w, h) };
+        (
+            m[0][0] as f32 * u + m[0][1] as f32 * v + nw * 0.5,
+            m[1][0] as f32 * u + m[1][1] as f32 * v + nh * 0.5,
+        )
+    }
+
+    pub fn transform_rect(&self, r: &BBox, w: f32, h: f32) -> BBox {
+        let (x0, y0) = self.transform(r.llx, r.lly, w, h);
+        let (x1, y1) = self.transform(r.urx, r.ury, w, h);
+        let mut b = BBox::new();
+        b.addpoint(x0, y0);
+        b.addpoint(x1, y1);
+        b
     }
 }
 
+impl std::str::FromStr for Orientation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Orientation, String> {
+        Orientation::from_string(&s.to_string())
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct AltSize {
     pub w: f32,
     pub h: f32,
@@ -157,11 +298,169 @@ pub struct AltSize {
 /// of a block packing file using the bookshelf circuit
 /// structure, and then generating a variety of block
 /// sizes when the floor planner runs.
+#[derive(Clone)]
 pub struct SoftSize {
     pub soft: bool,
     pub min_aspect: f32,
     pub max_aspect: f32,
     pub alt_sizes: Vec<AltSize>,
+    /// Required area of the block
+    pub area: f32,
+    /// Index of the alternate size currently in use, if one was picked
+    pub chosen: Option<usize>,
+    /// Set once the block has been given a shape (by set_soft_shape or
+    /// the floorplanner); write_blocks then writes it as a hard block
+    pub shaped: bool,
+}
+
+impl SoftSize {
+    /// Generates up to count alternate shapes for a block of the given
+    /// area, with aspect ratios (height over width) spread geometrically
+    /// from min_aspect to max_aspect.  With rounding, widths and heights
+    /// are integers, and the height is rounded up so that the area is
+    /// never less than required.  The width is then stepped to bring the
+    /// aspect ratio back into range; shapes that cannot be brought into
+    /// range are dropped, as are duplicates.  If no rounded shape fits,
+    /// the block gets a single unrounded shape.
+    pub fn generate(area: f32, min_aspect: f32, max_aspect: f32, count: usize, round: bool) -> SoftSize {
+        let mut lo = min_aspect.min(max_aspect);
+        let mut hi = max_aspect.max(min_aspect);
+        if lo <= 0.0 {
+            lo = 1.0;
+            hi = hi.max(1.0);
+        }
+        let mut alt_sizes: Vec<AltSize> = Vec::new();
+        let count = count.max(1);
+        for i in 0..count {
+            let aspect;
+            if count == 1 || hi == lo {
+                aspect = (lo * hi).sqrt();
+            } else {
+                aspect = lo * (hi / lo).powf(i as f32 / (count - 1) as f32);
+            }
+            let mut w = (area / aspect).sqrt();
+            let mut h = area / w;
+            let mut fits = true;
+            if round {
+                w = w.round().max(1.0);
+                h = (area / w).ceil();
+                while h / w > hi {
+                    w = w + 1.0;
+                    h = (area / w).ceil();
+                }
+                while h / w < lo && w > 1.0 {
+                    w = w - 1.0;
+                    h = (area / w).ceil();
+                }
+                fits = h / w >= lo && h / w <= hi;
+            }
+            if fits && !alt_sizes.iter().any(|a| a.w == w && a.h == h) {
+                alt_sizes.push(AltSize { w: w, h: h });
+            }
+            if count == 1 || hi == lo {
+                break;
+            }
+        }
+        if alt_sizes.is_empty() {
+            let w = (area / (lo * hi).sqrt()).sqrt();
+            alt_sizes.push(AltSize { w: w, h: area / w });
+        }
+        SoftSize {
+            soft: true,
+            min_aspect: min_aspect,
+            max_aspect: max_aspect,
+            alt_sizes: alt_sizes,
+            area: area,
+            chosen: None,
+            shaped: false,
+        }
+    }
+}
+
+/// Outline of a hardrectilinear block that is not a simple rectangle.
+/// The corners and rectangles are relative to the lower left of the
+/// cell bounding box.  As with the cell width and height, the original
+/// versions are for the unrotated block, and orient_cell fills in the
+/// others for the current orientation.
+#[derive(Clone)]
+pub struct Rectilinear {
+    pub corners: Vec<(f32, f32)>,
+    pub rects: Vec<BBox>,
+    pub original_corners: Vec<(f32, f32)>,
+    pub original_rects: Vec<BBox>,
+}
+
+impl Rectilinear {
+    /// Builds the shape from the polygon corners (in order around the
+    /// outline), splitting it into rectangles.  The polygon is cut into
+    /// horizontal slabs at each corner Y, and the slabs are merged back
+    /// together where the X extent is the same.  Returns the shape, with
+    /// the width and height of the bounding box.
+    pub fn from_corners(points: &Vec<(f32, f32)>) -> (Rectilinear, f32, f32) {
+        let mut bounds = BBox::new();
+        for (x, y) in points {
+            bounds.addpoint(*x, *y);
+        }
+        let corners: Vec<(f32, f32)> = points
+            .iter()
+            .map(|(x, y)| (*x - bounds.llx, *y - bounds.lly))
+            .collect();
+
+        let mut ys: Vec<f32> = corners.iter().map(|c| c.1).collect();
+        ys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        ys.dedup();
+
+        let mut rects: Vec<BBox> = Vec::new();
+        let n = corners.len();
+        for s in 1..ys.len() {
+            let (y0, y1) = (ys[s - 1], ys[s]);
+            let mid = (y0 + y1) * 0.5;
+            // Vertical edges that cross the middle of the slab
+            let mut xs = Vec::new();
+            for i in 0..n {
+                let a = corners[i];
+                let b = corners[(i + 1) % n];
+                if a.0 == b.0 && a.1.min(b.1) < mid && a.1.max(b.1) > mid {
+                    xs.push(a.0);
+                }
+            }
+            xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for pair in xs.chunks(2) {
+                if pair.len() < 2 {
+                    continue;
+                }
+                let mut merged = false;
+                for r in &mut rects {
+                    if r.ury == y0 && r.llx == pair[0] && r.urx == pair[1] {
+                        r.ury = y1;
+                        merged = true;
+                        break;
+                    }
+                }
+                if !merged {
+                    let mut r = BBox::new();
+                    r.addpoint(pair[0], y0);
+                    r.addpoint(pair[1], y1);
+                    rects.push(r);
+                }
+            }
+        }
+
+        (
+            Rectilinear {
+                corners: corners.clone(),
+                rects: rects.clone(),
+                original_corners: corners,
+                original_rects: rects,
+            },
+            bounds.dx(),
+            bounds.dy(),
+        )
+    }
+
+    pub fn area(&self) -> f32 {
+        self.rects.iter().map(|r| r.area()).sum()
+    }
 }
 
 pub struct Cell {
@@ -185,10 +484,15 @@ pub struct Cell {
     pub is_macro: bool,
     pub is_soft: bool,
     pub can_rotate: bool,
+    /// Hardrectilinear blocks with more than four corners
+    pub shape: Option<Rectilinear>,
 }
 
 impl Cell {
     pub fn area(&self) -> f32 {
+        if let Some(shape) = &self.shape {
+            return shape.area();
+        }
         self.h * self.w
     }
 }
@@ -196,6 +500,8 @@ impl Cell {
 pub struct Net {
     pub name: String,
     pub pins: Vec<PinRef>,
+    /// From the .wts file; 1.0 if not given
+    pub weight: f32,
 }
 
 pub struct Macro {
@@ -211,6 +517,43 @@ pub struct Row {
     pub name: String,
     pub bounds: bbox::BBox,
     pub site_spacing: f32,
+    /// Siteorient and Sitesymmetry from the SCL file
+    pub orient: Orientation,
+    pub symmetry: String,
+    pub tier: usize,
+}
+
+impl Row {
+    /// Orientations allowed for cells in the row: the site orientation,
+    /// and whatever the symmetry adds (Y symmetry allows the mirror
+    /// image in X, so an N row takes N and FN, and an FS row takes FS
+    /// and S).
+    pub fn orientations(&self) -> Vec<Orientation> {
+        let mut allowed = vec![self.orient];
+        let sym = self.symmetry.to_uppercase();
+        let mut k = 0;
+        while k < allowed.len() {
+            let o = allowed[k];
+            let mut add = Vec::new();
+            if sym.contains('Y') {
+                add.push(o.flip_x());
+            }
+            if sym.contains('X') {
+                add.push(o.flip_y());
+            }
+            for a in add {
+                if !allowed.contains(&a) {
+                    allowed.push(a);
+                }
+            }
+            k += 1;
+        }
+        allowed
+    }
+
+    pub fn allows(&self, o: Orientation) -> bool {
+        self.orientations().contains(&o)
+    }
 }
 pub struct Testme {
     pub p: point::Point,
@@ -267,6 +610,11 @@ pub struct BookshelfCircuit {
     /// Easy access row height
     pub row_height: f32,
     pub is_blockpacking: bool,
+    /// Device tier of each cell, for 3D designs (see the tiers module)
+    pub tier: Option<Vec<usize>>,
+    pub num_tiers: usize,
+    /// Added to the wire length of a net for each tier it crosses
+    pub via_cost: f32,
 }
 
 pub struct Display {
@@ -364,6 +712,9 @@ impl BookshelfCircuit {
             num_terminals: 0,
             row_height: 0.0,
             is_blockpacking: false,
+            tier: None,
+            num_tiers: 1,
+            via_cost: 0.0,
         };
 
         bc
@@ -409,6 +760,12 @@ impl BookshelfCircuit {
         pst.set_color(0.0, 0.0, 0.0, 1.0);
         pst.set_fill_color(0.8, 0.8, 1.0, 1.0);
         for i in 0..self.cells.len() {
+            if let Some(shape) = &self.cells[i].shape {
+                if !self.cells[i].terminal {
+                    self.ps_rectilinear(pst, i, shape);
+                }
+                continue;
+            }
             if !self.cells[i].terminal && self.cells[i].is_macro {
                 pst.add_filled_box(self.cellpos[i].x + 0.25, self.cellpos[i].y + 0.25, 
                     self.cellpos[i].x + self.cells[i].w - 0.5, 
@@ -423,6 +780,25 @@ impl BookshelfCircuit {
             }
         }
     }
+    /// Draws a rectilinear block -- the rectangles are filled without an
+    /// outline (so the seams between them do not show), and then the real
+    /// outline is drawn from the corners.
+    fn ps_rectilinear(&self, pst: &mut PSTool, i: usize, shape: &Rectilinear) {
+        let x = self.cellpos[i].x;
+        let y = self.cellpos[i].y;
+        pst.set_color(0.8, 0.8, 1.0, 1.0);
+        for r in &shape.rects {
+            pst.add_filled_box(x + r.llx, y + r.lly, x + r.urx, y + r.ury);
+        }
+        pst.set_color(0.0, 0.0, 0.0, 1.0);
+        let n = shape.corners.len();
+        for k in 0..n {
+            let (x0, y0) = shape.corners[k];
+            let (x1, y1) = shape.corners[(k + 1) % n];
+            pst.add_line(x + x0, y + y0, x + x1, y + y1);
+        }
+    }
+
     fn ps_color(pst: &mut pstools::PSTool, n: u32, k: u32) {
         // let mut r = 1.0 * (((11 * n) % k) as f32).sin().abs();
         // if r < 0.3 {
@@ -704,6 +1080,7 @@ impl BookshelfCircuit {
 
         bc.read_nodes(path.with_file_name(nodef).as_path());
         bc.read_nets(path.with_file_name(netf).as_path());
+        bc.read_wts(path.with_file_name(_wtf).as_path());
         bc.read_pl(path.with_file_name(plf).as_path(), false);
         bc.read_scl(path.with_file_name(sclf).as_path());
         if bc.rows.len() > 0 {
@@ -711,6 +1088,9 @@ impl BookshelfCircuit {
             bc.unit_y = bc.rows[0].bounds.dy();
             bc.row_height = bc.unit_y;
         }
+        if bc.num_tiers > 1 {
+            bc.set_tiers(bc.num_tiers);
+        }
         // Now go through and classify all the cell types
         bc.num_cells = 0;
         bc.num_macros = 0;
@@ -811,6 +1191,7 @@ impl BookshelfCircuit {
                     is_macro: false,
                     is_soft: false,
                     can_rotate: false,
+                    shape: None,
                 };
 
                 self.cells.push(c);
@@ -834,6 +1215,36 @@ impl BookshelfCircuit {
     pub fn write_wts(&self, filepath: &String) {
         let mut f = File::create(filepath).unwrap();
         writeln!(&mut f, "UCLA wts 1.0").unwrap();
+        writeln!(&mut f, "# Generated by bookshelf_r").unwrap();
+        for n in &self.nets {
+            if n.weight != 1.0 {
+                writeln!(&mut f, "{} {}", n.name, n.weight).unwrap();
+            }
+        }
+    }
+
+    /// Reads net weights.  The file is optional, and lines that name
+    /// something other than a net (the IBM benchmarks list cell
+    /// weights) are skipped.  Returns the number of nets weighted.
+    pub fn read_wts(&mut self, filepath: &Path) -> usize {
+        let f = match File::open(filepath) {
+            Ok(f) => f,
+            Err(_) => return 0,
+        };
+        let mut reader = BufReader::with_capacity(32000, f);
+        let mut num_weights = 0;
+        while let Ok(line) = BookshelfCircuit::getline(&mut reader) {
+            if let Ok((name, wt)) = scan_fmt!(&line, "{} {}", String, f32) {
+                if let Some(nidx) = self.net_map.get(&name) {
+                    self.nets[*nidx].weight = wt;
+                    num_weights += 1;
+                }
+            }
+        }
+        if num_weights > 0 {
+            println!("Read {} net weights", num_weights);
+        }
+        num_weights
     }
     pub fn write_nodes(&self, filepath: &String) {
         let mut f = File::create(filepath).unwrap();
@@ -895,7 +1306,8 @@ impl BookshelfCircuit {
                 // the center of the cell.  For the bookshelf reader, we add
                 // half cell width and height, so everything is relative to the
                 // lower left corner
-                writeln!(&mut f, " {} B : 0 0", c.name).unwrap();
+                let pin = &c.pins[p.index];
+                writeln!(&mut f, " {} {} : 0 0{}", c.name, pin.direction, BookshelfCircuit::pin_suffix(&pin.name)).unwrap();
             }
         }
     }
@@ -920,10 +1332,12 @@ impl BookshelfCircuit {
                 // height to get the correct location in the NETS file.
                 writeln!(
                     &mut f,
-                    " {} B : {:.1} {:.1}",
+                    " {} {} : {:.1} {:.1}{}",
                     c.name,
+                    pin.direction,
                     pin.dx - c.w / 2.0,
-                    pin.dy - c.h / 2.0
+                    pin.dy - c.h / 2.0,
+                    BookshelfCircuit::pin_suffix(&pin.name)
                 )
                 .unwrap();
             }
@@ -981,6 +1395,7 @@ impl BookshelfCircuit {
                 let mut net = Net {
                     name: nn.clone(),
                     pins: Vec::with_capacity(nd),
+                    weight: 1.0,
                 };
 
                 for p in 0..nd {
@@ -993,24 +1408,14 @@ impl BookshelfCircuit {
                         println!("Pin line {}", line);
                     }
 
-                    if let Ok((cn, sdir, colon, sdx, sdy)) = scan_fmt!(
-                        &line,
-                        " {} {} {} {} {}",
-                        String,
-                        String,
-                        String,
-                        String,
-                        String
-                    ) {
-                        cellname = cn.clone();
-                        if LDBG {
-                            println!("PIN NAME {} sdx {} sdy {}", cellname, sdx, sdy);
-                        }
-
-                        dx = sdx.parse().unwrap();
-                        dy = sdy.parse().unwrap();
-                    } else if let Ok((cn, sdir)) = scan_fmt!(&line, " {} {}", String, String) {
-                        cellname = cn.clone();
+                    let mut pinname = "".to_string();
+                    let mut direction = PinDirection::Bidirectional;
+                    if let Some((cn, pdir, pdx, pdy, pn)) = BookshelfCircuit::parse_pin(&line) {
+                        cellname = cn;
+                        direction = pdir;
+                        dx = pdx;
+                        dy = pdy;
+                        pinname = pn;
                     }
 
                     if LDBG {
@@ -1018,6 +1423,43 @@ impl BookshelfCircuit {
                     }
                     let cidx = self.find_cell(cellname);
 
+                    // Pins with the same name on a cell are one physical
+                    // pin; a repeat on the same net at a new offset is
+                    // another access point.
+                    if pinname != "" {
+                        if let Some(k) = self.cells[cidx].pins.iter().position(|p| p.name == pinname) {
+                            if self.cells[cidx].pins[k].parent_net == nidx {
+                                let ax = dx + self.cells[cidx].w / 2.0;
+                                let ay = dy + self.cells[cidx].h / 2.0;
+                                let pin = &mut self.cells[cidx].pins[k];
+                                if !pin.details.iter().any(|d| d.dx == ax && d.dy == ay) {
+                                    pin.details.push(PinDetail {
+                                        dx: ax,
+                                        dy: ay,
+                                        rect: None,
+                                    });
+                                }
+                                continue;
+                            }
+                            // A pin name is on one net only; keep the
+                            // pin, under a name not used on the cell
+                            let mut unique = 1;
+                            while self.cells[cidx].pins.iter().any(|p| p.name == format!("{}_{}", pinname, unique)) {
+                                unique += 1;
+                            }
+                            let renamed = format!("{}_{}", pinname, unique);
+                            println!(
+                                "Pin {} of cell {} is on nets {} and {}, renamed to {}",
+                                pinname,
+                                self.cells[cidx].name,
+                                self.nets[self.cells[cidx].pins[k].parent_net].name,
+                                nn,
+                                renamed
+                            );
+                            pinname = renamed;
+                        }
+                    }
+
                     let pr = PinRef {
                         parent_cell: cidx,
                         index: self.cells[cidx].pins.len(),
@@ -1030,7 +1472,7 @@ impl BookshelfCircuit {
 
                     net.pins.push(pr);
                     let pi = PinInstance {
-                        name: "".to_string(),
+                        name: pinname,
                         dx: dx + offx,
                         dy: dy + offy,
                         parent_cell: cidx,
@@ -1038,7 +1480,9 @@ impl BookshelfCircuit {
                         details: vec![PinDetail {
                             dx: dx + offx,
                             dy: dy + offy,
+                            rect: None,
                         }],
+                        direction: direction,
                     };
 
                     self.cells[cidx].pins.push(pi);
@@ -1089,19 +1533,30 @@ impl BookshelfCircuit {
                             refpos[cidx].x = x.parse().unwrap();
                             refpos[cidx].y = y.parse().unwrap();
                         }
+                        // 3D placements have a Z (the tier) after the Y; the
+                        // rows for the extra tiers are added at the end (or
+                        // after the SCL file, from read_aux)
+                        if let Some(z) = l.split_whitespace().nth(3).and_then(|z| z.parse::<f32>().ok()) {
+                            if !reference {
+                                self.set_tier(cidx, z.max(0.0).round() as usize);
+                            }
+                        }
                         self.orient[cidx] = Orientation::N;
                         // Try to get the orientation
-                        if let Ok((_cellname, _x, _y, orient)) =
-                            scan_fmt!(&l, " {} {} {} : {}", String, String, String, String)
-                        {
+                        if let Some(orient) = l.split(':').nth(1).and_then(|s| s.split_whitespace().next()) {
                             // println!("Got orientation {} for cell {}", orient, cellname);
-                            self.set_orientation(cidx, Orientation::from_string(&orient));
+                            match orient.parse::<Orientation>() {
+                                Ok(o) => self.set_orientation(cidx, o),
+                                Err(e) => println!("{} for cell {}", e, cellname),
+                            }
                         }
                     }
                 }
                 Err(_e) => {
                     if reference {
                         self.refpos = Some(refpos);
+                    } else if self.num_tiers > 1 {
+                        self.set_tiers(self.num_tiers);
                     }
                     // End of file
                     return 0;
@@ -1138,6 +1593,15 @@ impl BookshelfCircuit {
                     self.cells[ci].w = w;
                     self.cells[ci].h = h;
                     self.centerpin(ci);
+                    // The shape gives the unrotated size, and the
+                    // orientation goes on top of it
+                    if let Ok((o, k)) = scan_fmt!(orient, "{} /SHAPE {d}", String, usize) {
+                        if self.set_soft_shape(ci, k) {
+                            if let Ok(o) = o.parse::<Orientation>() {
+                                self.set_orientation(ci, o);
+                            }
+                        }
+                    }
                 } else {
                     println!("PLX error: cell {} not found", n);
                 }
@@ -1178,15 +1642,15 @@ impl BookshelfCircuit {
             if !c.terminal {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : {}",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.orient[i]
+                    "{}  {} {}{} : {}",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i), self.orient[i]
                 )
                 .unwrap();
             } else {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : {} /FIXED",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.orient[i]
+                    "{}  {} {}{} : {} /FIXED",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i), self.orient[i]
                 )
                 .unwrap();
             }
@@ -1214,10 +1678,17 @@ impl BookshelfCircuit {
         for i in 0..self.cells.len() {
             let c = &self.cells[i];
             if !c.terminal {
+                // Soft blocks note which of the alternate shapes is in use
+                let mut shape = "".to_string();
+                if let Some(soft) = &c.soft {
+                    if let Some(k) = soft.chosen {
+                        shape = format!(" /SHAPE {}", k);
+                    }
+                }
                 writeln!(
                     &mut f,
-                    "{}  {} {} {} {} : {}",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, c.w, c.h, self.orient[i]
+                    "{}  {} {} {} {} : {}{}",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, c.w, c.h, self.orient[i], shape
                 )
                 .unwrap();
             } else {
@@ -1246,15 +1717,15 @@ impl BookshelfCircuit {
             if c.is_macro || c.terminal {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : {} /FIXED",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.orient[i]
+                    "{}  {} {}{} : {} /FIXED",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i), self.orient[i]
                 )
                 .unwrap();
             } else {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : {}",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.orient[i]
+                    "{}  {} {}{} : {}",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i), self.orient[i]
                 )
                 .unwrap();
             }
@@ -1274,15 +1745,15 @@ impl BookshelfCircuit {
             if c.terminal {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : {} /FIXED",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.orient[i],
+                    "{}  {} {}{} : {} /FIXED",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i), self.orient[i],
                 )
                 .unwrap();
             } else {
                 writeln!(
                     &mut f,
-                    "{}  {} {} : N",
-                    c.name, self.cellpos[i].x, self.cellpos[i].y
+                    "{}  {} {}{} : N",
+                    c.name, self.cellpos[i].x, self.cellpos[i].y, self.z_field(i)
                 )
                 .unwrap();
             }
@@ -1317,8 +1788,8 @@ impl BookshelfCircuit {
             let mut height = 0 as f32;
             let mut sitewidth = 0 as f32;
             let mut sitespacing = 0 as f32;
-            let mut orient = 0 as u32;
-            let mut symmetry = 0 as u32;
+            let mut orient = Orientation::N;
+            let mut symmetry = "".to_string();
             let mut origin = 0 as f32;
             let mut numsites = 0 as f32;
 
@@ -1360,7 +1831,10 @@ impl BookshelfCircuit {
                 if LDBG {
                     println!("  Orient {}", so);
                 }
-                orient = 0;
+                match so.parse::<Orientation>() {
+                    Ok(o) => orient = o,
+                    Err(e) => println!("{} for row {}", e, row),
+                }
             }
             // Sitesymmetry : n
             let line = BookshelfCircuit::getline(&mut reader).unwrap();
@@ -1368,7 +1842,7 @@ impl BookshelfCircuit {
                 if LDBG {
                     println!("  Symmetry {}", sym);
                 }
-                symmetry = 0;
+                symmetry = sym;
             }
             // SubrowOrigin : n  Numsites : n
             let line = BookshelfCircuit::getline(&mut reader).unwrap();
@@ -1394,6 +1868,9 @@ impl BookshelfCircuit {
                 name: "row".to_string(),
                 bounds: bounds,
                 site_spacing: sitespacing,
+                orient: orient,
+                symmetry: symmetry,
+                tier: 0,
             });
         }
 
@@ -1404,15 +1881,18 @@ impl BookshelfCircuit {
         let mut f = File::create(filepath).unwrap();
         writeln!(&mut f, "UCLA scl 1.0").unwrap();
         writeln!(&mut f, "# Generated by bookshelf_r\n").unwrap();
-        writeln!(&mut f, "Numrows : {}\n", self.rows.len());
-        for r in &self.rows {
+        // The rows of the other tiers are copies of tier 0, and are made
+        // again by set_tiers when the PL file has Z coordinates
+        let rows: Vec<&Row> = self.rows.iter().filter(|r| r.tier == 0).collect();
+        writeln!(&mut f, "Numrows : {}\n", rows.len());
+        for r in rows {
             writeln!(&mut f, "CoreRow Horizontal").unwrap();
             writeln!(&mut f, " Coordinate   : {}", r.bounds.lly).unwrap();
             writeln!(&mut f, " Height       : {}", r.bounds.dy()).unwrap();
             writeln!(&mut f, " Sitewidth    : {}", 1).unwrap();
             writeln!(&mut f, " Sitespacing   : {}", r.site_spacing).unwrap();
-            writeln!(&mut f, " Siteorient   : N").unwrap();
-            writeln!(&mut f, " Sitesymmetry : Y").unwrap();
+            writeln!(&mut f, " Siteorient   : {}", r.orient).unwrap();
+            writeln!(&mut f, " Sitesymmetry : {}", r.symmetry).unwrap();
             writeln!(
                 &mut f,
                 " SubrowOrigin : {} Numsites : {}",
@@ -1424,12 +1904,28 @@ impl BookshelfCircuit {
         }
     }
 
+    /// Writes the aux, nodes, nets, wts, pl, and scl files for base.  The
+    /// aux file names the others without the directory part of base,
+    /// since read_aux looks for them next to the aux file (with the full
+    /// path, a design written to another directory could not be read
+    /// back).  The nets are written with write_nets, so the pin offsets
+    /// and every access point come back as they were; pin shapes
+    /// (PinDetail::rect) have no place in the nets file, and are lost.
     pub fn write_aux(&self, base: &String) {
+        if self.is_blockpacking {
+            self.write_blockpacking(base);
+            return;
+        }
+        let stem = Path::new(base)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
         let mut f = File::create(format!("{}.aux", base)).unwrap();
         // writeln!(&mut f, "UCLA aux 1.0").unwrap();
         writeln!(
             &mut f,
-            "RowBasedPlacement : {base}.nodes {base}.nets {base}.wts {base}.pl {base}.scl"
+            "RowBasedPlacement : {stem}.nodes {stem}.nets {stem}.wts {stem}.pl {stem}.scl"
         )
         .unwrap();
         writeln!(&mut f, "# Converted by write_aux from bookshelf_r").unwrap();
@@ -1438,12 +1934,161 @@ impl BookshelfCircuit {
         }
 
         self.write_nodes(&format!("{}.nodes", base));
-        self.write_nets_center(&format!("{}.nets", base));
+        self.write_nets(&format!("{}.nets", base));
         self.write_wts(&format!("{}.wts", base));
         self.write_pl(format!("{}.pl", base), &self.notes);
         self.write_scl(&format!("{}.scl", base));
     }
 
+    /// Writes a block packing design: an AUX file with the BlockPacking
+    /// line, along with the blocks, nets, and pl files.  The AUX file
+    /// refers to the other files by name only, so that the set can be
+    /// read from whatever directory it was written to.
+    pub fn write_blockpacking(&self, base: &String) {
+        let stem = Path::new(base)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let mut f = File::create(format!("{}.aux", base)).unwrap();
+        writeln!(
+            &mut f,
+            "BlockPacking : {stem}.blocks {stem}.nets {stem}.pl"
+        )
+        .unwrap();
+        writeln!(&mut f, "# Converted by write_blockpacking from bookshelf_r").unwrap();
+        for n in &self.notes {
+            writeln!(&mut f, "# {}", n).unwrap();
+        }
+
+        self.write_blocks(&format!("{}.blocks", base));
+        self.write_nets(&format!("{}.nets", base));
+        self.write_pl(format!("{}.pl", base), &self.notes);
+    }
+
+    /// Writes the blocks file for a block packing design.  Hard blocks use
+    /// the unrotated dimensions (the orientation goes into the pl file),
+    /// and soft blocks are written with their required area and aspect
+    /// ratio limits.  A soft block that has been given a shape is written
+    /// as a hard block of that shape, so that the floorplan is kept.
+    pub fn write_blocks(&self, filepath: &String) {
+        let still_soft = |c: &Cell| c.is_soft && !c.soft.as_ref().is_some_and(|s| s.shaped);
+        let mut f = File::create(filepath).unwrap();
+        writeln!(&mut f, "UCSC blocks 1.0").unwrap();
+        writeln!(&mut f, "# Generated by bookshelf_r\n").unwrap();
+        let mut num_soft = 0;
+        let mut num_hard = 0;
+        let mut num_term = 0;
+        for c in &self.cells {
+            if c.terminal {
+                num_term += 1;
+            } else if still_soft(c) {
+                num_soft += 1;
+            } else {
+                num_hard += 1;
+            }
+        }
+        writeln!(&mut f, "NumSoftRectangularBlocks : {}", num_soft).unwrap();
+        writeln!(&mut f, "NumHardRectilinearBlocks : {}", num_hard).unwrap();
+        writeln!(&mut f, "NumTerminals : {}\n", num_term).unwrap();
+        for c in &self.cells {
+            if c.terminal {
+                continue;
+            }
+            if still_soft(c) {
+                let mut area = c.original_w * c.original_h;
+                if let Some(soft) = &c.soft {
+                    area = soft.area;
+                }
+                writeln!(
+                    &mut f,
+                    "{} softrectangular {} {} {}",
+                    c.name, area, c.min_aspect, c.max_aspect
+                )
+                .unwrap();
+            } else if let Some(shape) = &c.shape {
+                let corners: Vec<String> = shape
+                    .original_corners
+                    .iter()
+                    .map(|(x, y)| format!("({}, {})", x, y))
+                    .collect();
+                writeln!(
+                    &mut f,
+                    "{} hardrectilinear {} {}",
+                    c.name,
+                    corners.len(),
+                    corners.join(" ")
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    &mut f,
+                    "{} hardrectilinear 4 (0, 0) (0, {}) ({}, {}) ({}, 0)",
+                    c.name, c.original_h, c.original_w, c.original_h, c.original_w
+                )
+                .unwrap();
+            }
+        }
+        writeln!(&mut f).unwrap();
+        for c in &self.cells {
+            if c.terminal {
+                writeln!(&mut f, "{} terminal", c.name).unwrap();
+            }
+        }
+    }
+
+    // Pin name for the end of a nets file pin line
+    fn pin_suffix(name: &String) -> String {
+        if name.is_empty() {
+            "".to_string()
+        } else {
+            format!(" : {}", name)
+        }
+    }
+
+    /// Writes the nets with the exact pin offsets.  Named pins with
+    /// more than one access point get a line for each (the reader
+    /// merges them again).
+    pub fn write_nets(&self, filepath: &String) {
+        let mut f = File::create(filepath).unwrap();
+        writeln!(&mut f, "UCLA nets 1.0").unwrap();
+        writeln!(&mut f, "# Generated by bookshelf_r").unwrap();
+        writeln!(&mut f, "NumNets : {}", self.nets.len()).unwrap();
+        let lines = |p: &PinRef| {
+            let pin = &self.cells[p.parent_cell].pins[p.index];
+            if pin.name.is_empty() {
+                1
+            } else {
+                pin.details.len()
+            }
+        };
+        let mut num_pins = 0;
+        for net in &self.nets {
+            num_pins += net.pins.iter().map(|p| lines(p)).sum::<usize>();
+        }
+        writeln!(&mut f, "NumPins : {}", num_pins).unwrap();
+        for net in &self.nets {
+            let degree: usize = net.pins.iter().map(|p| lines(p)).sum();
+            writeln!(&mut f, "NetDegree : {} {}", degree, net.name).unwrap();
+            for p in &net.pins {
+                let c = &self.cells[p.parent_cell];
+                let pin = &c.pins[p.index];
+                for d in pin.details.iter().take(lines(p)) {
+                    writeln!(
+                        &mut f,
+                        " {} {} : {} {}{}",
+                        c.name,
+                        pin.direction,
+                        d.dx - c.original_w / 2.0,
+                        d.dy - c.original_h / 2.0,
+                        BookshelfCircuit::pin_suffix(&pin.name)
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+
     pub fn cell_area(&self) -> f32 {
         let mut tot_area = 0.0;
         for c in &self.cells {
@@ -1671,7 +2316,7 @@ impl BookshelfCircuit {
                 ury = ury.max(py);
             }
         }
-        let len = (urx - llx) + (ury - lly);
+        let len = (urx - llx) + (ury - lly) + self.net_via_cost(n);
 
         len        
     }
@@ -1713,7 +2358,7 @@ impl BookshelfCircuit {
                     ury = ury.max(py);
                 }
             }
-            let len = (urx - llx) + (ury - lly);
+            let len = (urx - llx) + (ury - lly) + self.net_via_cost(n);
             if counter > 0 {
                 println!("BBox {} {}   {} {}   len {} ", llx, lly, urx, ury, len);
             }
@@ -1723,6 +2368,16 @@ impl BookshelfCircuit {
         total as f32
     }
 
+    /// HPWL with each net scaled by its weight (from the .wts file, or
+    /// from set_timing_weights).
+    pub fn weighted_wl(&self) -> f32 {
+        let mut total = 0.0;
+        for n in &self.nets {
+            total = total + n.weight * self.net_wl(n);
+        }
+        total
+    }
+
     /// Moves the pins in a cell (or macro) to the center (using the current width and height)
     pub fn centerpin(&mut self, cellid: usize) {
         let dx = self.cells[cellid].w * 0.5;
@@ -1743,6 +2398,56 @@ impl BookshelfCircuit {
         }
     }
 
+    /// Regenerates the alternate shapes for all of the soft blocks, with
+    /// count shapes per block (optionally rounded to integer sizes).
+    pub fn generate_soft_shapes(&mut self, count: usize, round: bool) {
+        for c in &mut self.cells {
+            if !c.is_soft {
+                continue;
+            }
+            let mut area = c.original_w * c.original_h;
+            if let Some(soft) = &c.soft {
+                area = soft.area;
+            }
+            c.soft = Some(SoftSize::generate(area, c.min_aspect, c.max_aspect, count, round));
+        }
+    }
+
+    /// Switches a soft block to one of its alternate shapes.  The width and
+    /// height are updated (with the orientation reset to N), and the pins
+    /// are moved to the center of the new shape.  Returns false if the
+    /// cell has no such shape.
+    pub fn set_soft_shape(&mut self, cell_id: usize, shape: usize) -> bool {
+        let size;
+        match &self.cells[cell_id].soft {
+            Some(soft) if shape < soft.alt_sizes.len() => {
+                size = soft.alt_sizes[shape];
+            }
+            _ => {
+                return false;
+            }
+        }
+        let cell = &mut self.cells[cell_id];
+        cell.w = size.w;
+        cell.h = size.h;
+        cell.original_w = size.w;
+        cell.original_h = size.h;
+        for p in &mut cell.pins {
+            for d in &mut p.details {
+                d.dx = size.w * 0.5;
+                d.dy = size.h * 0.5;
+                d.rect = None;
+            }
+        }
+        if let Some(soft) = &mut cell.soft {
+            soft.chosen = Some(shape);
+            soft.shaped = true;
+        }
+        self.orient[cell_id] = Orientation::N;
+        self.centerpin(cell_id);
+        true
+    }
+
     pub fn core(&self) -> bbox::BBox {
         let mut result = bbox::BBox::new();
         if self.rows.len() > 0 {
@@ -1867,13 +2572,123 @@ impl BookshelfCircuit {
         self.notes.push(format!("After expansion: {}", self.wl()));
         self.notes.push(format!("Expanded bounding box: {}", bb));
     }
+    /// Finds a pin on a cell by name.
+    pub fn find_pin(&self, cell_id: usize, name: &String) -> Option<PinRef> {
+        let index = self.cells[cell_id].pins.iter().position(|p| p.name == *name)?;
+        Some(PinRef {
+            parent_cell: cell_id,
+            index: index,
+        })
+    }
+
+    /// Finds a pin by cell name and pin name.
+    pub fn find_pin_by_name(&self, cellname: &String, name: &String) -> Option<PinRef> {
+        let cell_id = *self.cell_map.get(cellname)?;
+        self.find_pin(cell_id, name)
+    }
+
     pub fn pinloc(&self, pr: &PinRef) -> (f32, f32) {
         let px = self.cellpos[pr.parent_cell].x + self.cells[pr.parent_cell].pins[pr.index].dx;
         let py = self.cellpos[pr.parent_cell].y + self.cells[pr.parent_cell].pins[pr.index].dy;
         (px, py)
     }
 
+    /// Locations of all of the access points of a pin, for the current
+    /// cell position and orientation.
+    pub fn pin_access(&self, pr: &PinRef) -> Vec<(f32, f32)> {
+        let cell = &self.cells[pr.parent_cell];
+        let pos = &self.cellpos[pr.parent_cell];
+        let o = self.orient[pr.parent_cell];
+        cell.pins[pr.index]
+            .details
+            .iter()
+            .map(|d| {
+                let (x, y) = o.transform(d.dx, d.dy, cell.original_w, cell.original_h);
+                (pos.x + x, pos.y + y)
+            })
+            .collect()
+    }
+
+    /// Pin shapes (for the access points that have them), placed and
+    /// oriented.
+    pub fn pin_rects(&self, pr: &PinRef) -> Vec<BBox> {
+        let cell = &self.cells[pr.parent_cell];
+        let pos = &self.cellpos[pr.parent_cell];
+        let o = self.orient[pr.parent_cell];
+        let mut rects = Vec::new();
+        for d in &cell.pins[pr.index].details {
+            if let Some(r) = &d.rect {
+                let mut b = o.transform_rect(r, cell.original_w, cell.original_h);
+                b.llx = b.llx + pos.x;
+                b.urx = b.urx + pos.x;
+                b.lly = b.lly + pos.y;
+                b.ury = b.ury + pos.y;
+                rects.push(b);
+            }
+        }
+        rects
+    }
+
+    /// The point of the pin closest to (x, y): the nearest access point,
+    /// or the nearest point on a pin shape.
+    pub fn pinloc_near(&self, pr: &PinRef, x: f32, y: f32) -> (f32, f32) {
+        let mut best = self.pinloc(pr);
+        let mut best_d = (best.0 - x).abs() + (best.1 - y).abs();
+        let mut candidates = self.pin_access(pr);
+        for r in self.pin_rects(pr) {
+            candidates.push((x.max(r.llx).min(r.urx), y.max(r.lly).min(r.ury)));
+        }
+        for (px, py) in candidates {
+            let d = (px - x).abs() + (py - y).abs();
+            if d < best_d {
+                best_d = d;
+                best = (px, py);
+            }
+        }
+        best
+    }
+
+    /// Half perimeter wire length, with each pin at the access point
+    /// closest to the center of the net (the center is from pinloc).
+    pub fn net_wl_access(&self, n: &Net) -> f32 {
+        if n.pins.is_empty() {
+            return 0.0;
+        }
+        let mut cx = 0.0;
+        let mut cy = 0.0;
+        for pr in &n.pins {
+            let (px, py) = self.pinloc(pr);
+            cx = cx + px;
+            cy = cy + py;
+        }
+        cx = cx / n.pins.len() as f32;
+        cy = cy / n.pins.len() as f32;
+        let mut bb = BBox::new();
+        for pr in &n.pins {
+            let (px, py) = self.pinloc_near(pr, cx, cy);
+            bb.addpoint(px, py);
+        }
+        bb.dx() + bb.dy()
+    }
+
+    pub fn wl_access(&self) -> f32 {
+        let mut total = 0.0;
+        for n in &self.nets {
+            total = total + self.net_wl_access(n);
+        }
+        total
+    }
+
+    /// Reads a block packing design.  Each soft block gets 5 alternate
+    /// shapes, rounded to integer sizes.
     pub fn read_blockpacking(filename: String) -> BookshelfCircuit {
+        BookshelfCircuit::read_blockpacking_shapes(filename, 5, true)
+    }
+
+    /// Reads a block packing design.  Each soft block gets shape_count
+    /// alternate shapes (rounded to integer sizes if round_shapes is set);
+    /// see generate_soft_shapes.
+    pub fn read_blockpacking_shapes(filename: String, shape_count: usize, round_shapes: bool) -> BookshelfCircuit {
         let f = File::open(filename.clone()).unwrap();
         let mut reader = BufReader::with_capacity(32000, f);
         let line = BookshelfCircuit::getline(&mut reader).unwrap();
@@ -1890,16 +2705,17 @@ impl BookshelfCircuit {
         let path = Path::new(&filename);
 
         let mut bc = BookshelfCircuit::new();
-        bc.read_blocknodes(path.with_file_name(blockf).as_path());
+        bc.read_blocknodes(path.with_file_name(blockf).as_path(), shape_count, round_shapes);
         bc.read_nets(path.with_file_name(netf).as_path());
         bc.read_pl(path.with_file_name(plf).as_path(), false);
         bc.unit_x = 1.0;
         bc.unit_y = 1.0;
+        bc.is_blockpacking = true;
 
         bc
     }
 
-    pub fn read_blocknodes(&mut self, filepath: &Path) {
+    pub fn read_blocknodes(&mut self, filepath: &Path, shape_count: usize, round_shapes: bool) {
         let f = File::open(filepath).unwrap();
         let mut reader = BufReader::with_capacity(32000, f);
 
@@ -1918,31 +2734,20 @@ impl BookshelfCircuit {
                         println!("Got {} soft blocks", ns);
                         numsoft = ns;
                     }
-                    // Assume hardrectangular has exactly four corners....  Gripe if not.
-                    if let Ok((bname, corners, x0, y0, _x1, _y1, x2, y2, _x3, _y3)) = scan_fmt!(
-                        &l,
-                        "{} hardrectilinear {} ({}, {}) ({}, {}) ({}, {}) ({}, {})",
-                        String,
-                        u32,
-                        f32,
-                        f32,
-                        f32,
-                        f32,
-                        f32,
-                        f32,
-                        f32,
-                        f32
-                    ) {
+                    if let Some((bname, corners, points)) = BookshelfCircuit::parse_rectilinear(&l) {
                         if LDBG {
                             println!("Got hard macro {}", bname);
                         }
-                        if corners != 4 {
-                            println!("Block {bname} does not has {corners} corners!");
+                        if corners != points.len() {
+                            println!("Block {bname} lists {corners} corners, but has {}", points.len());
                         }
                         let cn = self.find_cell(bname.clone());
 
-                        let w = x2 - x0;
-                        let h = y2 - y0;
+                        let (shape, w, h) = Rectilinear::from_corners(&points);
+                        let mut rect_shape = None;
+                        if shape.rects.len() > 1 {
+                            rect_shape = Some(shape);
+                        }
                         let c = Cell {
                             name: bname,
                             w: w,
@@ -1957,6 +2762,7 @@ impl BookshelfCircuit {
                             is_macro: false,
                             is_soft: false,
                             can_rotate: false,
+                            shape: rect_shape,
                         };
                         self.cells.push(c);
                         let cp = point::Point { x: 0.0, y: 0.0 };
@@ -1967,6 +2773,7 @@ impl BookshelfCircuit {
                         let cn = self.find_cell(bname.clone());
                         let w = area.sqrt().trunc();
                         let h = (area/w).trunc() + 1.0;  // Force integer sizing
+                        let soft = SoftSize::generate(area, min_aspect, max_aspect, shape_count, round_shapes);
                         let c = Cell {
                             name: bname,
                             w: w,
@@ -1977,10 +2784,11 @@ impl BookshelfCircuit {
                             max_aspect: max_aspect,
                             pins: Vec::new(),
                             terminal: false,
-                            soft: None,
+                            soft: Some(soft),
                             is_macro: true,
                             is_soft: true,
-                            can_rotate: true
+                            can_rotate: true,
+                            shape: None,
                         };
                         self.cells.push(c);
                         let cp = point::Point{x: 0.0, y: 0.0};
@@ -2007,6 +2815,7 @@ impl BookshelfCircuit {
                             is_macro: false,
                             is_soft: false,
                             can_rotate: true,
+                            shape: None,
                         });
                         self.cellpos.push(point::Point { x: 0.0, y: 0.0 });
                         self.orient.push(Orientation::N);
@@ -2019,52 +2828,83 @@ impl BookshelfCircuit {
         }
     }
 
-    pub fn orient_cell(cell: &mut Cell, orient: Orientation) {
-        if orient == Orientation::FN
-            || orient == Orientation::S
-            || orient == Orientation::E
-            || orient == Orientation::FE
-        {
-            // Flip the X position of the pins
-            // println!("Doing X reorientation for {}", orient);
-            for pin in &mut cell.pins {
-                pin.dx = cell.original_w - pin.details[0].dx;
-                // println!("  pin {} moves to {}", pin.name, pin.dx);
+    /// Parses a pin line from a nets file: `cell I`, `cell I : dx dy`,
+    /// or either of these followed by a pin name (`cell I : dx dy : A`,
+    /// or just `cell I : dx dy A`).  Returns the cell name, the direction
+    /// (B if it is missing or not recognized), the offsets from the cell
+    /// center, and the pin name ("" if there is none).
+    fn parse_pin(l: &String) -> Option<(String, PinDirection, f32, f32, String)> {
+        let fields: Vec<&str> = l.split_whitespace().collect();
+        let cellname = fields.get(0)?.to_string();
+        let direction = fields
+            .get(1)
+            .and_then(|f| f.parse::<PinDirection>().ok())
+            .unwrap_or(PinDirection::Bidirectional);
+        let mut dx = 0.0;
+        let mut dy = 0.0;
+        let mut pinname = "".to_string();
+        if let Some(colon) = fields.iter().position(|f| *f == ":") {
+            let mut k = colon + 1;
+            let x = fields.get(k).and_then(|f| f.parse::<f32>().ok());
+            let y = fields.get(k + 1).and_then(|f| f.parse::<f32>().ok());
+            if let (Some(x), Some(y)) = (x, y) {
+                dx = x;
+                dy = y;
+                k = k + 2;
             }
-        } else {
-            for pin in &mut cell.pins {
-                pin.dx = pin.details[0].dx;
+            if let Some(name) = fields.iter().skip(k).find(|f| **f != ":") {
+                pinname = name.to_string();
             }
         }
+        Some((cellname, direction, dx, dy, pinname))
+    }
 
-        if orient == Orientation::S
-            || orient == Orientation::FS
-            || orient == Orientation::FE
-            || orient == Orientation::W
-        {
-            // Flip the X position of the pins
-            for pin in &mut cell.pins {
-                pin.dy = cell.original_h - pin.details[0].dy;
-            }
-        } else {
-            for pin in &mut cell.pins {
-                pin.dy = pin.details[0].dy;
-            }
+    /// Parses a hardrectilinear line from a blocks file, with any
+    /// number of corners, returning the block name, the corner count
+    /// given in the file, and the corners themselves.
+    fn parse_rectilinear(l: &String) -> Option<(String, usize, Vec<(f32, f32)>)> {
+        let mut fields = l.split_whitespace();
+        let bname = fields.next()?.to_string();
+        if fields.next()? != "hardrectilinear" {
+            return None;
+        }
+        let corners = fields.next()?.parse::<usize>().ok()?;
+        let rest: Vec<&str> = fields.collect();
+        let rest = rest.join(" ").replace("(", " ").replace(")", " ").replace(",", " ");
+        let mut values = Vec::new();
+        for v in rest.split_whitespace() {
+            values.push(v.parse::<f32>().ok()?);
         }
+        let points = values.chunks(2).filter(|c| c.len() == 2).map(|c| (c[0], c[1])).collect();
+        Some((bname, corners, points))
+    }
 
-        // Now potentially swap X and Y
-        if orient == Orientation::E
-            || orient == Orientation::FE
-            || orient == Orientation::W
-            || orient == Orientation::FW
-        {
-            cell.h = cell.original_w;
-            cell.w = cell.original_h;
-            for pin in &mut cell.pins {
-                let old_dx = pin.dx;
-                pin.dx = pin.dy;
-                pin.dy = old_dx;
-            }
+    pub fn orient_cell(cell: &mut Cell, orient: Orientation) {
+        let (w, h) = (cell.original_w, cell.original_h);
+        if let Some(shape) = &mut cell.shape {
+            shape.corners = shape
+                .original_corners
+                .iter()
+                .map(|(x, y)| orient.transform(*x, *y, w, h))
+                .collect();
+            shape.rects = shape
+                .original_rects
+                .iter()
+                .map(|r| orient.transform_rect(r, w, h))
+                .collect();
+        }
+
+        for pin in &mut cell.pins {
+            let (dx, dy) = orient.transform(pin.details[0].dx, pin.details[0].dy, w, h);
+            pin.dx = dx;
+            pin.dy = dy;
+        }
+        if orient.swaps() {
+            cell.w = h;
+            cell.h = w;
+        } else {
+            cell.w = w;
+            cell.h = h;
         }
     }
 
@@ -2080,6 +2920,35 @@ use crate::marklist;
 use crate::marklist::MarkList;
 use std::os::raw::{c_int, c_uint, c_ulong};
 
+/// How build_graph weights the hyperedges.  The weights are scaled by
+/// HyperParams::weight_scale and rounded, with a minimum of 1.  The
+/// default scale is 1 for the integer schemes (Uniform and TermProp),
+/// so that the cut counts nets, and 100 for the fractional ones.
+#[derive(Clone)]
+pub enum EdgeWeight {
+    /// Every net weighs 1
+    Uniform,
+    /// 6 for nets with a propagated terminal, 5 for the rest
+    TermProp,
+    /// 1/(degree-1), the weight of each edge in the clique model
+    Clique,
+    /// The net weights read from the .wts file
+    NetWeight,
+    /// 1 + (max_weight - 1) c^2, for per-net criticality c in 0..1
+    /// (indexed by net)
+    Criticality { criticality: Vec<f32>, max_weight: f32 },
+    /// Any function of the circuit and net index
+    Custom(Arc<dyn Fn(&BookshelfCircuit, usize) -> f32 + Send + Sync>),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VertexWeight {
+    Area,
+    Unit,
+    /// Number of pins on the cell
+    Pins,
+}
+
 pub struct HyperParams {
     pub cellmark: marklist::MarkList,
     pub netmark: marklist::MarkList,
@@ -2091,7 +2960,29 @@ pub struct HyperParams {
     pub term_prop: bool,
     pub passes: usize,
     pub seed: usize,
-    pub edgeweight: usize, // Different edge weighting modes
+    pub edgeweight: EdgeWeight,
+    /// Multiplier for the edge weights before rounding (0 to pick one
+    /// from the edge weight scheme)
+    pub weight_scale: f32,
+    pub vertexweight: VertexWeight,
+    /// Nets with more cells than this are left out of the graph (0 for
+    /// no limit)
+    pub max_net_degree: usize,
+    /// Regions for k-way partitioning.  With two or more regions,
+    /// build_graph adds one fixed vertex per region (after the cells,
+    /// in region order), and terminal propagation connects each net to
+    /// the regions holding its outside cells.  Each region vertex is
+    /// fixed on side 0 or 1 of the cut at split_point, by the region
+    /// center, so the graph can go straight to a bipartitioner.
+    /// Otherwise, the split is two-way at split_point, with a source and
+    /// sink vertex.
+    pub regions: Vec<BBox>,
+    /// Area fraction for each partition.  If empty, two-way splits use
+    /// the bias, and k-way splits are even.
+    pub targets: Vec<f32>,
+    /// Nets that build_graph gave propagated terminals, added up over
+    /// the calls (kway_partition clears it first)
+    pub propagated: usize,
 }
 
 impl HyperParams {
@@ -2107,7 +2998,121 @@ impl HyperParams {
             term_prop: true,
             passes: 1,
             seed: 8675309,
-            edgeweight: 0,
+            edgeweight: EdgeWeight::Uniform,
+            weight_scale: 0.0,
+            vertexweight: VertexWeight::Area,
+            max_net_degree: 0,
+            regions: Vec::new(),
+            targets: Vec::new(),
+            propagated: 0,
+        }
+    }
+
+    /// Sets up k-way partitioning into the regions, with area targets in
+    /// proportion to the region areas.
+    pub fn set_regions(&mut self, regions: Vec<BBox>) {
+        let total: f32 = regions.iter().map(|r| r.area()).sum();
+        self.targets = regions.iter().map(|r| r.area() / total).collect();
+        self.k = regions.len() as u32;
+        self.regions = regions;
+    }
+
+    /// Sets up partitioning into an nx by ny grid over the bounds (2 by 2
+    /// for quadrisection).  Regions are numbered by row, from the lower
+    /// left.
+    pub fn set_grid(&mut self, bounds: &BBox, nx: usize, ny: usize) {
+        let mut regions = Vec::new();
+        for j in 0..ny {
+            for i in 0..nx {
+                let mut r = BBox::new();
+                r.addpoint(
+                    bounds.llx + bounds.dx() * i as f32 / nx as f32,
+                    bounds.lly + bounds.dy() * j as f32 / ny as f32,
+                );
+                r.addpoint(
+                    bounds.llx + bounds.dx() * (i + 1) as f32 / nx as f32,
+                    bounds.lly + bounds.dy() * (j + 1) as f32 / ny as f32,
+                );
+                regions.push(r);
+            }
+        }
+        self.set_regions(regions);
+    }
+
+    /// Area fraction for each of the k partitions.
+    pub fn area_targets(&self) -> Vec<f32> {
+        let k = self.k.max(2) as usize;
+        if self.targets.len() == k {
+            let total: f32 = self.targets.iter().sum();
+            return self.targets.iter().map(|t| t / total).collect();
+        }
+        if k == 2 {
+            return vec![self.bias, 1.0 - self.bias];
+        }
+        vec![1.0 / k as f32; k]
+    }
+
+    /// The region containing a point, or the one with the closest center.
+    pub fn region_of(&self, x: f32, y: f32) -> usize {
+        let mut best = 0;
+        let mut best_d = f32::MAX;
+        for i in 0..self.regions.len() {
+            let r = &self.regions[i];
+            if x >= r.llx && x < r.urx && y >= r.lly && y < r.ury {
+                return i;
+            }
+            let d = (x - (r.llx + r.urx) * 0.5).abs() + (y - (r.lly + r.ury) * 0.5).abs();
+            if d < best_d {
+                best_d = d;
+                best = i;
+            }
+        }
+        best
+    }
+
+    /// Hyperedge weight for a net with the given number of cells.
+    pub fn net_weight(&self, ckt: &BookshelfCircuit, net_id: usize, degree: usize, propagated: bool) -> c_int {
+        let wt = match &self.edgeweight {
+            EdgeWeight::Uniform => 1.0,
+            EdgeWeight::TermProp => {
+                if propagated {
+                    6.0
+                } else {
+                    5.0
+                }
+            }
+            EdgeWeight::Clique => 1.0 / (degree.max(2) - 1) as f32,
+            EdgeWeight::NetWeight => ckt.nets[net_id].weight,
+            EdgeWeight::Criticality { criticality, max_weight } => {
+                let c = criticality.get(net_id).cloned().unwrap_or(0.0).max(0.0).min(1.0);
+                1.0 + (max_weight - 1.0) * c * c
+            }
+            EdgeWeight::Custom(f) => f(ckt, net_id),
+        };
+        let scale = if self.weight_scale > 0.0 {
+            self.weight_scale
+        } else {
+            match &self.edgeweight {
+                EdgeWeight::Uniform | EdgeWeight::TermProp => 1.0,
+                _ => 100.0,
+            }
+        };
+        ((wt * scale).round() as c_int).max(1)
+    }
+
+    pub fn vertex_weight(&self, cell: &Cell) -> c_int {
+        match self.vertexweight {
+            VertexWeight::Area => cell.area() as c_int,
+            VertexWeight::Unit => 1,
+            VertexWeight::Pins => cell.pins.len().max(1) as c_int,
+        }
+    }
+
+    /// Records the partition of each cell from the partition of each
+    /// vertex of the last graph built (fixed vertices are ignored).
+    pub fn set_partition(&mut self, vertex_part: &Vec<c_int>) {
+        for i in 0..self.cellmark.list.len() {
+            self.partition[self.cellmark.list[i]] = vertex_part[i];
         }
     }
 }
@@ -2193,6 +3198,9 @@ impl BookshelfCircuit {
         let mut cardinality = vec![0; params.netmark.list.len()];
         let mut sinks = vec![false; params.netmark.list.len()];
         let mut sources = vec![false; params.netmark.list.len()];
+        // Regions touched by each net, for k-way terminal propagation
+        let kway = params.regions.len() > 1;
+        let mut propagated: Vec<Vec<usize>> = vec![Vec::new(); params.netmark.list.len()];
 
         // let mut propagated = 0;
 
@@ -2207,7 +3215,14 @@ impl BookshelfCircuit {
                         cardinality[params.netmark.index[*net_id]] + 1;
                     // cell_ids.push(pr.parent_cell);
                 } else {
-                    if params.term_prop {
+                    if params.term_prop && kway {
+                        let r = params.region_of(self.cellpos[*cell_id].x, self.cellpos[*cell_id].y);
+                        let touched = &mut propagated[params.netmark.index[*net_id]];
+                        if !touched.contains(&r) {
+                            touched.push(r);
+                        }
+                        sources[params.netmark.index[*net_id]] = true;
+                    } else if params.term_prop {
                         // let (px, py) = self.pinloc(pr);
                         let px = self.cellpos[*cell_id].x;
                         let py = self.cellpos[*cell_id].y;
@@ -2267,7 +3282,7 @@ impl BookshelfCircuit {
         let mut tot_area = 0.0;
         for cell_id in &params.cellmark.list {
             let cell = &self.cells[*cell_id];
-            hg.vtxwt.push(cell.area() as c_int);
+            hg.vtxwt.push(params.vertex_weight(cell));
             tot_area = tot_area + cell.area();
             // vtxwt.push(1 as c_int);
             // println!("Added cell  {} index {} h {} w {}", cell.name, *c, cell.h, cell.w);
@@ -2278,7 +3293,19 @@ impl BookshelfCircuit {
         // These are only used if terminal propagation is enabled
         let mut src_id = 0;
         let mut sink_id = 0;
-        if params.term_prop {
+        let fixed_base = hg.vtxwt.len();
+        if params.term_prop && kway {
+            for r in 0..params.regions.len() {
+                let b = &params.regions[r];
+                let center = if params.horizontal {
+                    (b.lly + b.ury) * 0.5
+                } else {
+                    (b.llx + b.urx) * 0.5
+                };
+                hg.vtxwt.push(1);
+                hg.part.push(if center < params.split_point { 0 } else { 1 });
+            }
+        } else if params.term_prop {
             src_id = hg.vtxwt.len();
             hg.vtxwt.push(1);
             hg.part.push(0);
@@ -2301,6 +3328,9 @@ impl BookshelfCircuit {
             let mut card = 0;
 
             let mut cell_ids = self.cells_unique(*net_id);
+            if params.max_net_degree > 0 && cell_ids.len() > params.max_net_degree {
+                continue;
+            }
 
             for cell_id in &cell_ids {
                 if params.cellmark.marked[*cell_id] {
@@ -2316,27 +3346,20 @@ impl BookshelfCircuit {
             // if card < 3 {
             //     wt = wt + 1;
             // }
-            match params.edgeweight {
-                0 => {
-                    hg.hewt.push(1 as c_int);
-                }
-                1 => {
-                    if sources[params.netmark.index[*net_id]]
-                        || sinks[params.netmark.index[*net_id]]
-                    {
-                        hg.hewt.push(6 as c_int);
-                    } else {
-                        hg.hewt.push(5 as c_int);
-                    }
-                }
-                _ => {
-                    hg.hewt.push(1 as c_int);
-                }
-            }
+            let is_propagated = sources[params.netmark.index[*net_id]] || sinks[params.netmark.index[*net_id]];
+            hg.hewt.push(params.net_weight(self, *net_id, cell_ids.len(), is_propagated));
+            let prop_before = tot_prop;
 
             // Maybe push the source and sink -- and add vertex weights of zero
             // for these, and make them partition location -1
-            if params.term_prop {
+            if params.term_prop && kway {
+                for r in &propagated[params.netmark.index[*net_id]] {
+                    hg.eptr.push((fixed_base + *r) as c_uint);
+                    totfix = totfix + 1;
+                    card = card + 1;
+                    tot_prop = tot_prop + 1;
+                }
+            } else if params.term_prop {
                 if sources[params.netmark.index[*net_id]] {
                     // hg.eptr.push(hg.vtxwt.len() as u32);
                     hg.eptr.push(src_id as u32);
@@ -2357,6 +3380,10 @@ impl BookshelfCircuit {
                 }
             }
 
+            if tot_prop > prop_before {
+                params.propagated += 1;
+            }
+
             // Now push the eptr that ends this (it'll be the eptr for the next net)
             hg.eind.push(hg.eptr.len() as c_ulong);
             if card < 2 && params.term_prop && !warned {
@@ -2381,4 +3408,514 @@ mod tests {
 
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn soft_shapes_in_range() {
+        for area in [5.0, 7.0, 12.0, 30.0, 100.0, 1234.0] {
+            for (lo, hi) in [(0.9, 1.1), (0.5, 2.0), (1.0, 3.0), (2.5, 3.0), (0.3, 0.4)] {
+                for round in [false, true] {
+                    let soft = SoftSize::generate(area, lo, hi, 5, round);
+                    assert!(!soft.alt_sizes.is_empty());
+                    for a in &soft.alt_sizes {
+                        let aspect = a.h / a.w;
+                        assert!(aspect >= lo - 1e-4 && aspect <= hi + 1e-4, "{area} {lo} {hi}: {} by {}", a.w, a.h);
+                        assert!(a.w * a.h >= area - 1e-3);
+                    }
+                }
+            }
+        }
+        // Four rounded shapes, of which two are the same, and one (2 by 6)
+        // is stepped back into range
+        let sizes: Vec<(f32, f32)> =
+            SoftSize::generate(12.0, 0.5, 2.0, 5, true).alt_sizes.iter().map(|a| (a.w, a.h)).collect();
+        assert_eq!(sizes, vec![(5.0, 3.0), (4.0, 3.0), (3.0, 4.0)]);
+        // Rounding a square of area 5 goes to 2 by 3 or 3 by 2, both out
+        // of range, so the shape is left unrounded
+        let soft = SoftSize::generate(5.0, 0.9, 1.1, 3, true);
+        assert_eq!(soft.alt_sizes.len(), 1);
+        let a = soft.alt_sizes[0];
+        assert!((a.w * a.h - 5.0).abs() < 1e-3 && a.w != a.w.round());
+    }
+
+    // Two soft blocks, with up to three rounded shapes each
+    fn soft_blocks() -> BookshelfCircuit {
+        use crate::testutil::TestCircuit;
+        let mut bc = TestCircuit::new()
+            .cell("s0", 4.0, 3.0, 0.0, 0.0)
+            .cell("s1", 2.0, 8.0, 0.0, 0.0)
+            .net("n0", &["s0", "s1"])
+            .build();
+        for c in &mut bc.cells {
+            c.is_soft = true;
+            c.min_aspect = 0.5;
+            c.max_aspect = 2.0;
+        }
+        bc.generate_soft_shapes(3, true);
+        bc
+    }
+
+    #[test]
+    fn plx_round_trip() {
+        use crate::testutil::scratch_dir;
+        let blocks = soft_blocks;
+        let mut bc = blocks();
+        assert!(bc.set_soft_shape(0, 1));
+        bc.cellpos[0].x = 7.0;
+        bc.cellpos[1].y = 5.0;
+        let dir = scratch_dir();
+        let path = dir.join("t.plx").to_string_lossy().to_string();
+        bc.write_plx(&path, &Vec::new());
+
+        let mut back = blocks();
+        back.read_plx(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        for i in 0..2 {
+            assert_eq!((back.cellpos[i].x, back.cellpos[i].y), (bc.cellpos[i].x, bc.cellpos[i].y));
+            assert_eq!((back.cells[i].w, back.cells[i].h), (bc.cells[i].w, bc.cells[i].h));
+        }
+        let (a, b) = (bc.cells[0].soft.as_ref().unwrap(), back.cells[0].soft.as_ref().unwrap());
+        assert_eq!((b.chosen, b.shaped), (a.chosen, true));
+        let b = back.cells[1].soft.as_ref().unwrap();
+        assert_eq!((b.chosen, b.shaped), (None, false));
+        assert_eq!(back.wl(), bc.wl());
+    }
+
+    #[test]
+    fn plx_shape_to_blocks() {
+        use crate::testutil::scratch_dir;
+        let mut bc = soft_blocks();
+        let k = bc.cells[0].soft.as_ref().unwrap().alt_sizes.len() - 1;
+        let alt = bc.cells[0].soft.as_ref().unwrap().alt_sizes[k];
+        assert!(alt.w != alt.h);
+        assert!(bc.set_soft_shape(0, k));
+        bc.set_orientation(0, Orientation::E);
+        let dir = scratch_dir();
+        let path = dir.join("t.plx").to_string_lossy().to_string();
+        bc.write_plx(&path, &Vec::new());
+
+        let mut back = soft_blocks();
+        back.read_plx(&path);
+        let blocks = dir.join("t.blocks").to_string_lossy().to_string();
+        back.write_blocks(&blocks);
+        let text = std::fs::read_to_string(&blocks).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The rotated shape, over the new unrotated size
+        assert!(back.orient[0] == Orientation::E);
+        assert_eq!((back.cells[0].original_w, back.cells[0].original_h), (alt.w, alt.h));
+        assert_eq!((back.cells[0].w, back.cells[0].h), (alt.h, alt.w));
+        assert_eq!((back.cells[0].pins[0].dx, back.cells[0].pins[0].dy), (alt.h * 0.5, alt.w * 0.5));
+        let line = format!(
+            "s0 hardrectilinear 4 (0, 0) (0, {}) ({}, {}) ({}, 0)",
+            alt.h, alt.w, alt.h, alt.w
+        );
+        assert!(text.lines().any(|l| l == line), "{}", text);
+        assert!(text.lines().any(|l| l.starts_with("s1 softrectangular")));
+
+        // Turning it back keeps the shape
+        back.set_orientation(0, Orientation::N);
+        assert_eq!((back.cells[0].w, back.cells[0].h), (alt.w, alt.h));
+    }
+
+    #[test]
+    fn blockpacking_round_trip() {
+        use crate::testutil::scratch_dir;
+        let dir = scratch_dir();
+        let files = [
+            ("t.aux", "BlockPacking : t.blocks t.nets t.pl"),
+            (
+                "t.blocks",
+                "UCSC blocks 1.0\n\nNumSoftRectangularBlocks : 2\nNumHardRectilinearBlocks : 2\nNumTerminals : 1\n\n\
+                 s0 softrectangular 12 0.5 2\ns1 softrectangular 20 0.5 2\n\
+                 h0 hardrectilinear 4 (0, 0) (0, 3) (5, 3) (5, 0)\n\
+                 l0 hardrectilinear 6 (0, 0) (0, 4) (2, 4) (2, 2) (4, 2) (4, 0)\n\np0 terminal\n",
+            ),
+            (
+                "t.nets",
+                "UCLA nets 1.0\nNumNets : 2\nNumPins : 5\n\
+                 NetDegree : 3 n0\n s0 B\n h0 B : 1 0.5\n p0 B\n\
+                 NetDegree : 2 n1\n s1 B\n l0 B : -1 -1\n",
+            ),
+            ("t.pl", "UCLA pl 1.0\ns0 0 0\ns1 6 0\nh0 0 6 : E\nl0 10 0 : FS\np0 20 20\n"),
+        ];
+        for (name, text) in &files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        let mut bc = BookshelfCircuit::read_blockpacking(dir.join("t.aux").to_string_lossy().to_string());
+        let k = bc.cells[0].soft.as_ref().unwrap().alt_sizes.len() - 1;
+        assert!(bc.set_soft_shape(0, k));
+
+        let base = dir.join("out").to_string_lossy().to_string();
+        bc.write_blockpacking(&base);
+        let blocks = std::fs::read_to_string(format!("{}.blocks", base)).unwrap();
+        let back = BookshelfCircuit::read_blockpacking(format!("{}.aux", base));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The shaped soft block comes back as a hard block
+        let count = |key: &str| {
+            let line = blocks.lines().find(|l| l.starts_with(key)).unwrap();
+            line.split(':').nth(1).unwrap().trim().parse::<usize>().unwrap()
+        };
+        assert_eq!(count("NumSoftRectangularBlocks"), 1);
+        assert_eq!(count("NumHardRectilinearBlocks"), 3);
+        assert_eq!(count("NumTerminals"), 1);
+        let soft: Vec<bool> = back.cells.iter().map(|c| c.is_soft).collect();
+        assert_eq!(soft, vec![false, true, false, false, false]);
+        assert!(back.is_blockpacking);
+
+        assert_eq!(back.cells.len(), bc.cells.len());
+        for i in 0..bc.cells.len() {
+            let (c, d) = (&bc.cells[i], &back.cells[i]);
+            assert_eq!(c.name, d.name);
+            assert_eq!(c.terminal, d.terminal);
+            assert_eq!((c.w, c.h), (d.w, d.h), "{}", c.name);
+            assert_eq!((c.original_w, c.original_h), (d.original_w, d.original_h), "{}", c.name);
+            assert_eq!((bc.cellpos[i].x, bc.cellpos[i].y), (back.cellpos[i].x, back.cellpos[i].y));
+            assert!(bc.orient[i] == back.orient[i], "{} is {}", c.name, back.orient[i]);
+        }
+        assert!(back.cells[0].soft.is_none());
+        let alt = bc.cells[0].soft.as_ref().unwrap().alt_sizes[k];
+        assert_eq!((back.cells[0].w, back.cells[0].h), (alt.w, alt.h));
+        let corners = |c: &Cell| c.shape.as_ref().unwrap().original_corners.clone();
+        assert_eq!(corners(&back.cells[3]), corners(&bc.cells[3]));
+
+        assert_eq!(back.nets.len(), bc.nets.len());
+        for (n, m) in bc.nets.iter().zip(&back.nets) {
+            assert_eq!(n.name, m.name);
+            let cells = |bc: &BookshelfCircuit, net: &Net| {
+                net.pins.iter().map(|p| bc.cells[p.parent_cell].name.clone()).collect::<Vec<_>>()
+            };
+            assert_eq!(cells(&bc, n), cells(&back, m));
+        }
+        assert_eq!(back.wl(), bc.wl());
+    }
+
+    // A cell with the outline from a hardrectilinear line
+    fn rectilinear_cell(line: &str) -> Cell {
+        let (name, _count, points) = BookshelfCircuit::parse_rectilinear(&line.to_string()).unwrap();
+        let (shape, w, h) = Rectilinear::from_corners(&points);
+        Cell {
+            name: name,
+            w: w,
+            h: h,
+            original_w: w,
+            original_h: h,
+            min_aspect: 0.0,
+            max_aspect: 0.0,
+            pins: Vec::new(),
+            terminal: false,
+            soft: None,
+            is_macro: false,
+            is_soft: false,
+            can_rotate: false,
+            shape: Some(shape),
+        }
+    }
+
+    fn rects(c: &Cell) -> Vec<(f32, f32, f32, f32)> {
+        c.shape.as_ref().unwrap().rects.iter().map(|r| (r.llx, r.lly, r.urx, r.ury)).collect()
+    }
+
+    #[test]
+    fn rectilinear_l_shape() {
+        // A 4 by 3 L, with the base along the bottom and the upright on
+        // the left
+        let mut c = rectilinear_cell("l0 hardrectilinear 6 (0, 0) (0, 3) (1, 3) (1, 1) (4, 1) (4, 0)");
+        assert_eq!((c.w, c.h), (4.0, 3.0));
+        assert_eq!(rects(&c), vec![(0.0, 0.0, 4.0, 1.0), (0.0, 1.0, 1.0, 3.0)]);
+        assert_eq!(c.area(), 6.0);
+
+        // Turned clockwise, the upright lies along the top
+        BookshelfCircuit::orient_cell(&mut c, Orientation::E);
+        assert_eq!((c.w, c.h), (3.0, 4.0));
+        assert_eq!(rects(&c), vec![(0.0, 0.0, 1.0, 4.0), (1.0, 3.0, 3.0, 4.0)]);
+        assert_eq!(c.area(), 6.0);
+
+        // Mirrored, the upright moves to the right
+        BookshelfCircuit::orient_cell(&mut c, Orientation::FN);
+        assert_eq!((c.w, c.h), (4.0, 3.0));
+        assert_eq!(rects(&c), vec![(0.0, 0.0, 4.0, 1.0), (3.0, 1.0, 4.0, 3.0)]);
+        assert_eq!(c.area(), 6.0);
+    }
+
+    #[test]
+    fn rectilinear_u_shape() {
+        // Offset from the origin; the corners are moved to the lower left
+        let mut c = rectilinear_cell("u0 hardrectilinear 8 (5, 5) (5, 8) (6, 8) (6, 6) (7, 6) (7, 8) (8, 8) (8, 5)");
+        assert_eq!((c.w, c.h), (3.0, 3.0));
+        assert_eq!(c.shape.as_ref().unwrap().corners[1], (0.0, 3.0));
+        assert_eq!(rects(&c), vec![(0.0, 0.0, 3.0, 1.0), (0.0, 1.0, 1.0, 3.0), (2.0, 1.0, 3.0, 3.0)]);
+        assert_eq!(c.area(), 7.0);
+
+        // Upside down, by a flip in Y or by turning half way around
+        for o in [Orientation::FS, Orientation::S] {
+            BookshelfCircuit::orient_cell(&mut c, o);
+            let mut r = rects(&c);
+            r.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(r, vec![(0.0, 0.0, 1.0, 2.0), (0.0, 2.0, 3.0, 3.0), (2.0, 0.0, 3.0, 2.0)]);
+            assert_eq!(c.area(), 7.0);
+        }
+    }
+
+    #[test]
+    fn rectilinear_corner_count_mismatch() {
+        // The count says 8, but there are only the six corners of an L;
+        // the corners listed are used
+        let line = "l1 hardrectilinear 8 (0, 0) (0, 4) (2, 4) (2, 2) (4, 2) (4, 0)".to_string();
+        let (name, count, points) = BookshelfCircuit::parse_rectilinear(&line).unwrap();
+        assert_eq!((name.as_str(), count, points.len()), ("l1", 8, 6));
+        let c = rectilinear_cell(&line);
+        assert_eq!(rects(&c), vec![(0.0, 0.0, 4.0, 2.0), (0.0, 2.0, 2.0, 4.0)]);
+        assert_eq!(c.area(), 12.0);
+
+        assert!(BookshelfCircuit::parse_rectilinear(&"b0 hardrectilinear 4 (0, 0) (0, x)".to_string()).is_none());
+        assert!(BookshelfCircuit::parse_rectilinear(&"b0 softrectangular 12 0.5 2".to_string()).is_none());
+    }
+
+    #[test]
+    fn parse_pin_fields() {
+        let pin = |l: &str| BookshelfCircuit::parse_pin(&l.to_string()).unwrap();
+        assert_eq!(pin(" a I : 1.5 -2 p1"), ("a".to_string(), PinDirection::Input, 1.5, -2.0, "p1".to_string()));
+        assert_eq!(pin(" a O : 1 2"), ("a".to_string(), PinDirection::Output, 1.0, 2.0, "".to_string()));
+        assert_eq!(pin(" a I : 1 2 : p1").4, "p1");
+        assert_eq!(pin(" a : p1"), ("a".to_string(), PinDirection::Bidirectional, 0.0, 0.0, "p1".to_string()));
+        assert_eq!(pin(" a B"), ("a".to_string(), PinDirection::Bidirectional, 0.0, 0.0, "".to_string()));
+        assert!(BookshelfCircuit::parse_pin(&"".to_string()).is_none());
+    }
+
+    // Cell a has pin p twice on n0 (two access points), and p again on
+    // n1 and n2; b has an unnamed pin on each net
+    fn named_pins() -> BookshelfCircuit {
+        use crate::testutil::TestCircuit;
+        TestCircuit::new()
+            .cell("a", 4.0, 2.0, 0.0, 0.0)
+            .cell("b", 2.0, 2.0, 10.0, 0.0)
+            .named("n0", &[("a", "O", -1.0, 0.0, "p"), ("a", "O", 1.0, 0.0, "p"), ("b", "I", 0.0, 0.0, "")])
+            .named("n1", &[("a", "I", 0.0, 0.5, "p"), ("b", "O", 0.0, 0.0, "")])
+            .named("n2", &[("a", "I", 0.0, -0.5, "p"), ("b", "O", 0.0, 0.0, "")])
+            .build()
+    }
+
+    #[test]
+    fn pin_names_merge() {
+        let bc = named_pins();
+        let a = &bc.cells[bc.cell_map["a"]];
+        let names: Vec<&str> = a.pins.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["p", "p_1", "p_2"]);
+        assert_eq!(a.pins[0].details.len(), 2);
+        assert_eq!((a.pins[0].details[1].dx, a.pins[0].details[1].dy), (3.0, 1.0));
+        assert_eq!(bc.nets[0].pins.len(), 2);
+        for (k, p) in a.pins.iter().enumerate() {
+            assert_eq!(p.parent_net, k);
+        }
+    }
+
+    #[test]
+    fn write_nets_round_trip() {
+        use crate::testutil::scratch_dir;
+        let mut bc = named_pins();
+        let a = bc.cell_map["a"];
+        let mut rect = BBox::new();
+        rect.addpoint(0.5, 0.5);
+        rect.addpoint(1.5, 1.5);
+        bc.cells[a].pins[0].details[0].rect = Some(rect);
+        let dir = scratch_dir();
+        let base = dir.join("rt").to_string_lossy().to_string();
+        bc.write_aux(&base);
+        let back = BookshelfCircuit::read_aux(&format!("{}.aux", base));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(back.nets.len(), bc.nets.len());
+        for (c, d) in bc.cells.iter().zip(&back.cells) {
+            assert_eq!(c.pins.len(), d.pins.len());
+            for (p, q) in c.pins.iter().zip(&d.pins) {
+                assert_eq!((&p.name, p.parent_net, p.direction), (&q.name, q.parent_net, q.direction));
+                let points = |pin: &PinInstance| pin.details.iter().map(|d| (d.dx, d.dy)).collect::<Vec<_>>();
+                assert_eq!(points(p), points(q));
+            }
+        }
+        assert_eq!(back.wl(), bc.wl());
+        // The pin shape is not written
+        assert!(back.cells[a].pins[0].details.iter().all(|d| d.rect.is_none()));
+    }
+
+    #[test]
+    fn nearest_access_point() {
+        use crate::testutil::TestCircuit;
+        let mut bc = TestCircuit::new()
+            .cell("a", 4.0, 2.0, 10.0, 0.0)
+            .terminal("b", 1.0, 1.0, 30.0, 0.0)
+            .net("n0", &["a", "b"])
+            .build();
+        let pr = bc.find_pin_by_name(&"a".to_string(), &"".to_string()).unwrap();
+        bc.add_access_point(&pr, 3.5, 1.0, None);
+        assert_eq!(bc.pin_access(&pr), vec![(12.0, 1.0), (13.5, 1.0)]);
+        assert_eq!(bc.pinloc_near(&pr, 30.0, 0.0), (13.5, 1.0));
+        assert_eq!(bc.pinloc_near(&pr, 0.0, 0.0), (12.0, 1.0));
+        let n0 = &bc.nets[0];
+        assert_eq!(bc.net_wl(n0), 19.0);
+        assert_eq!(bc.net_wl_access(n0), 17.5);
+
+        // Mirrored, the extra access point is on the far side
+        bc.set_orientation(pr.parent_cell, Orientation::FN);
+        assert_eq!(bc.pinloc_near(&pr, 30.0, 0.0), (12.0, 1.0));
+        assert_eq!(bc.pinloc_near(&pr, 0.0, 0.0), (10.5, 1.0));
+
+        // The nearest point on a pin shape
+        let mut rect = BBox::new();
+        rect.addpoint(0.0, 0.0);
+        rect.addpoint(1.0, 2.0);
+        bc.add_access_point(&pr, 0.5, 1.0, Some(rect));
+        bc.set_orientation(pr.parent_cell, Orientation::N);
+        assert_eq!(bc.pinloc_near(&pr, 0.0, 0.5), (10.0, 0.5));
+        assert_eq!(bc.wl_access(), bc.net_wl_access(&bc.nets[0]));
+    }
+
+    #[test]
+    fn orientation_group() {
+        let n = Orientation::N;
+        for a in Orientation::ALL {
+            assert!(a.compose(n) == a && n.compose(a) == a);
+            assert!(a.compose(a.inverse()) == n && a.inverse().compose(a) == n);
+            assert!(a.inverse().inverse() == a);
+            assert!(a.flip_x().flip_x() == a && a.flip_y().flip_y() == a);
+            assert!(a.rotate90().rotate90().rotate90().rotate90() == a);
+            assert!(a.flip_x().is_flipped() != a.is_flipped());
+            assert!(a.to_string().parse::<Orientation>() == Ok(a));
+            for b in Orientation::ALL {
+                for c in Orientation::ALL {
+                    assert!(a.compose(b).compose(c) == a.compose(b.compose(c)));
+                }
+            }
+        }
+        assert!(n.rotate90() == Orientation::W);
+        assert!(Orientation::W.rotate90() == Orientation::S);
+        assert!(Orientation::E.inverse() == Orientation::W);
+        assert!(Orientation::FN.compose(Orientation::S) == Orientation::FS);
+    }
+
+    #[test]
+    fn orientation_transform() {
+        let (w, h) = (4.0, 2.0);
+        for a in Orientation::ALL {
+            let (aw, ah) = if a.swaps() { (h, w) } else { (w, h) };
+            // Corners of the cell stay corners of the oriented cell
+            for (x, y) in [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)] {
+                let (tx, ty) = a.transform(x, y, w, h);
+                assert!((tx == 0.0 || tx == aw) && (ty == 0.0 || ty == ah));
+            }
+            let mut pin = BBox::new();
+            pin.addpoint(0.0, 0.0);
+            pin.addpoint(1.0, 0.5);
+            let r = a.transform_rect(&pin, w, h);
+            assert_eq!(r.dx() * r.dy(), 0.5);
+            for b in Orientation::ALL {
+                // Transforming in two steps is the same as the composition
+                let (x, y) = a.transform(1.0, 0.5, w, h);
+                let two = b.transform(x, y, aw, ah);
+                let one = a.compose(b).transform(1.0, 0.5, w, h);
+                assert_eq!(one, two);
+            }
+        }
+    }
+
+    fn weight_circuit() -> BookshelfCircuit {
+        use crate::testutil::TestCircuit;
+        TestCircuit::new()
+            .rows(2, 20, 1)
+            .cell("a", 2.0, 1.0, 0.0, 0.0)
+            .cell("b", 2.0, 1.0, 4.0, 0.0)
+            .cell("c", 2.0, 1.0, 8.0, 0.0)
+            .cell("d", 2.0, 1.0, 12.0, 0.0)
+            .net("n2", &["a", "b"])
+            .net("n3", &["a", "b", "c"])
+            .net("n4", &["a", "b", "c", "d"])
+            .build()
+    }
+
+    #[test]
+    fn net_weights() {
+        use crate::testutil::scratch_dir;
+        let mut bc = weight_circuit();
+        let (n2, n3, n4) = (bc.net_map["n2"], bc.net_map["n3"], bc.net_map["n4"]);
+        let mut params = HyperParams::new(&bc);
+        assert_eq!(params.net_weight(&bc, n3, 3, true), 1);
+
+        // 1/(degree-1) at the default scale of 100, rounded
+        params.edgeweight = EdgeWeight::Clique;
+        assert_eq!(params.net_weight(&bc, n2, 2, false), 100);
+        assert_eq!(params.net_weight(&bc, n3, 3, false), 50);
+        assert_eq!(params.net_weight(&bc, n4, 4, false), 33);
+        assert_eq!(params.net_weight(&bc, n2, 1, false), 100);
+
+        // Weights from the .wts file, with nets not listed at 1.0, and
+        // small weights held at the minimum of 1
+        let dir = scratch_dir();
+        let wts = dir.join("w.wts");
+        std::fs::write(&wts, "UCLA wts 1.0\n\nn3 2.5\nn4 0.004\na 7\n").unwrap();
+        assert_eq!(bc.read_wts(&wts), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+        params.edgeweight = EdgeWeight::NetWeight;
+        assert_eq!(params.net_weight(&bc, n2, 2, false), 100);
+        assert_eq!(params.net_weight(&bc, n3, 3, false), 250);
+        assert_eq!(params.net_weight(&bc, n4, 4, false), 1);
+
+        // 1 + (max_weight - 1) c^2, with c clamped to 0..1
+        let mut criticality = vec![0.0; bc.nets.len()];
+        criticality[n3] = 0.5;
+        criticality[n4] = 2.0;
+        params.edgeweight = EdgeWeight::Criticality { criticality, max_weight: 5.0 };
+        assert_eq!(params.net_weight(&bc, n2, 2, false), 100);
+        assert_eq!(params.net_weight(&bc, n3, 3, false), 200);
+        assert_eq!(params.net_weight(&bc, n4, 4, false), 500);
+        params.weight_scale = 1.0;
+        assert_eq!(params.net_weight(&bc, n3, 3, false), 2);
+
+        params.weight_scale = 0.0;
+        params.edgeweight = EdgeWeight::Custom(Arc::new(|ckt, n| ckt.nets[n].pins.len() as f32 * 0.25));
+        assert_eq!(params.net_weight(&bc, n2, 2, false), 50);
+        assert_eq!(params.net_weight(&bc, n4, 4, false), 100);
+
+        // The params can go to another thread with a custom weighting
+        fn is_send<T: Send>(_: &T) {}
+        is_send(&params);
+    }
+
+    #[test]
+    fn vertex_weights() {
+        let bc = weight_circuit();
+        let (a, d) = (bc.cell_map["a"], bc.cell_map["d"]);
+        let mut params = HyperParams::new(&bc);
+        assert_eq!(params.vertex_weight(&bc.cells[a]), 2);
+        params.vertexweight = VertexWeight::Unit;
+        assert_eq!(params.vertex_weight(&bc.cells[a]), 1);
+        params.vertexweight = VertexWeight::Pins;
+        assert_eq!(params.vertex_weight(&bc.cells[a]), 3);
+        assert_eq!(params.vertex_weight(&bc.cells[d]), 1);
+    }
+
+    #[test]
+    fn max_net_degree_skips_nets() {
+        let bc = weight_circuit();
+        let cells: Vec<usize> = (0..bc.cells.len()).collect();
+        let mut params = HyperParams::new(&bc);
+        params.term_prop = false;
+        params.edgeweight = EdgeWeight::Clique;
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(hg.hewt.len(), 3);
+        assert_eq!(hg.eind.len(), 4);
+        assert_eq!(hg.eptr.len(), 9);
+
+        // The four-cell net is left out; the others are unchanged
+        params.max_net_degree = 3;
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(hg.hewt.len(), 2);
+        assert_eq!(hg.eind.len(), 3);
+        assert_eq!(hg.eptr.len(), 5);
+        let mut hewt = hg.hewt.clone();
+        hewt.sort();
+        assert_eq!(hewt, vec![50, 100]);
+        let d = bc.cell_map["d"] as c_uint;
+        assert!(!hg.eptr.contains(&d));
+    }
 }
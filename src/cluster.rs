@@ -0,0 +1,513 @@
+//! Cell clustering (coarsening) for multilevel placement.
+//!
+//! Cells are merged into clusters based on connectivity.  The score
+//! for merging two clusters is the sum of 1/(d-1) over the nets they
+//! share (d being the net degree), divided by their combined area, so
+//! that small, tightly connected cells go together first.
+//!
+//! First-choice visits the cells in a random order, and merges each
+//! one that has not been visited yet into its best neighbor.
+//! Best-choice keeps a priority queue of the best pair for each cluster,
+//! and always merges the best pair overall; the scores are updated
+//! lazily, as clusters change.  Both stop when the number of clusters
+//! gets down to the target.  Terminals are never merged, and clusters
+//! stay under the area and cell count limits.
+//!
+//! The clusters become the cells of a coarser BookshelfCircuit, with
+//! the nets mapped onto them.  After the coarse circuit is placed,
+//! `uncoarsen` moves the member cells into the area of each cluster.
+//! The coarse circuit can be clustered again, for more levels.
+use crate::bookshelf::{BookshelfCircuit, Row};
+use crate::random::Random;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClusterMode {
+    FirstChoice,
+    BestChoice,
+}
+
+pub struct ClusterParams {
+    pub mode: ClusterMode,
+    /// Stop when the number of clusters is this fraction of the cells
+    pub ratio: f32,
+    /// Cluster area limit, as a multiple of the average movable cell area
+    pub area_limit: f32,
+    /// Maximum number of cells in a cluster
+    pub max_cells: usize,
+    /// Nets with more pins than this are ignored for the scores
+    pub max_net_degree: usize,
+    pub seed: usize,
+    pub verbose: bool,
+}
+
+impl ClusterParams {
+    pub fn new() -> ClusterParams {
+        ClusterParams {
+            mode: ClusterMode::BestChoice,
+            ratio: 0.5,
+            area_limit: 6.0,
+            max_cells: 12,
+            max_net_degree: 50,
+            seed: 1,
+            verbose: false,
+        }
+    }
+}
+
+/// A coarse circuit, and the map between its cells and the original ones
+pub struct Clustering {
+    pub coarse: BookshelfCircuit,
+    /// Coarse cell for each original cell
+    pub cluster_of: Vec<usize>,
+    /// Original cells in each coarse cell
+    pub members: Vec<Vec<usize>>,
+}
+
+// Entry in the best-choice queue.  Stamp is the version of cluster a
+// when the score was computed.  Equal scores pop the lower (a, b) first.
+struct Candidate {
+    score: f32,
+    a: usize,
+    b: usize,
+    stamp: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| (other.a, other.b).cmp(&(self.a, self.b)))
+    }
+}
+
+// Working state while clustering; cluster ids start out as cell ids.
+struct ClusterState {
+    cluster_of: Vec<usize>,
+    members: Vec<Vec<usize>>,
+    area: Vec<f32>,
+    alive: Vec<bool>,
+    fixed: Vec<bool>,
+    stamp: Vec<usize>,
+    count: usize,
+}
+
+impl ClusterState {
+    fn merge(&mut self, a: usize, b: usize) {
+        let moved = std::mem::take(&mut self.members[b]);
+        for m in &moved {
+            self.cluster_of[*m] = a;
+        }
+        self.members[a].extend(moved);
+        self.area[a] = self.area[a] + self.area[b];
+        self.alive[b] = false;
+        self.stamp[a] += 1;
+        self.count -= 1;
+    }
+}
+
+impl BookshelfCircuit {
+    /// Clusters the cells, returning the coarse circuit and the cell map.
+    pub fn cluster(&self, params: &ClusterParams) -> Clustering {
+        let n = self.cells.len();
+        let mut st = ClusterState {
+            cluster_of: (0..n).collect(),
+            members: (0..n).map(|i| vec![i]).collect(),
+            area: self.cells.iter().map(|c| c.area()).collect(),
+            alive: vec![true; n],
+            fixed: self.cells.iter().map(|c| c.terminal).collect(),
+            stamp: vec![0; n],
+            count: n,
+        };
+
+        let movable: Vec<usize> = (0..n).filter(|i| !self.cells[*i].terminal).collect();
+        let mut avg_area = 0.0;
+        if !movable.is_empty() {
+            avg_area = movable.iter().map(|i| st.area[*i]).sum::<f32>() / movable.len() as f32;
+        }
+        let max_area = avg_area * params.area_limit;
+        let target = n - movable.len() + (movable.len() as f32 * params.ratio).ceil() as usize;
+
+        match params.mode {
+            ClusterMode::FirstChoice => {
+                let mut order = movable.clone();
+                let mut rng = Random::new(params.seed);
+                rng.shuffle(&mut order);
+                for v in order {
+                    if st.count <= target {
+                        break;
+                    }
+                    // Already merged with something
+                    if !st.alive[v] || st.members[v].len() > 1 {
+                        continue;
+                    }
+                    if let Some((b, _score)) = self.best_neighbor(&st, v, max_area, params) {
+                        st.merge(b, v);
+                    }
+                }
+            }
+            ClusterMode::BestChoice => {
+                let mut heap = BinaryHeap::new();
+                for v in &movable {
+                    if let Some((b, score)) = self.best_neighbor(&st, *v, max_area, params) {
+                        heap.push(Candidate { score: score, a: *v, b: b, stamp: 0 });
+                    }
+                }
+                while st.count > target {
+                    let cand = match heap.pop() {
+                        Some(c) => c,
+                        None => break,
+                    };
+                    let (a, b) = (cand.a, cand.b);
+                    if !st.alive[a] || st.stamp[a] != cand.stamp {
+                        continue;
+                    }
+                    // Scores go stale as the neighbors change; if this one
+                    // has, requeue it with the current best
+                    if let Some((fb, fscore)) = self.best_neighbor(&st, a, max_area, params) {
+                        if fb != b || fscore < cand.score {
+                            heap.push(Candidate { score: fscore, a: a, b: fb, stamp: st.stamp[a] });
+                            continue;
+                        }
+                        st.merge(a, b);
+                        if let Some((nb, score)) = self.best_neighbor(&st, a, max_area, params) {
+                            heap.push(Candidate { score: score, a: a, b: nb, stamp: st.stamp[a] });
+                        }
+                    }
+                }
+            }
+        }
+
+        let cl = self.build_coarse(&st);
+        if params.verbose {
+            println!(
+                "Clustered {} cells into {} ({:?})",
+                n,
+                cl.coarse.cells.len(),
+                params.mode
+            );
+        }
+        cl
+    }
+
+    // The neighbor cluster with the highest score, within the limits.
+    fn best_neighbor(&self, st: &ClusterState, a: usize, max_area: f32, params: &ClusterParams) -> Option<(usize, f32)> {
+        let mut conn: HashMap<usize, f32> = HashMap::new();
+        for m in &st.members[a] {
+            for pin in &self.cells[*m].pins {
+                let net = &self.nets[pin.parent_net];
+                let d = net.pins.len();
+                if d < 2 || d > params.max_net_degree {
+                    continue;
+                }
+                let w = 1.0 / (d - 1) as f32;
+                for pr in &net.pins {
+                    let b = st.cluster_of[pr.parent_cell];
+                    // Clusters stay on one tier
+                    if b != a && !st.fixed[b] && self.tier_of(pr.parent_cell) == self.tier_of(*m) {
+                        *conn.entry(b).or_insert(0.0) += w;
+                    }
+                }
+            }
+        }
+        let mut best = None;
+        let mut best_score = 0.0;
+        for (b, c) in conn {
+            let area = st.area[a] + st.area[b];
+            if area > max_area || st.members[a].len() + st.members[b].len() > params.max_cells {
+                continue;
+            }
+            let score = c / area.max(f32::MIN_POSITIVE);
+            // Ties go to the lower index, so that runs are repeatable
+            if score > best_score || (score == best_score && best.is_some_and(|bb| b < bb)) {
+                best_score = score;
+                best = Some(b);
+            }
+        }
+        best.map(|b| (b, best_score))
+    }
+
+    // Makes the coarse circuit from the clusters.
+    fn build_coarse(&self, st: &ClusterState) -> Clustering {
+        let mut coarse = BookshelfCircuit::new();
+        coarse.name = format!("{}_coarse", self.name);
+        coarse.unit_x = self.unit_x;
+        coarse.unit_y = self.unit_y;
+        coarse.row_height = self.row_height;
+        coarse.is_blockpacking = self.is_blockpacking;
+        for r in &self.rows {
+            coarse.rows.push(Row {
+                name: r.name.clone(),
+                bounds: r.bounds,
+                site_spacing: r.site_spacing,
+                orient: r.orient,
+                symmetry: r.symmetry.clone(),
+                tier: r.tier,
+            });
+        }
+
+        let mut index = vec![usize::MAX; self.cells.len()];
+        let mut members = Vec::new();
+        for a in 0..self.cells.len() {
+            if !st.alive[a] {
+                continue;
+            }
+            let mem = &st.members[a];
+            let k = members.len();
+            index[a] = k;
+            let first = &self.cells[mem[0]];
+
+            // Standard cell clusters stay one row tall
+            let max_h = mem.iter().map(|m| self.cells[*m].h).fold(0.0, f32::max);
+            let (w, h);
+            if mem.len() == 1 {
+                // Unrotated; set_orientation below turns it like the member
+                w = first.original_w;
+                h = first.original_h;
+            } else if self.row_height > 0.0 && max_h <= self.row_height {
+                h = self.row_height;
+                w = st.area[a] / h;
+            } else {
+                h = max_h;
+                w = st.area[a] / h;
+            }
+
+            // Clusters are named after the first member, with a number
+            // added if that name is taken ('+' is legal in cell names)
+            let name = if mem.len() == 1 {
+                first.name.clone()
+            } else {
+                let mut name = format!("{}+{}", first.name, mem.len());
+                let mut n = 0;
+                while self.cell_map.contains_key(&name) || coarse.cell_map.contains_key(&name) {
+                    n += 1;
+                    name = format!("{}+{}_{}", first.name, mem.len(), n);
+                }
+                name
+            };
+            let c = coarse.add_cell(&name, w, h, first.terminal).unwrap();
+
+            // Area weighted center of the members
+            let mut cx = 0.0;
+            let mut cy = 0.0;
+            let mut total = 0.0;
+            for m in mem {
+                let cell = &self.cells[*m];
+                let wt = cell.area().max(f32::MIN_POSITIVE);
+                cx = cx + (self.cellpos[*m].x + cell.w * 0.5) * wt;
+                cy = cy + (self.cellpos[*m].y + cell.h * 0.5) * wt;
+                total = total + wt;
+            }
+            coarse.cellpos[c].x = cx / total - w * 0.5;
+            coarse.cellpos[c].y = cy / total - h * 0.5;
+            if mem.len() == 1 {
+                coarse.cellpos[c] = self.cellpos[mem[0]].clone();
+                coarse.set_orientation(c, self.orient[mem[0]]);
+            }
+            if self.tier.is_some() {
+                coarse.set_tier(c, self.tier_of(mem[0]));
+            }
+            members.push(mem.clone());
+        }
+        let cluster_of: Vec<usize> = st.cluster_of.iter().map(|a| index[*a]).collect();
+        coarse.num_tiers = self.num_tiers;
+        coarse.via_cost = self.via_cost;
+
+        // Nets on two or more clusters, with pins at the cluster centers
+        for net in &self.nets {
+            let mut clusters: Vec<usize> = net.pins.iter().map(|pr| cluster_of[pr.parent_cell]).collect();
+            clusters.sort();
+            clusters.dedup();
+            if clusters.len() < 2 {
+                continue;
+            }
+            let ni = coarse.add_net(&net.name).unwrap();
+            coarse.nets[ni].weight = net.weight;
+            for c in clusters {
+                let (w, h) = (coarse.cells[c].original_w, coarse.cells[c].original_h);
+                coarse.connect(ni, c, &"".to_string(), w * 0.5, h * 0.5).unwrap();
+            }
+        }
+
+        coarse.notes.push(format!(
+            "Clustered {}: {} cells into {}",
+            self.name,
+            self.cells.len(),
+            coarse.cells.len()
+        ));
+        Clustering {
+            coarse: coarse,
+            cluster_of: cluster_of,
+            members: members,
+        }
+    }
+
+    /// Moves the member cells into the area of their (placed) clusters.
+    /// Members are packed left to right, starting a new line when the
+    /// cluster width is used up.  Terminals are not moved.
+    pub fn uncoarsen(&mut self, cl: &Clustering) {
+        for k in 0..cl.members.len() {
+            let p = &cl.coarse.cellpos[k];
+            let w = cl.coarse.cells[k].w;
+            if cl.members[k].len() == 1 {
+                let m = cl.members[k][0];
+                if !self.cells[m].terminal {
+                    self.cellpos[m] = p.clone();
+                    if self.orient[m] != cl.coarse.orient[k] {
+                        self.set_orientation(m, cl.coarse.orient[k]);
+                    }
+                }
+                continue;
+            }
+            let mut x = p.x;
+            let mut y = p.y;
+            let mut line_h: f32 = 0.0;
+            for m in &cl.members[k] {
+                let m = *m;
+                if self.cells[m].terminal {
+                    continue;
+                }
+                let mw = self.cells[m].w;
+                if x + mw > p.x + w + 0.001 && x > p.x {
+                    x = p.x;
+                    y = y + line_h;
+                    line_h = 0.0;
+                }
+                self.cellpos[m].x = x;
+                self.cellpos[m].y = y;
+                x = x + mw;
+                line_h = line_h.max(self.cells[m].h);
+            }
+        }
+    }
+
+    /// Colors each cell by its cluster, for ps_color_cells.
+    pub fn color_clusters(&mut self, cl: &Clustering) {
+        self.cell_color = Some(cl.cluster_of.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    // A chain of 16 cells, widths 1 and 2, between terminals t0 and t1
+    fn chain() -> BookshelfCircuit {
+        let mut tc = TestCircuit::new()
+            .rows(2, 40, 1)
+            .terminal("t0", 1.0, 1.0, 0.0, 0.0)
+            .terminal("t1", 1.0, 1.0, 39.0, 0.0);
+        let mut x = 1.0;
+        for i in 0..16 {
+            let w = (1 + i % 2) as f32;
+            tc = tc.cell(&format!("c{i}"), w, 1.0, x, 0.0);
+            x += w;
+        }
+        tc = tc.net("n0", &["t0", "c0"]).net("n16", &["c15", "t1"]);
+        for i in 0..15 {
+            tc = tc.net(&format!("n{}", i + 1), &[&format!("c{i}"), &format!("c{}", i + 1)]);
+        }
+        tc.build()
+    }
+
+    #[test]
+    fn candidate_ties() {
+        let mut heap = BinaryHeap::new();
+        for (a, b) in [(3, 1), (1, 4), (1, 2), (2, 0)] {
+            heap.push(Candidate { score: 1.0, a: a, b: b, stamp: 0 });
+        }
+        heap.push(Candidate { score: 0.5, a: 0, b: 1, stamp: 0 });
+        let order: Vec<(usize, usize)> = std::iter::from_fn(|| heap.pop()).map(|c| (c.a, c.b)).collect();
+        assert_eq!(order, vec![(1, 2), (1, 4), (2, 0), (3, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn cluster_limits() {
+        let bc = chain();
+        for mode in [ClusterMode::FirstChoice, ClusterMode::BestChoice] {
+            let mut params = ClusterParams::new();
+            params.mode = mode;
+            params.area_limit = 3.0;
+            params.max_cells = 3;
+            let cl = bc.cluster(&params);
+            // 2 terminals, and half of the 16 cells
+            assert_eq!(cl.coarse.cells.len(), 10);
+            assert_eq!(cl.members.len(), 10);
+            for (k, mem) in cl.members.iter().enumerate() {
+                let area: f32 = mem.iter().map(|m| bc.cells[*m].area()).sum();
+                assert!(area <= 1.5 * 3.0);
+                assert!(mem.len() <= 3);
+                if mem.iter().any(|m| bc.cells[*m].terminal) {
+                    assert_eq!(mem.len(), 1);
+                    assert!(cl.coarse.cells[k].terminal);
+                }
+                for m in mem {
+                    assert_eq!(cl.cluster_of[*m], k);
+                }
+            }
+            assert_eq!(cl.members.iter().map(|m| m.len()).sum::<usize>(), bc.cells.len());
+
+            // Same clusters again for the same seed
+            assert_eq!(bc.cluster(&params).members, cl.members);
+        }
+    }
+
+    #[test]
+    fn uncoarsen_into_clusters() {
+        let mut bc = chain();
+        let mut cl = bc.cluster(&ClusterParams::new());
+        // Move every movable cluster up a row
+        for k in 0..cl.coarse.cells.len() {
+            if !cl.coarse.cells[k].terminal {
+                cl.coarse.cellpos[k].y += 1.0;
+            }
+        }
+        bc.uncoarsen(&cl);
+        for (c, k) in cl.cluster_of.iter().enumerate() {
+            let (p, cc) = (&cl.coarse.cellpos[*k], &cl.coarse.cells[*k]);
+            if bc.cells[c].terminal {
+                assert_eq!(bc.cellpos[c].y, 0.0);
+                continue;
+            }
+            assert!(bc.cellpos[c].x >= p.x && bc.cellpos[c].x + bc.cells[c].w <= p.x + cc.w + 0.001);
+            assert_eq!(bc.cellpos[c].y, p.y);
+        }
+    }
+
+    #[test]
+    fn cluster_names_unique() {
+        // Cells already named like the clusters would be
+        let bc = TestCircuit::new()
+            .rows(1, 20, 1)
+            .cell("a", 1.0, 1.0, 0.0, 0.0)
+            .cell("b", 1.0, 1.0, 1.0, 0.0)
+            .cell("a+2", 1.0, 1.0, 2.0, 0.0)
+            .cell("b+2", 1.0, 1.0, 3.0, 0.0)
+            .net("n0", &["a", "b"])
+            .net("n1", &["a+2", "b+2"])
+            .build();
+        let cl = bc.cluster(&ClusterParams::new());
+        assert_eq!(cl.coarse.cells.len(), 2);
+        let names: Vec<&String> = cl.coarse.cells.iter().map(|c| &c.name).collect();
+        assert!(names[0] != names[1]);
+        for name in names {
+            assert!(!bc.cell_map.contains_key(name), "{name}");
+            assert_eq!(&cl.coarse.cells[cl.coarse.cell_map[name]].name, name);
+        }
+    }
+}
@@ -0,0 +1,649 @@
+//! Detailed placement.
+//!
+//! These passes start from a legal placement (after the legalizer),
+//! and try to reduce wire length while keeping everything legal.
+//!
+//! * Global swap -- a cell is moved towards its optimal region,
+//!   swapping with a cell of the same width, or into an open gap.
+//!   A cell that changes rows takes an orientation the new row allows.
+//! * Vertical swap -- the same idea, but only to the rows directly
+//!   above or below.
+//! * Local reordering -- a sliding window of 3 or 4 adjacent cells
+//!   in a segment, trying all of the orderings.
+//! * Single segment clustering -- the cells of a segment keep their
+//!   order, but are shifted to their optimal positions, with
+//!   overlapping cells clustered together (much like Abacus).
+//! * Flipping -- cells are mirrored (N to FN, FS to S, and so on),
+//!   which keeps them legal in the row, but moves the pins.
+//!
+//! All of the wire length changes are computed incrementally, with a
+//! WlCalc for the nets connected to the cells that are moving.
+use crate::bookshelf::{BookshelfCircuit, WlCalc};
+use crate::legalizer::RowSegment;
+
+pub struct DetailParams {
+    pub passes: usize,
+    /// Number of cells in the reordering window (3 or 4 are reasonable;
+    /// clamped to 2..=5, as every permutation is tried)
+    pub window: usize,
+    pub global_swap: bool,
+    pub vertical_swap: bool,
+    pub reorder: bool,
+    pub clustering: bool,
+    pub flip: bool,
+    pub verbose: bool,
+}
+
+impl DetailParams {
+    pub fn new() -> DetailParams {
+        DetailParams {
+            passes: 2,
+            window: 3,
+            global_swap: true,
+            vertical_swap: true,
+            reorder: true,
+            clustering: true,
+            flip: true,
+            verbose: false,
+        }
+    }
+}
+
+/// The cells in each row segment, sorted by X.  seg_of gives the
+/// segment for each cell (or None for terminals, macros, and cells
+/// that are not inside a segment).
+pub struct SegmentMap {
+    pub segments: Vec<RowSegment>,
+    pub cells: Vec<Vec<usize>>,
+    pub seg_of: Vec<Option<usize>>,
+}
+
+impl SegmentMap {
+    pub fn new(bc: &BookshelfCircuit) -> SegmentMap {
+        let segments = bc.row_segments(true);
+        let mut cells = vec![Vec::new(); segments.len()];
+        let mut seg_of = vec![None; bc.cells.len()];
+        for i in 0..bc.cells.len() {
+            let c = &bc.cells[i];
+            if c.terminal || c.is_macro {
+                continue;
+            }
+            let p = &bc.cellpos[i];
+            for s in 0..segments.len() {
+                let seg = &segments[s];
+                if seg.y == p.y && seg.tier == bc.tier_of(i) && p.x >= seg.llx && p.x + c.w <= seg.urx {
+                    cells[s].push(i);
+                    seg_of[i] = Some(s);
+                    break;
+                }
+            }
+        }
+        for list in &mut cells {
+            list.sort_by(|a, b| bc.cellpos[*a].x.partial_cmp(&bc.cellpos[*b].x).unwrap());
+        }
+        SegmentMap {
+            segments: segments,
+            cells: cells,
+            seg_of: seg_of,
+        }
+    }
+
+    /// The segment in the row nearest to y that contains x, on the
+    /// given tier.
+    pub fn find(&self, x: f32, y: f32, tier: usize) -> Option<usize> {
+        let mut best = None;
+        let mut best_d = f32::MAX;
+        for s in 0..self.segments.len() {
+            let seg = &self.segments[s];
+            if seg.tier != tier {
+                continue;
+            }
+            let dy = (seg.y - y).abs();
+            let dx = (seg.llx - x).max(x - seg.urx).max(0.0);
+            if dx + dy < best_d {
+                best_d = dx + dy;
+                best = Some(s);
+            }
+        }
+        best
+    }
+
+    /// Position of a cell within its segment list, or None if the cell
+    /// is not in the list.
+    fn index(&self, bc: &BookshelfCircuit, seg: usize, cell: usize) -> Option<usize> {
+        let x = bc.cellpos[cell].x;
+        let list = &self.cells[seg];
+        let start = list.partition_point(|c| bc.cellpos[*c].x < x);
+        // Cells at the same X follow in any order; fall back to a full
+        // scan if the list is not sorted the way we expect.
+        list[start..]
+            .iter()
+            .take_while(|c| bc.cellpos[**c].x == x)
+            .position(|c| *c == cell)
+            .map(|i| start + i)
+            .or_else(|| list.iter().position(|c| *c == cell))
+    }
+
+    /// Range of legal X positions for a cell of width w, placed in the
+    /// gap after position idx of the segment list.
+    fn gap(&self, bc: &BookshelfCircuit, seg: usize, idx: Option<usize>) -> (f32, f32) {
+        let list = &self.cells[seg];
+        let s = &self.segments[seg];
+        let left;
+        let next;
+        match idx {
+            Some(i) => {
+                left = bc.cellpos[list[i]].x + bc.cells[list[i]].w;
+                next = i + 1;
+            }
+            None => {
+                left = s.llx;
+                next = 0;
+            }
+        }
+        let mut right = s.urx;
+        if next < list.len() {
+            right = bc.cellpos[list[next]].x;
+        }
+        (left, right)
+    }
+}
+
+impl BookshelfCircuit {
+    /// Runs the detailed placement passes, returning the total reduction
+    /// in wire length.
+    pub fn detail_place(&mut self, params: &DetailParams) -> f32 {
+        let start = self.wl();
+        let mut map = SegmentMap::new(self);
+        let mut wlc = WlCalc::new(self);
+
+        for pass in 0..params.passes {
+            let before = self.wl();
+            if params.global_swap {
+                self.global_swap(&mut map, &mut wlc, false);
+            }
+            if params.vertical_swap {
+                self.global_swap(&mut map, &mut wlc, true);
+            }
+            if params.reorder {
+                self.reorder(&mut map, &mut wlc, params.window);
+            }
+            if params.clustering {
+                self.segment_clustering(&mut map, &mut wlc);
+            }
+            if params.flip {
+                self.flip_cells(&mut wlc);
+            }
+            let after = self.wl();
+            if params.verbose {
+                println!("Detail pass {}: HPWL {} -> {}", pass, before, after);
+            }
+            if after >= before {
+                break;
+            }
+        }
+        let end = self.wl();
+        self.notes.push(format!("Detail placement: HPWL {} -> {}", start, end));
+        start - end
+    }
+
+    /// The optimal location for the center of a cell: the median of the
+    /// bounding box edges of the connected nets, not counting the cell
+    /// itself.
+    pub fn optimal_region(&self, cell: usize) -> (f32, f32) {
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+        for p in &self.cells[cell].pins {
+            let net = &self.nets[p.parent_net];
+            let mut first = true;
+            let (mut llx, mut lly, mut urx, mut ury) = (0.0, 0.0, 0.0, 0.0);
+            for pr in &net.pins {
+                if pr.parent_cell == cell {
+                    continue;
+                }
+                let (px, py) = self.pinloc(pr);
+                if first {
+                    llx = px;
+                    urx = px;
+                    lly = py;
+                    ury = py;
+                    first = false;
+                } else {
+                    llx = f32::min(llx, px);
+                    urx = f32::max(urx, px);
+                    lly = f32::min(lly, py);
+                    ury = f32::max(ury, py);
+                }
+            }
+            if !first {
+                xs.push(llx);
+                xs.push(urx);
+                ys.push(lly);
+                ys.push(ury);
+            }
+        }
+        if xs.is_empty() {
+            return (
+                self.cellpos[cell].x + self.cells[cell].w * 0.5,
+                self.cellpos[cell].y + self.cells[cell].h * 0.5,
+            );
+        }
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        ys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let m = xs.len() / 2;
+        ((xs[m - 1] + xs[m]) * 0.5, (ys[m - 1] + ys[m]) * 0.5)
+    }
+
+    // Wire length of the nets on a set of cells.
+    fn cells_wl(&self, wlc: &mut WlCalc, cells: &Vec<usize>) -> f32 {
+        wlc.clear();
+        wlc.add_cells(self, cells);
+        wlc.wl(self)
+    }
+
+    fn global_swap(&mut self, map: &mut SegmentMap, wlc: &mut WlCalc, vertical: bool) -> usize {
+        let mut moves = 0;
+        for c in 0..self.cells.len() {
+            let seg = match map.seg_of[c] {
+                Some(s) => s,
+                None => continue,
+            };
+            let (ox, oy) = self.optimal_region(c);
+            let w = self.cells[c].w;
+            let h = self.cells[c].h;
+            let cx = self.cellpos[c].x;
+            let cy = self.cellpos[c].y;
+            if (ox - (cx + w * 0.5)).abs() < w && (oy - (cy + h * 0.5)).abs() < h * 0.5 {
+                continue;
+            }
+
+            let target = if vertical {
+                // Only the row directly above or below, at the same X
+                let row_h = map.segments[seg].height;
+                let ty = if oy > cy + h { cy + row_h } else if oy < cy { cy - row_h } else { continue };
+                map.find(cx + w * 0.5, ty, map.segments[seg].tier)
+            } else {
+                map.find(ox, oy - h * 0.5, map.segments[seg].tier)
+            };
+            let target = match target {
+                Some(t) => t,
+                None => continue,
+            };
+            let tx = if vertical { cx } else { ox - w * 0.5 };
+            let tseg_y = map.segments[target].y;
+            if h > map.segments[target].height {
+                continue;
+            }
+
+            // Candidates: same width cells around the target position,
+            // and the gaps next to them.
+            let list_pos = map.cells[target].partition_point(|o| self.cellpos[*o].x < tx);
+            let lo = list_pos.saturating_sub(2);
+            let hi = (list_pos + 2).min(map.cells[target].len());
+
+            let mut best_gain = 0.0;
+            let mut best_move: Option<(Option<usize>, f32)> = None; // (swap cell, or gap x)
+            for i in lo..hi {
+                let o = map.cells[target][i];
+                if o == c || self.cells[o].w != w || self.cells[o].h != h {
+                    continue;
+                }
+                let pair = vec![c, o];
+                let before = self.cells_wl(wlc, &pair);
+                self.swap_positions(c, o);
+                let after = wlc.wl(self);
+                self.swap_positions(c, o);
+                if before - after > best_gain {
+                    best_gain = before - after;
+                    best_move = Some((Some(o), 0.0));
+                }
+            }
+            let first_gap = if lo == 0 { None } else { Some(lo - 1) };
+            let mut gaps = vec![first_gap];
+            for i in lo..hi {
+                gaps.push(Some(i));
+            }
+            for g in gaps {
+                let (left, right) = map.gap(self, target, g);
+                if right - left < w {
+                    continue;
+                }
+                let x = match self.fit_gap(&map.segments[target], left, right, w, tx) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let single = vec![c];
+                let before = self.cells_wl(wlc, &single);
+                self.cellpos[c].x = x;
+                self.cellpos[c].y = tseg_y;
+                let after = wlc.wl(self);
+                self.cellpos[c].x = cx;
+                self.cellpos[c].y = cy;
+                if before - after > best_gain {
+                    best_gain = before - after;
+                    best_move = Some((None, x));
+                }
+            }
+
+            match best_move {
+                Some((Some(o), _)) => {
+                    let (ci, oi) = match (map.index(self, seg, c), map.index(self, target, o)) {
+                        (Some(ci), Some(oi)) => (ci, oi),
+                        _ => continue,
+                    };
+                    self.swap_positions(c, o);
+                    self.set_orientation(c, self.row_orientation(c, map.segments[target].row));
+                    self.set_orientation(o, self.row_orientation(o, map.segments[seg].row));
+                    map.cells[seg][ci] = o;
+                    map.cells[target][oi] = c;
+                    map.seg_of[c] = Some(target);
+                    map.seg_of[o] = Some(seg);
+                    moves += 1;
+                }
+                Some((None, x)) => {
+                    let ci = match map.index(self, seg, c) {
+                        Some(ci) => ci,
+                        None => continue,
+                    };
+                    map.cells[seg].remove(ci);
+                    self.cellpos[c].x = x;
+                    self.cellpos[c].y = tseg_y;
+                    self.set_orientation(c, self.row_orientation(c, map.segments[target].row));
+                    let ti = map.cells[target].partition_point(|o| self.cellpos[*o].x < x);
+                    map.cells[target].insert(ti, c);
+                    map.seg_of[c] = Some(target);
+                    moves += 1;
+                }
+                None => {}
+            }
+        }
+        moves
+    }
+
+    fn swap_positions(&mut self, a: usize, b: usize) {
+        let (ax, ay) = (self.cellpos[a].x, self.cellpos[a].y);
+        self.cellpos[a].x = self.cellpos[b].x;
+        self.cellpos[a].y = self.cellpos[b].y;
+        self.cellpos[b].x = ax;
+        self.cellpos[b].y = ay;
+    }
+
+    // Site aligned position closest to tx, for a cell of width w in the
+    // open span from left to right.  None if no site in the span fits
+    // the cell (the ends of a gap need not be on the site grid).
+    fn fit_gap(&self, seg: &RowSegment, left: f32, right: f32, w: f32, tx: f32) -> Option<f32> {
+        let mut x = tx.max(left).min(right - w);
+        if seg.site_spacing > 0.0 {
+            let site = seg.site_spacing;
+            x = seg.origin + ((x - seg.origin) / site).round() * site;
+            if x < left {
+                x = x + site;
+            }
+            if x + w > right {
+                x = x - site;
+            }
+        }
+        if x >= left && x + w <= right {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
+    fn reorder(&mut self, map: &mut SegmentMap, wlc: &mut WlCalc, window: usize) -> usize {
+        let window = window.max(2).min(5);
+        let perms = permutations(window);
+        let mut moves = 0;
+        for s in 0..map.cells.len() {
+            if map.cells[s].len() < window {
+                continue;
+            }
+            for start in 0..=(map.cells[s].len() - window) {
+                let cells: Vec<usize> = map.cells[s][start..start + window].to_vec();
+                let x0 = self.cellpos[cells[0]].x;
+                let orig: Vec<f32> = cells.iter().map(|c| self.cellpos[*c].x).collect();
+                let before = self.cells_wl(wlc, &cells);
+                let mut best = before;
+                let mut best_perm = 0;
+                for (pi, perm) in perms.iter().enumerate().skip(1) {
+                    let mut x = x0;
+                    for k in perm {
+                        self.cellpos[cells[*k]].x = x;
+                        x = x + self.cells[cells[*k]].w;
+                    }
+                    let after = wlc.wl(self);
+                    if after < best {
+                        best = after;
+                        best_perm = pi;
+                    }
+                }
+                if best_perm == 0 {
+                    for k in 0..window {
+                        self.cellpos[cells[k]].x = orig[k];
+                    }
+                    continue;
+                }
+                let mut x = x0;
+                for (k, idx) in perms[best_perm].iter().enumerate() {
+                    let c = cells[*idx];
+                    self.cellpos[c].x = x;
+                    x = x + self.cells[c].w;
+                    map.cells[s][start + k] = c;
+                }
+                moves += 1;
+            }
+        }
+        moves
+    }
+
+    fn segment_clustering(&mut self, map: &mut SegmentMap, wlc: &mut WlCalc) -> usize {
+        let mut improved = 0;
+        for s in 0..map.cells.len() {
+            let list = map.cells[s].clone();
+            if list.is_empty() {
+                continue;
+            }
+            let seg = &map.segments[s];
+            let orig: Vec<f32> = list.iter().map(|c| self.cellpos[*c].x).collect();
+            let before = self.cells_wl(wlc, &list);
+
+            // Clusters hold (x, total weight, q, width, first index)
+            let mut clusters: Vec<(f32, f32, f32, f32, usize)> = Vec::new();
+            for (i, c) in list.iter().enumerate() {
+                let w = self.cells[*c].w;
+                let (ox, _oy) = self.optimal_region(*c);
+                let tx = ox - w * 0.5;
+                let mut merged = false;
+                if let Some(last) = clusters.last_mut() {
+                    if last.0 + last.3 > tx {
+                        last.1 = last.1 + 1.0;
+                        last.2 = last.2 + (tx - last.3);
+                        last.3 = last.3 + w;
+                        merged = true;
+                    }
+                }
+                if !merged {
+                    clusters.push((tx, 1.0, tx, w, i));
+                }
+                loop {
+                    let k = clusters.len() - 1;
+                    let x = seg.snap(clusters[k].2 / clusters[k].1, clusters[k].3);
+                    clusters[k].0 = x;
+                    if k > 0 && clusters[k - 1].0 + clusters[k - 1].3 > x {
+                        let cl = clusters.pop().unwrap();
+                        let prev = clusters.last_mut().unwrap();
+                        prev.2 = prev.2 + cl.2 - cl.1 * prev.3;
+                        prev.1 = prev.1 + cl.1;
+                        prev.3 = prev.3 + cl.3;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            for k in 0..clusters.len() {
+                let end = if k + 1 < clusters.len() { clusters[k + 1].4 } else { list.len() };
+                let mut x = clusters[k].0;
+                for i in clusters[k].4..end {
+                    self.cellpos[list[i]].x = x;
+                    x = x + self.cells[list[i]].w;
+                }
+            }
+            let after = wlc.wl(self);
+            if after < before {
+                improved += 1;
+            } else {
+                for i in 0..list.len() {
+                    self.cellpos[list[i]].x = orig[i];
+                }
+            }
+        }
+        improved
+    }
+
+    /// Mirrors cells left-to-right if that reduces wire length, and the
+    /// row allows the mirrored orientation.  The cell footprint does
+    /// not change, so the placement stays legal.
+    pub fn flip_cells(&mut self, wlc: &mut WlCalc) -> usize {
+        let mut flips = 0;
+        for c in 0..self.cells.len() {
+            if self.cells[c].terminal || self.cells[c].is_macro {
+                continue;
+            }
+            let current = self.orient[c];
+            let flipped = current.flip_x();
+            match self.row_at(self.cellpos[c].x, self.cellpos[c].y) {
+                Some(r) if self.rows[r].allows(flipped) => {}
+                _ => continue,
+            }
+            let single = vec![c];
+            let before = self.cells_wl(wlc, &single);
+            self.set_orientation(c, flipped);
+            let after = wlc.wl(self);
+            if after < before {
+                flips += 1;
+            } else {
+                self.set_orientation(c, current);
+            }
+        }
+        flips
+    }
+}
+
+// All orderings of 0..n, with the identity first.
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    let mut result = Vec::new();
+    let mut current: Vec<usize> = (0..n).collect();
+    permute(&mut current, 0, &mut result);
+    result
+}
+
+fn permute(v: &mut Vec<usize>, k: usize, result: &mut Vec<Vec<usize>>) {
+    if k == v.len() {
+        result.push(v.clone());
+        return;
+    }
+    for i in k..v.len() {
+        v.swap(k, i);
+        permute(v, k + 1, result);
+        v.swap(k, i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bookshelf::Orientation;
+    use crate::legalizer::LegalizerParams;
+    use crate::testutil::{assert_legal, TestCircuit};
+
+    // Four rows of 40 sites, with 16 cells legalized, and tied to
+    // terminals at the corners (with the pins off center, so that
+    // flipping matters)
+    fn unplaced() -> BookshelfCircuit {
+        let mut tc = TestCircuit::new()
+            .rows(4, 40, 10)
+            .terminal("t0", 1.0, 1.0, -5.0, -5.0)
+            .terminal("t1", 1.0, 1.0, 45.0, -5.0)
+            .terminal("t2", 1.0, 1.0, -5.0, 45.0)
+            .terminal("t3", 1.0, 1.0, 45.0, 45.0);
+        let widths = [2.0, 3.0, 4.0, 5.0, 6.0];
+        for i in 0..16 {
+            let x = ((i * 7) % 30) as f32;
+            let y = (10 * (i % 4)) as f32;
+            tc = tc.cell(&format!("c{i}"), widths[i % 5], 10.0, x, y);
+        }
+        for k in 0..16 {
+            let a = format!("c{k}");
+            let b = format!("c{}", (k * 5 + 3) % 16);
+            let t = format!("t{}", k % 4);
+            tc = tc.pins(&format!("n{k}"), &[(&a, "B", 0.8, 0.0), (&b, "B", -0.8, 0.0), (&t, "B", 0.0, 0.0)]);
+        }
+        tc.build()
+    }
+
+    fn placed() -> BookshelfCircuit {
+        let mut bc = unplaced();
+        bc.legalize(&LegalizerParams::new());
+        assert_legal(&bc);
+        bc
+    }
+
+    fn only(pass: &str) -> DetailParams {
+        let mut params = DetailParams::new();
+        params.global_swap = pass == "global";
+        params.vertical_swap = pass == "vertical";
+        params.reorder = pass == "reorder";
+        params.clustering = pass == "cluster";
+        params.flip = pass == "flip";
+        params
+    }
+
+    #[test]
+    fn passes_stay_legal() {
+        for pass in ["global", "vertical", "reorder", "cluster", "flip"] {
+            let mut bc = placed();
+            let before = bc.wl();
+            let gain = bc.detail_place(&only(pass));
+            assert!(gain >= -1e-3, "{pass} increased HPWL");
+            assert!(bc.wl() <= before + 1e-3, "{pass} increased HPWL");
+            assert_legal(&bc);
+        }
+        let mut bc = placed();
+        assert!(bc.detail_place(&DetailParams::new()) > 0.0);
+        assert_legal(&bc);
+    }
+
+    #[test]
+    fn flip_needs_row_symmetry() {
+        let mut bc = placed();
+        for r in &mut bc.rows {
+            r.symmetry = "".to_string();
+        }
+        assert_eq!(bc.detail_place(&only("flip")), 0.0);
+        assert!(bc.orient.iter().all(|o| *o == Orientation::N));
+    }
+
+    #[test]
+    fn narrow_gap_off_grid() {
+        let bc = TestCircuit::new().rows(1, 20, 10).build();
+        let seg = &bc.row_segments(true)[0];
+        // The gap is wider than the cell, but no site in it fits
+        assert_eq!(bc.fit_gap(seg, 3.5, 6.2, 2.5, 4.0), None);
+        assert_eq!(bc.fit_gap(seg, 3.5, 7.0, 2.5, 4.0), Some(4.0));
+        assert_eq!(bc.fit_gap(seg, 3.5, 7.0, 2.5, 0.0), Some(4.0));
+    }
+
+    #[test]
+    fn alternating_rows_keep_orientation() {
+        // N and FS rows, as the generator makes them
+        let mut bc = unplaced();
+        for (r, row) in bc.rows.iter_mut().enumerate() {
+            row.orient = if r % 2 == 0 { Orientation::N } else { Orientation::FS };
+        }
+        bc.legalize(&LegalizerParams::new());
+        assert!(bc.check_row_orientations().is_empty());
+        assert!(bc.detail_place(&DetailParams::new()) > 0.0);
+        assert!(bc.check_row_orientations().is_empty());
+        assert_legal(&bc);
+    }
+}
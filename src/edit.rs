@@ -0,0 +1,423 @@
+//! Netlist editing.
+//!
+//! The BookshelfCircuit fields are public, but a cell or net touches
+//! several of them at once: `cells`, `cellpos`, `orient` (and `refpos`,
+//! `cell_color`, and `tier`, if present), the name maps, the PinRefs on the
+//! nets, and the PinInstances on the cells.  These methods keep all of
+//! that consistent, so that `wl`, `build_graph`, and the writers work
+//! after any edit.
+//!
+//! Pin offsets passed to `connect` are from the lower left corner of
+//! the unrotated cell (like the PinDetail offsets).  Removing cells or
+//! nets renumbers everything after them; disconnecting pins can leave
+//! empty nets behind, and `compact` clears those out.
+use crate::bookshelf::{BookshelfCircuit, Cell, Net, Orientation, PinDetail, PinDirection, PinInstance, PinRef};
+use pstools::bbox::BBox;
+use pstools::point;
+
+impl BookshelfCircuit {
+    /// Adds a new cell at the origin, returning the index, or None if
+    /// the name is already in use.
+    pub fn add_cell(&mut self, name: &String, w: f32, h: f32, terminal: bool) -> Option<usize> {
+        if self.cell_map.contains_key(name) {
+            return None;
+        }
+        let idx = self.cells.len();
+        let is_macro = !terminal && self.row_height > 0.0 && h > self.row_height;
+        self.cell_map.insert(name.clone(), idx);
+        self.cells.push(Cell {
+            name: name.clone(),
+            w: w,
+            h: h,
+            original_w: w,
+            original_h: h,
+            min_aspect: 0.0,
+            max_aspect: 0.0,
+            pins: Vec::new(),
+            terminal: terminal,
+            soft: None,
+            is_macro: is_macro,
+            is_soft: false,
+            can_rotate: false,
+            shape: None,
+        });
+        self.cellpos.push(point::Point { x: 0.0, y: 0.0 });
+        self.orient.push(Orientation::N);
+        if let Some(rp) = &mut self.refpos {
+            rp.push(point::Point { x: 0.0, y: 0.0 });
+        }
+        if let Some(color) = &mut self.cell_color {
+            color.push(0);
+        }
+        if let Some(tier) = &mut self.tier {
+            tier.push(0);
+        }
+        if terminal {
+            self.num_terminals += 1;
+        } else if is_macro {
+            self.num_macros += 1;
+        } else {
+            self.num_cells += 1;
+        }
+        Some(idx)
+    }
+
+    /// Adds a new (empty) net, returning the index, or None if the name
+    /// is already in use.
+    pub fn add_net(&mut self, name: &String) -> Option<usize> {
+        if self.net_map.contains_key(name) {
+            return None;
+        }
+        let idx = self.nets.len();
+        self.net_map.insert(name.clone(), idx);
+        self.nets.push(Net {
+            name: name.clone(),
+            pins: Vec::new(),
+            weight: 1.0,
+        });
+        Some(idx)
+    }
+
+    /// Adds a pin on a cell, connected to a net.  Returns the reference
+    /// to the new pin, or to the existing pin if the cell already has a
+    /// pin with this name on the net.  Returns None if the name is in
+    /// use on the cell for a different net.
+    pub fn connect(&mut self, net_id: usize, cell_id: usize, name: &String, dx: f32, dy: f32) -> Option<PinRef> {
+        if !name.is_empty() {
+            if let Some(pr) = self.find_pin(cell_id, name) {
+                if self.cells[cell_id].pins[pr.index].parent_net == net_id {
+                    return Some(pr);
+                }
+                return None;
+            }
+        }
+        let pr = PinRef {
+            parent_cell: cell_id,
+            index: self.cells[cell_id].pins.len(),
+        };
+        self.cells[cell_id].pins.push(PinInstance {
+            name: name.clone(),
+            dx: dx,
+            dy: dy,
+            parent_cell: cell_id,
+            parent_net: net_id,
+            details: vec![PinDetail {
+                dx: dx,
+                dy: dy,
+                rect: None,
+            }],
+            direction: PinDirection::Bidirectional,
+        });
+        self.nets[net_id].pins.push(pr);
+        // Offsets for the current orientation
+        let o = self.orient[cell_id];
+        if o != Orientation::N {
+            self.set_orientation(cell_id, o);
+        }
+        Some(pr)
+    }
+
+    /// Adds another access point (and optionally a shape) to a pin.
+    /// Offsets are from the lower left corner of the unrotated cell.
+    pub fn add_access_point(&mut self, pr: &PinRef, dx: f32, dy: f32, rect: Option<BBox>) {
+        self.cells[pr.parent_cell].pins[pr.index].details.push(PinDetail {
+            dx: dx,
+            dy: dy,
+            rect: rect,
+        });
+    }
+
+    /// Removes a pin from its cell and net.  The pins after it on the
+    /// cell move down by one.  Returns false if there is no such pin.
+    pub fn disconnect(&mut self, pr: &PinRef) -> bool {
+        if pr.parent_cell >= self.cells.len() || pr.index >= self.cells[pr.parent_cell].pins.len() {
+            return false;
+        }
+        let net_id = self.cells[pr.parent_cell].pins[pr.index].parent_net;
+        self.nets[net_id]
+            .pins
+            .retain(|p| !(p.parent_cell == pr.parent_cell && p.index == pr.index));
+        self.cells[pr.parent_cell].pins.remove(pr.index);
+
+        // Fix up the references to the pins that moved
+        for k in pr.index..self.cells[pr.parent_cell].pins.len() {
+            let n = self.cells[pr.parent_cell].pins[k].parent_net;
+            for p in &mut self.nets[n].pins {
+                if p.parent_cell == pr.parent_cell && p.index == k + 1 {
+                    p.index = k;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn rename_cell(&mut self, cell_id: usize, name: &String) -> bool {
+        if self.cell_map.contains_key(name) {
+            return false;
+        }
+        self.cell_map.remove(&self.cells[cell_id].name);
+        self.cell_map.insert(name.clone(), cell_id);
+        self.cells[cell_id].name = name.clone();
+        true
+    }
+
+    pub fn rename_net(&mut self, net_id: usize, name: &String) -> bool {
+        if self.net_map.contains_key(name) {
+            return false;
+        }
+        self.net_map.remove(&self.nets[net_id].name);
+        self.net_map.insert(name.clone(), net_id);
+        self.nets[net_id].name = name.clone();
+        true
+    }
+
+    pub fn remove_cell(&mut self, cell_id: usize) {
+        self.remove_cells(&vec![cell_id]);
+    }
+
+    /// Removes a set of cells (and all of their pins).  The remaining
+    /// cells are renumbered.
+    pub fn remove_cells(&mut self, cells: &Vec<usize>) {
+        let mut keep = vec![true; self.cells.len()];
+        for c in cells {
+            keep[*c] = false;
+            while !self.cells[*c].pins.is_empty() {
+                let pr = PinRef {
+                    parent_cell: *c,
+                    index: self.cells[*c].pins.len() - 1,
+                };
+                self.disconnect(&pr);
+            }
+        }
+        let keep_nets = vec![true; self.nets.len()];
+        self.renumber(&keep, &keep_nets);
+    }
+
+    pub fn remove_net(&mut self, net_id: usize) {
+        self.remove_nets(&vec![net_id]);
+    }
+
+    /// Removes a set of nets (and their pins on the cells).  The
+    /// remaining nets are renumbered.
+    pub fn remove_nets(&mut self, nets: &Vec<usize>) {
+        let mut keep = vec![true; self.nets.len()];
+        for n in nets {
+            keep[*n] = false;
+            while let Some(pr) = self.nets[*n].pins.last() {
+                let pr = *pr;
+                self.disconnect(&pr);
+            }
+        }
+        let keep_cells = vec![true; self.cells.len()];
+        self.renumber(&keep_cells, &keep);
+    }
+
+    /// Removes the nets that have no pins, renumbering the rest.
+    /// Returns the number of nets removed.
+    pub fn compact(&mut self) -> usize {
+        let keep: Vec<bool> = self.nets.iter().map(|n| !n.pins.is_empty()).collect();
+        let removed = keep.iter().filter(|k| !**k).count();
+        if removed > 0 {
+            let keep_cells = vec![true; self.cells.len()];
+            self.renumber(&keep_cells, &keep);
+        }
+        removed
+    }
+
+    // Drops the cells and nets that are not kept (these must not have
+    // any pins left), and renumbers everything else.
+    fn renumber(&mut self, keep_cells: &Vec<bool>, keep_nets: &Vec<bool>) {
+        let mut cell_index = vec![usize::MAX; self.cells.len()];
+        let mut next = 0;
+        for i in 0..self.cells.len() {
+            if keep_cells[i] {
+                cell_index[i] = next;
+                next += 1;
+            } else if self.cells[i].terminal {
+                self.num_terminals -= 1;
+            } else if self.cells[i].is_macro {
+                self.num_macros -= 1;
+            } else {
+                self.num_cells -= 1;
+            }
+        }
+        let mut net_index = vec![usize::MAX; self.nets.len()];
+        let mut next = 0;
+        for i in 0..self.nets.len() {
+            if keep_nets[i] {
+                net_index[i] = next;
+                next += 1;
+            }
+        }
+
+        let mut k = 0;
+        self.cells.retain(|_c| {
+            k += 1;
+            keep_cells[k - 1]
+        });
+        let mut k = 0;
+        self.cellpos.retain(|_p| {
+            k += 1;
+            keep_cells[k - 1]
+        });
+        let mut k = 0;
+        self.orient.retain(|_o| {
+            k += 1;
+            keep_cells[k - 1]
+        });
+        if let Some(rp) = &mut self.refpos {
+            let mut k = 0;
+            rp.retain(|_p| {
+                k += 1;
+                keep_cells[k - 1]
+            });
+        }
+        if let Some(color) = &mut self.cell_color {
+            let mut k = 0;
+            color.retain(|_c| {
+                k += 1;
+                keep_cells[k - 1]
+            });
+        }
+        if let Some(tier) = &mut self.tier {
+            let mut k = 0;
+            tier.retain(|_t| {
+                k += 1;
+                keep_cells[k - 1]
+            });
+        }
+        let mut k = 0;
+        self.nets.retain(|_n| {
+            k += 1;
+            keep_nets[k - 1]
+        });
+
+        for c in &mut self.cells {
+            for p in &mut c.pins {
+                p.parent_cell = cell_index[p.parent_cell];
+                p.parent_net = net_index[p.parent_net];
+            }
+        }
+        for n in &mut self.nets {
+            for p in &mut n.pins {
+                p.parent_cell = cell_index[p.parent_cell];
+            }
+        }
+
+        self.cell_map.clear();
+        for i in 0..self.cells.len() {
+            self.cell_map.insert(self.cells[i].name.clone(), i);
+        }
+        self.net_map.clear();
+        for i in 0..self.nets.len() {
+            self.net_map.insert(self.nets[i].name.clone(), i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    fn check(bc: &BookshelfCircuit) {
+        assert_eq!(bc.cell_map.len(), bc.cells.len());
+        assert_eq!(bc.cellpos.len(), bc.cells.len());
+        assert_eq!(bc.orient.len(), bc.cells.len());
+        for (i, c) in bc.cells.iter().enumerate() {
+            assert_eq!(bc.cell_map[&c.name], i);
+            for (k, p) in c.pins.iter().enumerate() {
+                assert_eq!(p.parent_cell, i);
+                let found = bc.nets[p.parent_net]
+                    .pins
+                    .iter()
+                    .filter(|pr| pr.parent_cell == i && pr.index == k)
+                    .count();
+                assert_eq!(found, 1);
+            }
+        }
+        assert_eq!(bc.net_map.len(), bc.nets.len());
+        for (i, n) in bc.nets.iter().enumerate() {
+            assert_eq!(bc.net_map[&n.name], i);
+            for pr in &n.pins {
+                assert_eq!(bc.cells[pr.parent_cell].pins[pr.index].parent_net, i);
+            }
+        }
+    }
+
+    // Cells c0..c4 in a row, with net n0 on c0..c2, n1 on c1..c3,
+    // n2 on c2..c4, and n3 on c0 and c4
+    fn circuit() -> BookshelfCircuit {
+        let mut tc = TestCircuit::new();
+        for i in 0..5 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 1.0, 10.0 * i as f32, 0.0);
+        }
+        tc.net("n0", &["c0", "c1", "c2"])
+            .net("n1", &["c1", "c2", "c3"])
+            .net("n2", &["c2", "c3", "c4"])
+            .net("n3", &["c0", "c4"])
+            .build()
+    }
+
+    #[test]
+    fn add_and_connect() {
+        let mut bc = circuit();
+        check(&bc);
+        assert!(bc.add_cell(&"c0".to_string(), 1.0, 1.0, false).is_none());
+        assert!(bc.add_net(&"n0".to_string()).is_none());
+        let c = bc.add_cell(&"c5".to_string(), 1.0, 1.0, false).unwrap();
+        let n = bc.add_net(&"n4".to_string()).unwrap();
+        let pr = bc.connect(n, c, &"a".to_string(), 0.0, 0.0).unwrap();
+        assert_eq!(pr.index, 0);
+        bc.connect(n, 0, &"b".to_string(), 0.0, 0.0).unwrap();
+        assert_eq!(bc.nets[n].pins.len(), 2);
+        check(&bc);
+
+        // The same name on the same net is the same pin; on another
+        // net, it is refused
+        let again = bc.connect(n, c, &"a".to_string(), 0.5, 0.5).unwrap();
+        assert_eq!((again.parent_cell, again.index), (c, 0));
+        assert_eq!(bc.nets[n].pins.len(), 2);
+        assert!(bc.connect(bc.net_map["n0"], c, &"a".to_string(), 0.0, 0.0).is_none());
+        assert_eq!(bc.cells[c].pins.len(), 1);
+        check(&bc);
+    }
+
+    #[test]
+    fn disconnect_and_compact() {
+        let mut bc = circuit();
+        // c2 has a pin on n0, n1, and n2; drop the middle one
+        let n1 = bc.net_map["n1"];
+        let pr = *bc.nets[n1].pins.iter().find(|pr| pr.parent_cell == 2).unwrap();
+        assert!(bc.disconnect(&pr));
+        check(&bc);
+        assert_eq!(bc.nets[n1].pins.len(), 2);
+
+        // Empty n3, and compact it away
+        let n3 = bc.net_map["n3"];
+        while let Some(pr) = bc.nets[n3].pins.last() {
+            let pr = *pr;
+            bc.disconnect(&pr);
+        }
+        assert_eq!(bc.compact(), 1);
+        assert!(!bc.net_map.contains_key("n3"));
+        check(&bc);
+    }
+
+    #[test]
+    fn remove_renumbers() {
+        let mut bc = circuit();
+        let wl = bc.net_wl(&bc.nets[bc.net_map["n2"]]);
+        bc.remove_cell(1);
+        check(&bc);
+        assert_eq!(bc.cells.len(), 4);
+        assert!(!bc.cell_map.contains_key("c1"));
+        // Positions moved down with the cells
+        assert_eq!(bc.cellpos[bc.cell_map["c3"]].x, 30.0);
+
+        bc.remove_net(bc.net_map["n0"]);
+        check(&bc);
+        assert_eq!(bc.nets.len(), 3);
+        assert_eq!(bc.net_wl(&bc.nets[bc.net_map["n2"]]), wl);
+    }
+}
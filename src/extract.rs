@@ -0,0 +1,502 @@
+//! Sub-circuit extraction.
+//!
+//! Cuts a window out of a design -- either a list of cells, or all of
+//! the cells inside a rectangular region -- and makes a new, stand-alone
+//! BookshelfCircuit from it.  Each net that connects the window to the
+//! rest of the design gets a new (zero size) terminal cell, standing in
+//! for the pins outside.  The terminal is named after the net, with a
+//! _cut suffix (and a number, if needed to keep it apart from the cell
+//! names).  Rows are clipped to the region, and kept on the original
+//! site grid.
+//!
+//! Cell names are kept, so that the placement of the window can be
+//! merged back into the parent design with `merge_placement`.
+use crate::bookshelf::{BookshelfCircuit, Cell, Net, PinDirection, PinInstance, PinRef, Row};
+use crate::marklist::MarkList;
+use pstools::bbox::BBox;
+use pstools::point;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CutTerminal {
+    /// Put the terminal on the boundary of the region, at the point
+    /// closest to the outside pin
+    Boundary,
+    /// Put the terminal at the point of the bounding box of the outside
+    /// pins that is closest to the center of the region
+    OutsidePin,
+}
+
+pub struct ExtractParams {
+    pub terminals: CutTerminal,
+    pub verbose: bool,
+}
+
+impl ExtractParams {
+    pub fn new() -> ExtractParams {
+        ExtractParams {
+            terminals: CutTerminal::OutsidePin,
+            verbose: false,
+        }
+    }
+}
+
+/// Reference from a new cell back to the parent design
+pub struct Extracted {
+    pub circuit: BookshelfCircuit,
+    /// Index of each cell in the parent design, or None for the
+    /// terminals that were added for cut nets
+    pub parent_cell: Vec<Option<usize>>,
+    pub cut_nets: usize,
+}
+
+impl BookshelfCircuit {
+    /// Extracts all of the cells that lie entirely within the region.
+    /// Cells that straddle the boundary are kept as fixed (terminal)
+    /// cells, so that they still block the rows.
+    pub fn extract_region(&self, region: &BBox, params: &ExtractParams) -> Extracted {
+        let mut cells = Vec::new();
+        let mut fixed = Vec::new();
+        for i in 0..self.cells.len() {
+            let p = &self.cellpos[i];
+            let c = &self.cells[i];
+            if p.x >= region.llx && p.y >= region.lly && p.x + c.w <= region.urx && p.y + c.h <= region.ury {
+                cells.push(i);
+            } else if p.x < region.urx && p.x + c.w > region.llx && p.y < region.ury && p.y + c.h > region.lly {
+                cells.push(i);
+                fixed.push(i);
+            }
+        }
+        self.extract(&cells, &fixed, region, params)
+    }
+
+    /// Extracts a list of cells.  The region (for the rows, and for
+    /// boundary terminals) is the bounding box of the cells.
+    pub fn extract_cells(&self, cells: &Vec<usize>, params: &ExtractParams) -> Extracted {
+        let mut region = BBox::new();
+        for c in cells {
+            let p = &self.cellpos[*c];
+            region.addpoint(p.x, p.y);
+            region.addpoint(p.x + self.cells[*c].w, p.y + self.cells[*c].h);
+        }
+        self.extract(cells, &Vec::new(), &region, params)
+    }
+
+    fn extract(&self, cells: &Vec<usize>, fixed: &Vec<usize>, region: &BBox, params: &ExtractParams) -> Extracted {
+        let mut sub = BookshelfCircuit::new();
+        sub.name = format!("{}_window", self.name);
+        sub.unit_x = self.unit_x;
+        sub.unit_y = self.unit_y;
+        sub.row_height = self.row_height;
+        sub.is_blockpacking = self.is_blockpacking;
+
+        let mut cellmark = MarkList::new(self.cells.len());
+        let mut netmark = MarkList::new(self.nets.len());
+        for c in cells {
+            cellmark.mark(*c);
+        }
+        let mut parent_cell = Vec::new();
+        for i in 0..cellmark.list.len() {
+            let ci = cellmark.list[i];
+            let c = &self.cells[ci];
+            sub.cell_map.insert(c.name.clone(), sub.cells.len());
+            sub.cells.push(Cell {
+                name: c.name.clone(),
+                w: c.original_w,
+                h: c.original_h,
+                original_w: c.original_w,
+                original_h: c.original_h,
+                min_aspect: c.min_aspect,
+                max_aspect: c.max_aspect,
+                pins: Vec::new(),
+                terminal: c.terminal || fixed.contains(&ci),
+                soft: c.soft.clone(),
+                is_macro: c.is_macro,
+                is_soft: c.is_soft,
+                can_rotate: c.can_rotate,
+                shape: c.shape.clone(),
+            });
+            sub.cellpos.push(self.cellpos[ci].clone());
+            sub.orient.push(self.orient[ci]);
+            parent_cell.push(Some(ci));
+            for pin in &c.pins {
+                netmark.mark(pin.parent_net);
+            }
+        }
+
+        // Nets, with a terminal for each cut net
+        let center_x = (region.llx + region.urx) * 0.5;
+        let center_y = (region.lly + region.ury) * 0.5;
+        let mut cut_nets = 0;
+        for ni in &netmark.list {
+            let net = &self.nets[*ni];
+            let nidx = sub.nets.len();
+            let mut new_net = Net {
+                name: net.name.clone(),
+                pins: Vec::new(),
+                weight: net.weight,
+            };
+            let mut outside = BBox::new();
+            let mut num_outside = 0;
+            let mut outside_driver = false;
+            for pr in &net.pins {
+                if cellmark.marked[pr.parent_cell] {
+                    let sc = cellmark.index[pr.parent_cell];
+                    let pin = &self.cells[pr.parent_cell].pins[pr.index];
+                    new_net.pins.push(PinRef {
+                        parent_cell: sc,
+                        index: sub.cells[sc].pins.len(),
+                    });
+                    sub.cells[sc].pins.push(PinInstance {
+                        name: pin.name.clone(),
+                        dx: pin.details[0].dx,
+                        dy: pin.details[0].dy,
+                        parent_cell: sc,
+                        parent_net: nidx,
+                        details: pin.details.clone(),
+                        direction: pin.direction,
+                    });
+                } else {
+                    if self.cells[pr.parent_cell].pins[pr.index].direction == PinDirection::Output {
+                        outside_driver = true;
+                    }
+                    let (px, py) = self.pinloc(pr);
+                    outside.addpoint(px, py);
+                    num_outside += 1;
+                }
+            }
+            if num_outside > 0 {
+                cut_nets += 1;
+                // The point of the outside pins' box nearest the region center stands in for all of them
+                let mut px = center_x.max(outside.llx).min(outside.urx);
+                let mut py = center_y.max(outside.lly).min(outside.ury);
+                if params.terminals == CutTerminal::Boundary {
+                    px = px.max(region.llx).min(region.urx);
+                    py = py.max(region.lly).min(region.ury);
+                    // Inside points go to the closest edge
+                    let d = [px - region.llx, region.urx - px, py - region.lly, region.ury - py];
+                    let mut side = 0;
+                    for s in 1..4 {
+                        if d[s] < d[side] {
+                            side = s;
+                        }
+                    }
+                    match side {
+                        0 => px = region.llx,
+                        1 => px = region.urx,
+                        2 => py = region.lly,
+                        _ => py = region.ury,
+                    }
+                }
+                let mut tname = format!("{}_cut", net.name);
+                let mut k = 0;
+                while self.cell_map.contains_key(&tname) || sub.cell_map.contains_key(&tname) {
+                    k += 1;
+                    tname = format!("{}_cut{}", net.name, k);
+                }
+                let tc = sub.cells.len();
+                sub.cell_map.insert(tname.clone(), tc);
+                sub.cells.push(Cell {
+                    name: tname,
+                    w: 0.0,
+                    h: 0.0,
+                    original_w: 0.0,
+                    original_h: 0.0,
+                    min_aspect: 0.0,
+                    max_aspect: 0.0,
+                    pins: Vec::new(),
+                    terminal: true,
+                    soft: None,
+                    is_macro: false,
+                    is_soft: false,
+                    can_rotate: false,
+                    shape: None,
+                });
+                sub.cellpos.push(point::Point { x: px, y: py });
+                sub.orient.push(crate::bookshelf::Orientation::N);
+                parent_cell.push(None);
+                new_net.pins.push(PinRef {
+                    parent_cell: tc,
+                    index: 0,
+                });
+                sub.cells[tc].pins.push(PinInstance {
+                    name: "".to_string(),
+                    dx: 0.0,
+                    dy: 0.0,
+                    parent_cell: tc,
+                    parent_net: nidx,
+                    details: vec![crate::bookshelf::PinDetail {
+                        dx: 0.0,
+                        dy: 0.0,
+                        rect: None,
+                    }],
+                    // Drives the net if the driver was outside
+                    direction: if outside_driver { PinDirection::Output } else { PinDirection::Input },
+                });
+            }
+            sub.net_map.insert(new_net.name.clone(), nidx);
+            sub.nets.push(new_net);
+        }
+
+        // Pin offsets and sizes for the current orientations
+        for i in 0..sub.cells.len() {
+            let o = sub.orient[i];
+            sub.set_orientation(i, o);
+        }
+
+        // Tiers of the copied cells (the cut net terminals are on tier 0)
+        if self.tier.is_some() {
+            for i in 0..sub.cells.len() {
+                if let Some(ci) = parent_cell[i] {
+                    sub.set_tier(i, self.tier_of(ci));
+                }
+            }
+            sub.num_tiers = self.num_tiers;
+            sub.via_cost = self.via_cost;
+        }
+
+        // Rows clipped to the region, staying on the site grid
+        for r in &self.rows {
+            if r.bounds.lly < region.lly || r.bounds.ury > region.ury {
+                continue;
+            }
+            let spacing = r.site_spacing.max(f32::MIN_POSITIVE);
+            let origin = r.bounds.llx;
+            let llx = origin + ((region.llx.max(r.bounds.llx) - origin) / spacing).ceil() * spacing;
+            let urx = origin + ((region.urx.min(r.bounds.urx) - origin) / spacing).floor() * spacing;
+            if urx <= llx {
+                continue;
+            }
+            let mut bounds = BBox::new();
+            bounds.addpoint(llx, r.bounds.lly);
+            bounds.addpoint(urx, r.bounds.ury);
+            sub.rows.push(Row {
+                name: r.name.clone(),
+                bounds: bounds,
+                site_spacing: r.site_spacing,
+                orient: r.orient,
+                symmetry: r.symmetry.clone(),
+                tier: r.tier,
+            });
+        }
+
+        for c in &sub.cells {
+            if c.terminal {
+                sub.num_terminals += 1;
+            } else if c.is_macro {
+                sub.num_macros += 1;
+            } else {
+                sub.num_cells += 1;
+            }
+        }
+        sub.notes.push(format!(
+            "Extracted from {}: region {}, {} cells, {} cut nets",
+            self.name,
+            region,
+            cellmark.list.len(),
+            cut_nets
+        ));
+        if params.verbose {
+            println!(
+                "Extracted {} cells, {} nets ({} cut), {} rows",
+                cellmark.list.len(),
+                sub.nets.len(),
+                cut_nets,
+                sub.rows.len()
+            );
+        }
+
+        Extracted {
+            circuit: sub,
+            parent_cell: parent_cell,
+            cut_nets: cut_nets,
+        }
+    }
+}
+
+/// Result of merging a sub-circuit placement back into the parent
+pub struct MergeResult {
+    /// Cells with a new position or orientation
+    pub moved: usize,
+    /// Sub-circuit cells not found in the parent (cut net terminals)
+    pub skipped: usize,
+    /// Fixed cells whose location differs; these are not copied
+    pub fixed_moved: Vec<String>,
+    pub wl_before: f32,
+    pub wl_after: f32,
+}
+
+impl BookshelfCircuit {
+    /// Copies the cell positions and orientations from a placed
+    /// sub-circuit back into this design, matching the cells by name.
+    /// Fixed cells (terminals in either design) are not moved; any that
+    /// were moved in the sub-circuit are reported.
+    pub fn merge_placement(&mut self, sub: &BookshelfCircuit) -> MergeResult {
+        let wl_before = self.wl();
+        let mut moved = 0;
+        let mut skipped = 0;
+        let mut fixed_moved = Vec::new();
+        for si in 0..sub.cells.len() {
+            let sc = &sub.cells[si];
+            let pi = match self.cell_map.get(&sc.name) {
+                Some(pi) => *pi,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let sp = &sub.cellpos[si];
+            let same = self.cellpos[pi].x == sp.x
+                && self.cellpos[pi].y == sp.y
+                && self.orient[pi] == sub.orient[si]
+                && self.tier_of(pi) == sub.tier_of(si);
+            if same {
+                continue;
+            }
+            if sc.terminal || self.cells[pi].terminal {
+                fixed_moved.push(sc.name.clone());
+                continue;
+            }
+            self.cellpos[pi].x = sp.x;
+            self.cellpos[pi].y = sp.y;
+            if self.orient[pi] != sub.orient[si] {
+                self.set_orientation(pi, sub.orient[si]);
+            }
+            if self.tier_of(pi) != sub.tier_of(si) {
+                self.set_tier(pi, sub.tier_of(si));
+            }
+            moved += 1;
+        }
+        let wl_after = self.wl();
+
+        if !fixed_moved.is_empty() {
+            println!("Merge: {} fixed cells moved in the sub-circuit (not copied)", fixed_moved.len());
+            for n in fixed_moved.iter().take(10) {
+                println!("  {}", n);
+            }
+        }
+        println!(
+            "Merge: {} cells moved, {} skipped, HPWL {} -> {} ({:+})",
+            moved,
+            skipped,
+            wl_before,
+            wl_after,
+            wl_after - wl_before
+        );
+        self.notes.push(format!(
+            "Merged placement of {}: {} cells moved, HPWL {} -> {}",
+            sub.name, moved, wl_before, wl_after
+        ));
+
+        MergeResult {
+            moved: moved,
+            skipped: skipped,
+            fixed_moved: fixed_moved,
+            wl_before: wl_before,
+            wl_after: wl_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bookshelf::Orientation;
+    use crate::testutil::TestCircuit;
+
+    // A terminal t0 and cells c0..c4 in a row, on nets t0-c0, c0-c1-c2,
+    // c2-c3, and c3-c4
+    fn circuit() -> BookshelfCircuit {
+        let mut tc = TestCircuit::new().terminal("t0", 1.0, 1.0, -10.0, 0.0);
+        for i in 0..5 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 1.0, 10.0 * i as f32, 3.0 * i as f32);
+        }
+        tc.net("n0", &["t0", "c0"])
+            .net("n1", &["c0", "c1", "c2"])
+            .net("n2", &["c2", "c3"])
+            .net("n3", &["c3", "c4"])
+            .build()
+    }
+
+    #[test]
+    fn extract_cells() {
+        let bc = circuit();
+        let cells: Vec<usize> = ["c1", "c2", "c3"].iter().map(|n| bc.cell_map[*n]).collect();
+        let ex = bc.extract_cells(&cells, &ExtractParams::new());
+        let sub = &ex.circuit;
+        assert_eq!(ex.cut_nets, 2);
+        assert_eq!(sub.nets.len(), 3);
+        assert_eq!(sub.cells.len(), 5);
+        assert_eq!(sub.num_terminals, 2);
+        for (i, p) in ex.parent_cell.iter().enumerate() {
+            match p {
+                Some(pi) => assert_eq!(bc.cells[*pi].name, sub.cells[i].name),
+                None => assert!(sub.cells[i].terminal && !bc.cell_map.contains_key(&sub.cells[i].name)),
+            }
+        }
+        // Terminals at the outside pins keep the wire length of each net
+        for n in &sub.nets {
+            let pn = &bc.nets[bc.net_map[&n.name]];
+            assert_eq!(sub.net_wl(n), bc.net_wl(pn));
+        }
+    }
+
+    #[test]
+    fn cut_terminals() {
+        // A cell already named like the terminal of n1, and a net with pins on both sides
+        let mut tc = TestCircuit::new().terminal("t0", 1.0, 1.0, -10.0, 0.0);
+        for i in 0..5 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 1.0, 10.0 * i as f32, 3.0 * i as f32);
+        }
+        let mut bc = tc
+            .cell("n1_cut", 2.0, 1.0, 50.0, 0.0)
+            .net("n1", &["c0", "c1", "c2"])
+            .net("n4", &["c0", "c2", "c4"])
+            .build();
+        let cells: Vec<usize> = ["c1", "c2", "c3"].iter().map(|n| bc.cell_map[*n]).collect();
+        let ex = bc.extract_cells(&cells, &ExtractParams::new());
+        let sub = &ex.circuit;
+        assert_eq!(ex.cut_nets, 2);
+        assert!(!sub.cell_map.contains_key("n1_cut"));
+        assert!(sub.cells[sub.cell_map["n1_cut1"]].terminal);
+
+        // Pins of n4 at (1, 0.5) and (41, 12.5) box in the center of (10, 3)-(32, 10)
+        let t = sub.cell_map["n4_cut"];
+        assert_eq!((sub.cellpos[t].x, sub.cellpos[t].y), (21.0, 6.5));
+
+        // The terminals are not merged into the parent
+        let res = bc.merge_placement(sub);
+        assert_eq!(res.moved, 0);
+        assert_eq!(res.skipped, 2);
+        assert_eq!(bc.cellpos[bc.cell_map["n1_cut"]].x, 50.0);
+    }
+
+    #[test]
+    fn merge_round_trip() {
+        let mut bc = circuit();
+        let cells: Vec<usize> = ["c1", "c2", "c3"].iter().map(|n| bc.cell_map[*n]).collect();
+        let mut sub = bc.extract_cells(&cells, &ExtractParams::new()).circuit;
+
+        // Nothing changed, nothing to copy
+        let wl = bc.wl();
+        let res = bc.merge_placement(&sub);
+        assert_eq!(res.moved, 0);
+        assert_eq!(res.skipped, 2);
+        assert_eq!(bc.wl(), wl);
+
+        // Swap c1 and c3, and flip c2
+        let (s1, s2, s3) = (sub.cell_map["c1"], sub.cell_map["c2"], sub.cell_map["c3"]);
+        sub.cellpos.swap(s1, s3);
+        sub.set_orientation(s2, Orientation::FS);
+        let res = bc.merge_placement(&sub);
+        assert_eq!(res.moved, 3);
+        assert!(res.fixed_moved.is_empty());
+        for name in ["c1", "c2", "c3"] {
+            let (si, pi) = (sub.cell_map[name], bc.cell_map[name]);
+            assert_eq!(bc.cellpos[pi].x, sub.cellpos[si].x);
+            assert_eq!(bc.cellpos[pi].y, sub.cellpos[si].y);
+            assert!(bc.orient[pi] == sub.orient[si]);
+        }
+        assert_eq!(res.wl_after, bc.wl());
+        assert_eq!(bc.net_wl(&bc.nets[bc.net_map["n2"]]), sub.net_wl(&sub.nets[sub.net_map["n2"]]));
+    }
+}
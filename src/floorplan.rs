@@ -0,0 +1,519 @@
+//! Fixed-outline floorplanning for block packing benchmarks.
+//!
+//! Blocks are represented with a sequence pair (Murata et al., 1995).
+//! Block a is to the left of block b if a comes before b in both
+//! sequences; a is below b if a comes after b in the first sequence,
+//! and before b in the second.  Packing is done with the longest common
+//! subsequence approach (Tang, Tian, Wong, 2000), which is O(n log n).
+//!
+//! Simulated annealing perturbs the sequence pair (swapping blocks in one
+//! or both sequences), rotates hard blocks that are allowed to rotate,
+//! and reshapes soft blocks (to one of their alternate sizes if they have
+//! them, or else within their aspect ratio limits).  The cost is a
+//! weighted sum of the packing area and HPWL, with a penalty for going
+//! outside of the fixed outline (if one is given).  Terminals stay where
+//! they are.
+use crate::bookshelf::{BookshelfCircuit, Orientation};
+use crate::random::Random;
+
+pub struct FloorplanParams {
+    /// Weight on the area of the packing
+    pub area_weight: f32,
+    /// Weight on the wire length
+    pub wl_weight: f32,
+    /// Fixed outline width and height.  If None, the packing is free to
+    /// take any shape.
+    pub outline: Option<(f32, f32)>,
+    /// Weight on the outline violation
+    pub outline_weight: f32,
+    /// Moves per temperature step, per block
+    pub moves_per_block: usize,
+    /// Temperature multiplier after each step
+    pub cooling: f32,
+    /// Annealing stops when the temperature falls below this fraction of
+    /// the starting temperature
+    pub stop_ratio: f32,
+    pub seed: usize,
+    pub verbose: bool,
+}
+
+impl FloorplanParams {
+    pub fn new() -> FloorplanParams {
+        FloorplanParams {
+            area_weight: 0.5,
+            wl_weight: 0.5,
+            outline: None,
+            outline_weight: 2.0,
+            moves_per_block: 20,
+            cooling: 0.95,
+            stop_ratio: 0.0005,
+            seed: 8675309,
+            verbose: false,
+        }
+    }
+
+    /// Sets a fixed outline for the circuit, with the aspect ratio
+    /// (height / width) and fraction of white space over the total
+    /// block area.
+    pub fn set_outline(&mut self, bc: &BookshelfCircuit, aspect: f32, whitespace: f32) {
+        let area = bc.cell_area() * (1.0 + whitespace);
+        let w = (area / aspect).sqrt();
+        let h = w * aspect;
+        self.outline = Some((w, h));
+    }
+}
+
+pub struct FloorplanResult {
+    pub width: f32,
+    pub height: f32,
+    pub area: f32,
+    pub wl: f32,
+    pub fits_outline: bool,
+}
+
+// Shape of a block in the annealer.  Soft blocks keep the same area, and
+// change the aspect ratio (height over width).  Soft blocks with
+// alternate sizes only take those, and alt is the one in use.
+#[derive(Clone, Copy)]
+struct Shape {
+    w: f32,
+    h: f32,
+    rotated: bool,
+    alt: Option<usize>,
+}
+
+#[derive(Clone)]
+struct SeqPair {
+    pos: Vec<usize>,
+    neg: Vec<usize>,
+    shapes: Vec<Shape>,
+}
+
+// Prefix maximum Fenwick tree, used for the longest common subsequence
+// packing.
+struct MaxTree {
+    t: Vec<f32>,
+}
+
+impl MaxTree {
+    fn new(n: usize) -> MaxTree {
+        MaxTree { t: vec![0.0; n + 1] }
+    }
+    // Max over positions 0..i (exclusive)
+    fn query(&self, i: usize) -> f32 {
+        let mut i = i;
+        let mut m: f32 = 0.0;
+        while i > 0 {
+            m = m.max(self.t[i]);
+            i = i & (i - 1);
+        }
+        m
+    }
+    fn update(&mut self, i: usize, v: f32) {
+        let mut i = i + 1;
+        while i < self.t.len() {
+            self.t[i] = self.t[i].max(v);
+            i = i + (i & i.wrapping_neg());
+        }
+    }
+}
+
+struct Annealer {
+    blocks: Vec<usize>,
+    x: Vec<f32>,
+    y: Vec<f32>,
+    area_norm: f32,
+    wl_norm: f32,
+}
+
+impl BookshelfCircuit {
+    /// Floorplans all of the non-terminal cells, writing the resulting
+    /// positions, orientations, and soft block shapes back into the
+    /// circuit.
+    pub fn floorplan(&mut self, params: &FloorplanParams) -> FloorplanResult {
+        let blocks: Vec<usize> = (0..self.cells.len()).filter(|i| !self.cells[*i].terminal).collect();
+        let n = blocks.len();
+        let mut rng = Random::new(params.seed);
+
+        let mut sp = SeqPair {
+            pos: (0..n).collect(),
+            neg: (0..n).collect(),
+            shapes: blocks.iter().map(|b| self.fp_shape(*b)).collect(),
+        };
+        rng.shuffle(&mut sp.pos);
+        rng.shuffle(&mut sp.neg);
+
+        let mut an = Annealer {
+            blocks: blocks,
+            x: vec![0.0; n],
+            y: vec![0.0; n],
+            area_norm: 1.0,
+            wl_norm: 1.0,
+        };
+        if n == 0 {
+            return self.floorplan_result(params);
+        }
+
+        // Normalize the area and wire length terms with averages over
+        // random perturbations, and find a starting temperature that
+        // accepts most uphill moves.
+        let mut area_sum = 0.0;
+        let mut wl_sum = 0.0;
+        let samples = 50.max(n);
+        for _i in 0..samples {
+            let trial = self.fp_perturb(&sp, &an.blocks, &mut rng);
+            let (w, h) = self.fp_pack(&trial, &mut an);
+            area_sum += w * h;
+            wl_sum += self.wl();
+            sp = trial;
+        }
+        an.area_norm = (area_sum / samples as f32).max(1.0);
+        an.wl_norm = (wl_sum / samples as f32).max(1.0);
+
+        let mut cost = self.fp_cost(&sp, &mut an, params);
+        let mut uphill = 0.0;
+        let mut num_uphill = 0;
+        for _i in 0..samples {
+            let trial = self.fp_perturb(&sp, &an.blocks, &mut rng);
+            let c = self.fp_cost(&trial, &mut an, params);
+            if c > cost {
+                uphill += c - cost;
+                num_uphill += 1;
+            }
+        }
+        let mut temp = 1.0;
+        if num_uphill > 0 {
+            // Accept an average uphill move with probability 0.9 at the start
+            temp = -(uphill / num_uphill as f32) / (0.9 as f32).ln();
+        }
+        let stop = temp * params.stop_ratio;
+
+        let mut best = sp.clone();
+        let mut best_cost = cost;
+        let moves = params.moves_per_block * n;
+        let mut step = 0;
+        while temp > stop {
+            let mut accepted = 0;
+            for _m in 0..moves {
+                let trial = self.fp_perturb(&sp, &an.blocks, &mut rng);
+                let c = self.fp_cost(&trial, &mut an, params);
+                let delta = c - cost;
+                if delta <= 0.0 || rng.uniform() < (-delta / temp).exp() {
+                    sp = trial;
+                    cost = c;
+                    accepted += 1;
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = sp.clone();
+                    }
+                }
+            }
+            if params.verbose && step % 10 == 0 {
+                println!(
+                    "Floorplan temp {:.5} cost {:.4} best {:.4} accepted {}/{}",
+                    temp, cost, best_cost, accepted, moves
+                );
+            }
+            temp = temp * params.cooling;
+            step += 1;
+        }
+
+        self.fp_pack(&best, &mut an);
+        self.fp_apply(&best, &an);
+        let result = self.floorplan_result(params);
+        self.notes.push(format!(
+            "Floorplan: {:.1} x {:.1}, area {:.1}, HPWL {}, fits outline {}",
+            result.width, result.height, result.area, result.wl, result.fits_outline
+        ));
+        result
+    }
+
+    fn floorplan_result(&self, params: &FloorplanParams) -> FloorplanResult {
+        let bb = self.cell_bounds();
+        let (w, h) = (bb.urx.max(0.0), bb.ury.max(0.0));
+        let mut fits = true;
+        if let Some((ow, oh)) = params.outline {
+            fits = w <= ow && h <= oh;
+        }
+        FloorplanResult {
+            width: w,
+            height: h,
+            area: w * h,
+            wl: self.wl(),
+            fits_outline: fits,
+        }
+    }
+
+    // Starting shape of a block.  Soft blocks with alternate sizes start
+    // with the one chosen, or the first.
+    fn fp_shape(&self, c: usize) -> Shape {
+        let cell = &self.cells[c];
+        if let Some(soft) = &cell.soft {
+            if cell.is_soft && !soft.alt_sizes.is_empty() {
+                let k = soft.chosen.unwrap_or(0).min(soft.alt_sizes.len() - 1);
+                return Shape {
+                    w: soft.alt_sizes[k].w,
+                    h: soft.alt_sizes[k].h,
+                    rotated: false,
+                    alt: Some(k),
+                };
+            }
+        }
+        Shape {
+            w: cell.original_w,
+            h: cell.original_h,
+            rotated: false,
+            alt: None,
+        }
+    }
+
+    // Random move: swap two blocks in the positive sequence, swap two
+    // blocks in both sequences, rotate a block, or reshape a soft block.
+    fn fp_perturb(&self, sp: &SeqPair, blocks: &Vec<usize>, rng: &mut Random) -> SeqPair {
+        let mut t = sp.clone();
+        let n = t.pos.len();
+        let kind = rng.range(4);
+        let a = rng.range(n);
+        let mut b = rng.range(n);
+        if n > 1 {
+            while b == a {
+                b = rng.range(n);
+            }
+        }
+        match kind {
+            0 => {
+                t.pos.swap(a, b);
+            }
+            1 => {
+                let ba = t.pos[a];
+                let bb = t.pos[b];
+                t.pos.swap(a, b);
+                let ia = t.neg.iter().position(|v| *v == ba).unwrap();
+                let ib = t.neg.iter().position(|v| *v == bb).unwrap();
+                t.neg.swap(ia, ib);
+            }
+            2 => {
+                t.neg.swap(a, b);
+            }
+            _ => {
+                let block = t.pos[a];
+                let cell = &self.cells[blocks[block]];
+                let s = &mut t.shapes[block];
+                let alt_sizes = match &cell.soft {
+                    Some(soft) if cell.is_soft && !soft.alt_sizes.is_empty() => Some(&soft.alt_sizes),
+                    _ => None,
+                };
+                if let Some(alt_sizes) = alt_sizes {
+                    // Stick to the alternate sizes, so that rounded shapes
+                    // stay rounded
+                    let k = rng.range(alt_sizes.len());
+                    s.w = alt_sizes[k].w;
+                    s.h = alt_sizes[k].h;
+                    s.alt = Some(k);
+                } else if cell.is_soft && cell.max_aspect > 0.0 {
+                    let area = s.w * s.h;
+                    let lo = cell.min_aspect.min(cell.max_aspect);
+                    let hi = cell.max_aspect.max(cell.min_aspect);
+                    let aspect = rng.between(lo, hi);
+                    s.w = (area / aspect).sqrt();
+                    s.h = area / s.w;
+                } else if cell.can_rotate {
+                    let old_w = s.w;
+                    s.w = s.h;
+                    s.h = old_w;
+                    s.rotated = !s.rotated;
+                } else {
+                    t.pos.swap(a, b);
+                }
+            }
+        }
+        t
+    }
+
+    // Packs the sequence pair, with blocks to the lower left, and puts
+    // the locations into cellpos.  Returns the width and height.
+    fn fp_pack(&mut self, sp: &SeqPair, an: &mut Annealer) -> (f32, f32) {
+        let n = sp.pos.len();
+        let mut neg_index = vec![0; n];
+        for (i, b) in sp.neg.iter().enumerate() {
+            neg_index[*b] = i;
+        }
+        let mut tree = MaxTree::new(n);
+        let mut width: f32 = 0.0;
+        for b in &sp.pos {
+            let p = neg_index[*b];
+            an.x[*b] = tree.query(p);
+            tree.update(p, an.x[*b] + sp.shapes[*b].w);
+            width = width.max(an.x[*b] + sp.shapes[*b].w);
+        }
+        let mut tree = MaxTree::new(n);
+        let mut height: f32 = 0.0;
+        for b in sp.pos.iter().rev() {
+            let p = neg_index[*b];
+            an.y[*b] = tree.query(p);
+            tree.update(p, an.y[*b] + sp.shapes[*b].h);
+            height = height.max(an.y[*b] + sp.shapes[*b].h);
+        }
+        // Pins are at the block centers for the cost function; the real
+        // pin offsets are set when the result is applied.
+        for i in 0..n {
+            let c = an.blocks[i];
+            let s = &sp.shapes[i];
+            self.cellpos[c].x = an.x[i] + s.w * 0.5 - self.cells[c].w * 0.5;
+            self.cellpos[c].y = an.y[i] + s.h * 0.5 - self.cells[c].h * 0.5;
+        }
+        (width, height)
+    }
+
+    fn fp_cost(&mut self, sp: &SeqPair, an: &mut Annealer, params: &FloorplanParams) -> f32 {
+        let (w, h) = self.fp_pack(sp, an);
+        let mut cost = params.area_weight * (w * h) / an.area_norm
+            + params.wl_weight * self.wl() / an.wl_norm;
+        if let Some((ow, oh)) = params.outline {
+            let excess = (w - ow).max(0.0) / ow + (h - oh).max(0.0) / oh;
+            cost = cost + params.outline_weight * excess;
+        }
+        cost
+    }
+
+    // Writes the final shapes and orientations into the cells.
+    fn fp_apply(&mut self, sp: &SeqPair, an: &Annealer) {
+        for i in 0..an.blocks.len() {
+            let c = an.blocks[i];
+            let s = sp.shapes[i];
+            let is_soft = self.cells[c].is_soft;
+            if let (true, Some(k)) = (is_soft, s.alt) {
+                self.set_soft_shape(c, k);
+            } else if is_soft {
+                let old_w = self.cells[c].w;
+                let old_h = self.cells[c].h;
+                let cell = &mut self.cells[c];
+                cell.w = s.w;
+                cell.h = s.h;
+                cell.original_w = s.w;
+                cell.original_h = s.h;
+                // Continuous reshaping -- not one of the alternate sizes
+                if let Some(soft) = &mut cell.soft {
+                    soft.chosen = None;
+                    soft.shaped = true;
+                }
+                // Pins keep the same relative position in the block
+                for p in &mut cell.pins {
+                    p.dx = p.dx * s.w / old_w;
+                    p.dy = p.dy * s.h / old_h;
+                    for d in &mut p.details {
+                        d.dx = d.dx * s.w / old_w;
+                        d.dy = d.dy * s.h / old_h;
+                        if let Some(r) = &mut d.rect {
+                            r.llx = r.llx * s.w / old_w;
+                            r.urx = r.urx * s.w / old_w;
+                            r.lly = r.lly * s.h / old_h;
+                            r.ury = r.ury * s.h / old_h;
+                        }
+                    }
+                }
+                self.orient[c] = Orientation::N;
+            } else if s.rotated {
+                self.set_orientation(c, Orientation::E);
+            } else {
+                self.set_orientation(c, Orientation::N);
+            }
+            self.cellpos[c].x = an.x[i];
+            self.cellpos[c].y = an.y[i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{overlaps, TestCircuit};
+
+    // Seven hard blocks that can rotate, a soft block of area 12, and a
+    // chain of nets through them
+    fn blocks() -> BookshelfCircuit {
+        let sizes = [(4.0, 2.0), (3.0, 3.0), (5.0, 1.0), (2.0, 6.0), (4.0, 4.0), (1.0, 3.0), (6.0, 2.0), (3.0, 4.0)];
+        let mut tc = TestCircuit::new();
+        for (i, (w, h)) in sizes.iter().enumerate() {
+            tc = tc.cell(&format!("b{i}"), *w, *h, 0.0, 0.0);
+        }
+        for i in 0..sizes.len() - 1 {
+            tc = tc.net(&format!("n{i}"), &[&format!("b{i}"), &format!("b{}", i + 1)]);
+        }
+        let mut bc = tc.build();
+        for c in &mut bc.cells {
+            c.can_rotate = true;
+        }
+        let s = &mut bc.cells[7];
+        s.can_rotate = false;
+        s.is_soft = true;
+        s.min_aspect = 0.5;
+        s.max_aspect = 2.0;
+        bc
+    }
+
+    fn quick() -> FloorplanParams {
+        let mut params = FloorplanParams::new();
+        params.moves_per_block = 5;
+        params.stop_ratio = 0.01;
+        params
+    }
+
+    #[test]
+    fn packing_is_legal() {
+        let mut bc = blocks();
+        let res = bc.floorplan(&quick());
+        for (p, c) in bc.cellpos.iter().zip(&bc.cells) {
+            assert!(p.x >= 0.0 && p.y >= 0.0);
+            assert!(p.x + c.w <= res.width + 1e-3 && p.y + c.h <= res.height + 1e-3);
+        }
+        assert!(overlaps(&bc).is_empty());
+        assert!(res.area >= bc.cell_area() - 1e-3);
+
+        // The soft block keeps its area, within its aspect limits
+        let s = &bc.cells[7];
+        assert!((s.w * s.h - 12.0).abs() < 1e-3);
+        assert!(s.h / s.w >= 0.5 - 1e-3 && s.h / s.w <= 2.0 + 1e-3);
+    }
+
+    #[test]
+    fn fits_fixed_outline() {
+        let mut bc = blocks();
+        let mut params = FloorplanParams::new();
+        params.set_outline(&bc, 1.0, 0.3);
+        let (ow, oh) = params.outline.unwrap();
+        assert!((ow * oh - bc.cell_area() * 1.3).abs() < 1e-2 && (ow - oh).abs() < 1e-3);
+        let res = bc.floorplan(&params);
+        assert!(res.fits_outline, "{} x {} in {} x {}", res.width, res.height, ow, oh);
+        assert!(res.width <= ow && res.height <= oh);
+        let bb = bc.cell_bounds();
+        assert_eq!((res.width, res.height), (bb.urx, bb.ury));
+        assert!(overlaps(&bc).is_empty());
+    }
+
+    #[test]
+    fn soft_block_keeps_alternate_size() {
+        let mut bc = blocks();
+        bc.generate_soft_shapes(5, true);
+        let alts: Vec<(f32, f32)> = bc.cells[7].soft.as_ref().unwrap().alt_sizes.iter().map(|a| (a.w, a.h)).collect();
+        bc.floorplan(&quick());
+        let s = &bc.cells[7];
+        let soft = s.soft.as_ref().unwrap();
+        let k = soft.chosen.unwrap();
+        assert_eq!((s.w, s.h), alts[k]);
+        assert!(soft.shaped);
+        assert_eq!((s.w, s.h), (s.w.round(), s.h.round()));
+    }
+
+    #[test]
+    fn same_seed_same_floorplan() {
+        let mut a = blocks();
+        let mut b = blocks();
+        let ra = a.floorplan(&quick());
+        let rb = b.floorplan(&quick());
+        assert_eq!((ra.width, ra.height, ra.wl), (rb.width, rb.height, rb.wl));
+        for i in 0..a.cells.len() {
+            assert_eq!((a.cellpos[i].x, a.cellpos[i].y), (b.cellpos[i].x, b.cellpos[i].y));
+        }
+    }
+}
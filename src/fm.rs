@@ -0,0 +1,588 @@
+//! Fiduccia-Mattheyses bipartitioning, with multilevel coarsening.
+//!
+//! A pure Rust alternative to the metapartition backends, working
+//! directly on the HyperGraph from `build_graph`.  Vertices with part 0
+//! or 1 (the source and sink used for terminal propagation) stay on
+//! that side; vertices with any other part value are free.  Side 0
+//! gets the `bias` fraction of the total vertex weight, give or take
+//! the tolerance (and, with `heavy_slack`, never less slack than the
+//! heaviest free vertex).
+//!
+//! The graph is coarsened by heavy-edge matching until it is small,
+//! split from several random starts, and then projected back down with
+//! FM refinement at each level.  Runs depend only on the seed, so cuts
+//! are the same on any machine.
+use crate::random::Random;
+use hypergraph::hypergraph::HyperGraph;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::os::raw::c_int;
+
+pub struct FMParams {
+    /// Maximum FM passes at each level
+    pub passes: usize,
+    /// Stop coarsening at this many vertices
+    pub coarsen_to: usize,
+    /// Random initial partitions tried on the coarsest graph
+    pub starts: usize,
+    /// Allowed imbalance, as a fraction of the total vertex weight
+    pub tolerance: f32,
+    /// Widen the window to the heaviest free vertex, so that a macro
+    /// can always cross.  Turn this off when the area targets matter
+    /// more than the cut (k-way partitioning).
+    pub heavy_slack: bool,
+    /// Nets larger than this are ignored when matching vertices
+    pub match_degree: usize,
+    pub seed: usize,
+    pub verbose: bool,
+}
+
+impl FMParams {
+    pub fn new() -> FMParams {
+        FMParams {
+            passes: 8,
+            coarsen_to: 100,
+            starts: 8,
+            tolerance: 0.02,
+            heavy_slack: true,
+            match_degree: 20,
+            seed: 8675309,
+            verbose: false,
+        }
+    }
+}
+
+pub struct FMPartitioner {
+    pub params: FMParams,
+}
+
+// One level of the multilevel hierarchy
+struct Level {
+    vwt: Vec<i64>,
+    // -1 for free vertices, otherwise the side
+    fixed: Vec<i8>,
+    ewt: Vec<i64>,
+    pins: Vec<Vec<usize>>,
+    edges: Vec<Vec<usize>>,
+}
+
+impl Level {
+    fn new(vwt: Vec<i64>, fixed: Vec<i8>, ewt: Vec<i64>, pins: Vec<Vec<usize>>) -> Level {
+        let mut edges = vec![Vec::new(); vwt.len()];
+        for e in 0..pins.len() {
+            for v in &pins[e] {
+                edges[*v].push(e);
+            }
+        }
+        Level {
+            vwt: vwt,
+            fixed: fixed,
+            ewt: ewt,
+            pins: pins,
+            edges: edges,
+        }
+    }
+
+    fn from_hypergraph(hg: &HyperGraph) -> Level {
+        let vwt: Vec<i64> = hg.vtxwt.iter().map(|w| *w as i64).collect();
+        let fixed: Vec<i8> = (0..vwt.len())
+            .map(|v| match hg.part.get(v) {
+                Some(0) => 0,
+                Some(1) => 1,
+                _ => -1,
+            })
+            .collect();
+        let mut ewt = Vec::new();
+        let mut pins = Vec::new();
+        for e in 0..hg.eind.len().saturating_sub(1) {
+            let mut p: Vec<usize> = hg.eptr[hg.eind[e] as usize..hg.eind[e + 1] as usize]
+                .iter()
+                .map(|v| *v as usize)
+                .collect();
+            p.sort();
+            p.dedup();
+            if p.len() < 2 {
+                continue;
+            }
+            ewt.push(hg.hewt.get(e).cloned().unwrap_or(1) as i64);
+            pins.push(p);
+        }
+        Level::new(vwt, fixed, ewt, pins)
+    }
+
+    // Heavy-edge matching.  Returns the coarser level and the map from
+    // each vertex to its coarse vertex, or None if the graph barely
+    // shrinks.
+    fn coarsen(&self, rng: &mut Random, max_wt: i64, match_degree: usize) -> Option<(Level, Vec<usize>)> {
+        let n = self.vwt.len();
+        let mut order: Vec<usize> = (0..n).collect();
+        rng.shuffle(&mut order);
+        let mut map = vec![usize::MAX; n];
+        let mut score = vec![0.0; n];
+        let mut touched = Vec::new();
+        let mut nc = 0;
+        for v in order {
+            if map[v] != usize::MAX {
+                continue;
+            }
+            for e in &self.edges[v] {
+                let p = &self.pins[*e];
+                if p.len() > match_degree {
+                    continue;
+                }
+                let s = self.ewt[*e] as f64 / (p.len() - 1) as f64;
+                for u in p {
+                    if *u == v || map[*u] != usize::MAX {
+                        continue;
+                    }
+                    if score[*u] == 0.0 {
+                        touched.push(*u);
+                    }
+                    score[*u] += s;
+                }
+            }
+            let mut best = None;
+            let mut best_score = 0.0;
+            for u in &touched {
+                let compatible = self.fixed[v] < 0 || self.fixed[*u] < 0 || self.fixed[v] == self.fixed[*u];
+                if compatible && self.vwt[v] + self.vwt[*u] <= max_wt && score[*u] > best_score {
+                    best_score = score[*u];
+                    best = Some(*u);
+                }
+                score[*u] = 0.0;
+            }
+            touched.clear();
+            map[v] = nc;
+            if let Some(u) = best {
+                map[u] = nc;
+            }
+            nc += 1;
+        }
+        if nc as f32 > 0.9 * n as f32 {
+            return None;
+        }
+
+        let mut vwt = vec![0; nc];
+        let mut fixed = vec![-1; nc];
+        for v in 0..n {
+            vwt[map[v]] += self.vwt[v];
+            if self.fixed[v] >= 0 {
+                fixed[map[v]] = self.fixed[v];
+            }
+        }
+        // Parallel edges are merged, adding the weights
+        let mut ewt: Vec<i64> = Vec::new();
+        let mut pins: Vec<Vec<usize>> = Vec::new();
+        let mut seen: HashMap<Vec<usize>, usize> = HashMap::new();
+        for e in 0..self.pins.len() {
+            let mut p: Vec<usize> = self.pins[e].iter().map(|v| map[*v]).collect();
+            p.sort();
+            p.dedup();
+            if p.len() < 2 {
+                continue;
+            }
+            match seen.get(&p) {
+                Some(ce) => ewt[*ce] += self.ewt[e],
+                None => {
+                    seen.insert(p.clone(), pins.len());
+                    ewt.push(self.ewt[e]);
+                    pins.push(p);
+                }
+            }
+        }
+        Some((Level::new(vwt, fixed, ewt, pins), map))
+    }
+
+    fn cut(&self, part: &Vec<u8>) -> i64 {
+        let mut cut = 0;
+        for e in 0..self.pins.len() {
+            let s = part[self.pins[e][0]];
+            if self.pins[e].iter().any(|v| part[*v] != s) {
+                cut += self.ewt[e];
+            }
+        }
+        cut
+    }
+}
+
+// Balance window for side 0
+struct Balance {
+    target: i64,
+    lo: i64,
+    hi: i64,
+}
+
+impl Balance {
+    fn ok(&self, w0: i64) -> bool {
+        w0 >= self.lo && w0 <= self.hi
+    }
+
+    // A move is allowed if it stays inside the window, or gets closer
+    fn allowed(&self, w0: i64, new_w0: i64) -> bool {
+        self.ok(new_w0) || (new_w0 - self.target).abs() < (w0 - self.target).abs()
+    }
+}
+
+impl FMPartitioner {
+    pub fn new(params: FMParams) -> FMPartitioner {
+        FMPartitioner { params: params }
+    }
+
+    /// Splits the graph, returning the vertices on each side and the
+    /// cut (the sum of the weights of the cut hyperedges).  Same shape
+    /// as the metapartition result.
+    pub fn hg_partition(&self, hg: &HyperGraph, bias: f32) -> (Vec<usize>, Vec<usize>, usize) {
+        let (part, cut) = self.bipartition(hg, bias);
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for v in 0..part.len() {
+            if part[v] == 0 {
+                left.push(v);
+            } else {
+                right.push(v);
+            }
+        }
+        (left, right, cut)
+    }
+
+    /// Splits the graph, returning the side (0 or 1) of each vertex,
+    /// and the cut.
+    pub fn bipartition(&self, hg: &HyperGraph, bias: f32) -> (Vec<c_int>, usize) {
+        let params = &self.params;
+        let mut rng = Random::new(params.seed);
+        let finest = Level::from_hypergraph(hg);
+        let n = finest.vwt.len();
+        if n == 0 {
+            return (Vec::new(), 0);
+        }
+        let total: i64 = finest.vwt.iter().sum();
+        let mut slack = (params.tolerance as f64 * total as f64) as i64;
+        for v in 0..n {
+            if params.heavy_slack && finest.fixed[v] < 0 {
+                slack = slack.max(finest.vwt[v]);
+            }
+        }
+        let target = (bias.max(0.0).min(1.0) as f64 * total as f64) as i64;
+        let balance = Balance {
+            target: target,
+            lo: target - slack,
+            hi: target + slack,
+        };
+
+        // Coarsen
+        let max_wt = ((total as f64 * 1.5 / params.coarsen_to.max(1) as f64) as i64).max(1);
+        let mut levels = vec![finest];
+        let mut maps: Vec<Vec<usize>> = Vec::new();
+        while levels.last().unwrap().vwt.len() > params.coarsen_to {
+            match levels.last().unwrap().coarsen(&mut rng, max_wt, params.match_degree) {
+                Some((coarse, map)) => {
+                    levels.push(coarse);
+                    maps.push(map);
+                }
+                None => break,
+            }
+        }
+        if params.verbose {
+            let sizes: Vec<usize> = levels.iter().map(|l| l.vwt.len()).collect();
+            println!("FM: levels {:?}", sizes);
+        }
+
+        // Best of several starts on the coarsest graph
+        let coarsest = levels.last().unwrap();
+        let mut best_part = Vec::new();
+        let mut best_key = (true, i64::MAX);
+        for _s in 0..params.starts.max(1) {
+            let mut part = self.initial(coarsest, &balance, &mut rng);
+            self.refine(coarsest, &mut part, &balance);
+            // Balanced first, then the smallest cut
+            let key = (!balance.ok(side_weight(coarsest, &part)), coarsest.cut(&part));
+            if best_part.is_empty() || key < best_key {
+                best_key = key;
+                best_part = part;
+            }
+        }
+
+        // Project and refine
+        let mut part = best_part;
+        for l in (0..maps.len()).rev() {
+            let map = &maps[l];
+            part = (0..map.len()).map(|v| part[map[v]]).collect();
+            self.refine(&levels[l], &mut part, &balance);
+        }
+
+        let cut = levels[0].cut(&part);
+        if params.verbose {
+            println!(
+                "FM: cut {}, side 0 weight {} (target {})",
+                cut,
+                side_weight(&levels[0], &part),
+                target
+            );
+        }
+        (part.iter().map(|s| *s as c_int).collect(), cut as usize)
+    }
+
+    // Random fill: fixed vertices on their side, then free vertices in
+    // random order onto side 0 until it reaches the target.
+    fn initial(&self, lv: &Level, balance: &Balance, rng: &mut Random) -> Vec<u8> {
+        let n = lv.vwt.len();
+        let mut part = vec![1; n];
+        let mut w0 = 0;
+        for v in 0..n {
+            if lv.fixed[v] == 0 {
+                part[v] = 0;
+                w0 += lv.vwt[v];
+            }
+        }
+        let mut order: Vec<usize> = (0..n).filter(|v| lv.fixed[*v] < 0).collect();
+        rng.shuffle(&mut order);
+        for v in order {
+            if w0 + lv.vwt[v] / 2 <= balance.target {
+                part[v] = 0;
+                w0 += lv.vwt[v];
+            }
+        }
+        part
+    }
+
+    fn refine(&self, lv: &Level, part: &mut Vec<u8>, balance: &Balance) {
+        for _p in 0..self.params.passes.max(1) {
+            if !fm_pass(lv, part, balance) {
+                break;
+            }
+        }
+    }
+}
+
+fn side_weight(lv: &Level, part: &Vec<u8>) -> i64 {
+    let mut w0 = 0;
+    for v in 0..part.len() {
+        if part[v] == 0 {
+            w0 += lv.vwt[v];
+        }
+    }
+    w0
+}
+
+// One FM pass: move every free vertex once (highest gain first, as
+// balance allows), then roll back to the best point seen.  Returns
+// true if the partition improved.
+fn fm_pass(lv: &Level, part: &mut Vec<u8>, balance: &Balance) -> bool {
+    let n = lv.vwt.len();
+    let mut count = vec![[0u32; 2]; lv.pins.len()];
+    for e in 0..lv.pins.len() {
+        for v in &lv.pins[e] {
+            count[e][part[*v] as usize] += 1;
+        }
+    }
+    let mut gain = vec![0i64; n];
+    let mut locked = vec![false; n];
+    let mut heaps = [BinaryHeap::new(), BinaryHeap::new()];
+    for v in 0..n {
+        if lv.fixed[v] >= 0 {
+            locked[v] = true;
+            continue;
+        }
+        let from = part[v] as usize;
+        for e in &lv.edges[v] {
+            if count[*e][from] == 1 {
+                gain[v] += lv.ewt[*e];
+            }
+            if count[*e][1 - from] == 0 {
+                gain[v] -= lv.ewt[*e];
+            }
+        }
+        heaps[from].push((gain[v], v));
+    }
+
+    let mut w0 = side_weight(lv, part);
+    let start_ok = balance.ok(w0);
+    let better = |ok: bool, cum: i64, w0: i64, best: (bool, i64, i64)| {
+        let dist = (w0 - balance.target).abs();
+        (ok && !best.0) || (ok == best.0 && (cum > best.1 || (cum == best.1 && dist < best.2)))
+    };
+    let mut best = (start_ok, 0, (w0 - balance.target).abs());
+    let mut best_moves = 0;
+    let mut moves = Vec::new();
+    let mut cum = 0;
+
+    loop {
+        // Highest gain legal move from either side
+        let mut choice = None;
+        for s in 0..2 {
+            while let Some((g, v)) = heaps[s].peek().cloned() {
+                if locked[v] || part[v] as usize != s || gain[v] != g {
+                    heaps[s].pop();
+                    continue;
+                }
+                let new_w0 = if s == 0 { w0 - lv.vwt[v] } else { w0 + lv.vwt[v] };
+                if !balance.allowed(w0, new_w0) {
+                    // Can't move this one now; leave it for the next pass
+                    heaps[s].pop();
+                    locked[v] = true;
+                    continue;
+                }
+                match choice {
+                    Some((bg, _)) if bg >= g => {}
+                    _ => choice = Some((g, v)),
+                }
+                break;
+            }
+        }
+        let (g, v) = match choice {
+            Some(c) => c,
+            None => break,
+        };
+
+        // Move v, and update the gains of its neighbors
+        let from = part[v] as usize;
+        let to = 1 - from;
+        locked[v] = true;
+        for e in &lv.edges[v] {
+            let w = lv.ewt[*e];
+            let pins = &lv.pins[*e];
+            if count[*e][to] == 0 {
+                for u in pins {
+                    if !locked[*u] {
+                        gain[*u] += w;
+                        heaps[part[*u] as usize].push((gain[*u], *u));
+                    }
+                }
+            } else if count[*e][to] == 1 {
+                for u in pins {
+                    if part[*u] as usize == to && !locked[*u] {
+                        gain[*u] -= w;
+                        heaps[to].push((gain[*u], *u));
+                    }
+                }
+            }
+            count[*e][from] -= 1;
+            count[*e][to] += 1;
+            if count[*e][from] == 0 {
+                for u in pins {
+                    if !locked[*u] {
+                        gain[*u] -= w;
+                        heaps[part[*u] as usize].push((gain[*u], *u));
+                    }
+                }
+            } else if count[*e][from] == 1 {
+                for u in pins {
+                    if *u != v && part[*u] as usize == from && !locked[*u] {
+                        gain[*u] += w;
+                        heaps[from].push((gain[*u], *u));
+                    }
+                }
+            }
+        }
+        part[v] = to as u8;
+        w0 = if from == 0 { w0 - lv.vwt[v] } else { w0 + lv.vwt[v] };
+        cum += g;
+        moves.push(v);
+        if better(balance.ok(w0), cum, w0, best) {
+            best = (balance.ok(w0), cum, (w0 - balance.target).abs());
+            best_moves = moves.len();
+        }
+    }
+
+    // Undo the moves past the best point
+    for v in moves[best_moves..].iter().rev() {
+        part[*v] = 1 - part[*v];
+    }
+    best_moves > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two chains of 20 unit vertices joined by one edge, with a fixed
+    // source (vertex 40, side 0) on the first and a fixed sink (vertex
+    // 41, side 1) on the second.
+    fn two_chains() -> HyperGraph {
+        let mut edges: Vec<Vec<u32>> = Vec::new();
+        for base in [0, 20] {
+            for i in base..base + 19 {
+                edges.push(vec![i, i + 1]);
+                if i + 2 < base + 20 {
+                    edges.push(vec![i, i + 1, i + 2]);
+                }
+            }
+        }
+        edges.push(vec![19, 20]);
+        edges.push(vec![40, 0]);
+        edges.push(vec![41, 39]);
+
+        let mut hg = HyperGraph {
+            vtxwt: vec![1; 42],
+            hewt: Vec::new(),
+            part: vec![-1; 42],
+            eind: vec![0],
+            eptr: Vec::new(),
+        };
+        hg.vtxwt[40] = 0;
+        hg.vtxwt[41] = 0;
+        hg.part[40] = 0;
+        hg.part[41] = 1;
+        for e in &edges {
+            hg.eptr.extend(e);
+            hg.eind.push(hg.eptr.len() as _);
+            hg.hewt.push(1);
+        }
+        hg
+    }
+
+    fn side0(part: &Vec<c_int>, hg: &HyperGraph) -> i32 {
+        (0..part.len()).filter(|v| part[*v] == 0).map(|v| hg.vtxwt[v] as i32).sum()
+    }
+
+    #[test]
+    fn finds_the_bridge() {
+        let hg = two_chains();
+        let (part, cut) = FMPartitioner::new(FMParams::new()).bipartition(&hg, 0.5);
+        assert_eq!(cut, 1);
+        assert_eq!(part[40], 0);
+        assert_eq!(part[41], 1);
+        assert!((0..20).all(|v| part[v] == 0));
+        assert!((20..40).all(|v| part[v] == 1));
+    }
+
+    #[test]
+    fn fixed_vertices_stay() {
+        // Pull the fixed vertices to the wrong chains
+        let mut hg = two_chains();
+        hg.part[40] = 1;
+        hg.part[41] = 0;
+        let (part, _cut) = FMPartitioner::new(FMParams::new()).bipartition(&hg, 0.5);
+        assert_eq!(part[40], 1);
+        assert_eq!(part[41], 0);
+    }
+
+    #[test]
+    fn bias_window() {
+        let hg = two_chains();
+        for bias in [0.25, 0.5, 0.7] {
+            let (part, _cut) = FMPartitioner::new(FMParams::new()).bipartition(&hg, bias);
+            // The window is one vertex wide (the heaviest free vertex)
+            let target = (bias * 40.0) as i32;
+            assert!((side0(&part, &hg) - target).abs() <= 1, "bias {bias}");
+        }
+        let mut params = FMParams::new();
+        params.heavy_slack = false;
+        params.tolerance = 0.1;
+        let (part, _cut) = FMPartitioner::new(params).bipartition(&hg, 0.25);
+        assert!((side0(&part, &hg) - 10).abs() <= 4);
+    }
+
+    #[test]
+    fn deterministic() {
+        let hg = two_chains();
+        let mut params = FMParams::new();
+        params.coarsen_to = 8;
+        params.seed = 42;
+        let fm = FMPartitioner::new(params);
+        let first = fm.bipartition(&hg, 0.3);
+        for _ in 0..3 {
+            assert_eq!(fm.bipartition(&hg, 0.3), first);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Synthetic benchmark generator.
+//!
+//! Builds a BookshelfCircuit from a handful of parameters, so that
+//! families of test cases (of any size) can be written out with
+//! `write_aux`.  The connectivity follows Rent's rule: the cells are
+//! arranged in a binary hierarchy (by index), and a block of m cells
+//! should have about T(m) = k m^p external connections.  A net that
+//! spans both halves of a block of size m, but nothing outside of it,
+//! is "owned" by that block.  Each block owns about T(m/2) - T(m)/2
+//! nets, and these are used as the weights when picking where each
+//! new net goes.  Net degrees follow a power law, and terminals
+//! connect to small clusters of cells.
+//!
+//! Movable cells are left at the lower left of the core (as in the
+//! ISPD benchmarks); terminals are spaced evenly around the core.
+//! Pins are at the cell centers.
+use crate::bookshelf::{BookshelfCircuit, Orientation, PinDirection, Row};
+use crate::pads::{PadMode, PadParams};
+use crate::random::Random;
+use pstools::bbox::BBox;
+
+pub struct GeneratorParams {
+    /// Number of movable cells (standard cells and macros)
+    pub num_cells: usize,
+    /// Standard cell widths, in sites.  Small cells are more common
+    /// (the widths are log-uniform).
+    pub min_sites: usize,
+    pub max_sites: usize,
+    pub row_height: f32,
+    pub site_width: f32,
+    /// Fraction of the movable cells that are macros
+    pub macro_fraction: f32,
+    /// Macro heights, in rows
+    pub macro_min_rows: usize,
+    pub macro_max_rows: usize,
+    /// Rent exponent (p) and pins per cell (k)
+    pub rent_exponent: f32,
+    pub pins_per_cell: f32,
+    /// Net degrees are between 2 and max_degree, with the probability
+    /// of degree d proportional to d^-degree_exponent
+    pub max_degree: usize,
+    pub degree_exponent: f32,
+    /// Number of terminals; if None, this comes from Rent's rule
+    pub num_terminals: Option<usize>,
+    /// Cell area over core area
+    pub utilization: f32,
+    /// Core height over width
+    pub aspect: f32,
+    pub seed: usize,
+}
+
+impl GeneratorParams {
+    pub fn new(num_cells: usize) -> GeneratorParams {
+        GeneratorParams {
+            num_cells: num_cells,
+            min_sites: 2,
+            max_sites: 20,
+            row_height: 12.0,
+            site_width: 1.0,
+            macro_fraction: 0.0,
+            macro_min_rows: 4,
+            macro_max_rows: 16,
+            rent_exponent: 0.65,
+            pins_per_cell: 3.5,
+            max_degree: 16,
+            degree_exponent: 2.5,
+            num_terminals: None,
+            utilization: 0.7,
+            aspect: 1.0,
+            seed: 1,
+        }
+    }
+
+    fn rent(&self, m: f32) -> f32 {
+        self.pins_per_cell * m.powf(self.rent_exponent)
+    }
+}
+
+// A block in the cell hierarchy: cells start..start+len, split at mid.
+struct Block {
+    start: usize,
+    len: usize,
+}
+
+impl BookshelfCircuit {
+    /// Creates a synthetic circuit.  The result has rows, and is ready
+    /// for placement (or for write_aux).
+    pub fn generate(params: &GeneratorParams) -> BookshelfCircuit {
+        let mut rng = Random::new(params.seed);
+        let mut bc = BookshelfCircuit::new();
+        bc.name = format!("synth{}", params.num_cells);
+        let n = params.num_cells.max(2);
+        let rh = params.row_height;
+        let sw = params.site_width;
+
+        // Cell sizes.  Macro sizes are settled once the core is known.
+        let num_macros = (n as f32 * params.macro_fraction).round() as usize;
+        let mut is_macro = vec![false; n];
+        let mut order: Vec<usize> = (0..n).collect();
+        rng.shuffle(&mut order);
+        for i in order.iter().take(num_macros) {
+            is_macro[*i] = true;
+        }
+        let mut sizes = Vec::with_capacity(n);
+        for i in 0..n {
+            if is_macro[i] {
+                let rows = params.macro_min_rows
+                    + rng.range(params.macro_max_rows.saturating_sub(params.macro_min_rows) + 1);
+                let h = rows.max(2) as f32 * rh;
+                let w = (h * rng.between(0.5, 2.0) / sw).round().max(1.0) * sw;
+                sizes.push((w, h));
+            } else {
+                let lo = (params.min_sites.max(1) as f32).ln();
+                let hi = ((params.max_sites.max(params.min_sites) + 1) as f32).ln();
+                let sites = rng.between(lo, hi).exp().floor().max(1.0);
+                sizes.push((sites * sw, rh));
+            }
+        }
+
+        // Core and rows
+        let area: f32 = sizes.iter().map(|(w, h)| w * h).sum::<f32>() / params.utilization;
+        let height = (area * params.aspect).sqrt();
+        let num_rows = ((height / rh).round() as usize).max(1);
+        let width = ((area / (num_rows as f32 * rh)) / sw).ceil() * sw;
+        let core_h = num_rows as f32 * rh;
+        for r in 0..num_rows {
+            let mut bounds = BBox::new();
+            bounds.addpoint(0.0, r as f32 * rh);
+            bounds.addpoint(width, (r + 1) as f32 * rh);
+            bc.rows.push(Row {
+                name: "row".to_string(),
+                bounds: bounds,
+                site_spacing: sw,
+                orient: if r % 2 == 0 { Orientation::N } else { Orientation::FS },
+                symmetry: "Y".to_string(),
+                tier: 0,
+            });
+        }
+        bc.unit_x = sw;
+        bc.unit_y = rh;
+        bc.row_height = rh;
+
+        // Keep each macro to a third of the core in each direction
+        for i in 0..n {
+            if is_macro[i] {
+                let (w, h) = sizes[i];
+                let max_h = ((core_h / 3.0) / rh).floor().max(1.0) * rh;
+                let max_w = ((width / 3.0) / sw).floor().max(1.0) * sw;
+                sizes[i] = (w.min(max_w), h.min(max_h));
+                if sizes[i].1 <= rh {
+                    is_macro[i] = false;
+                }
+            }
+        }
+
+        for i in 0..n {
+            let (w, h) = sizes[i];
+            let name = format!("{}{}", if is_macro[i] { "m" } else { "c" }, i);
+            bc.add_cell(&name, w, h, false);
+        }
+
+        // Terminals, from Rent's rule for the whole design
+        let num_terms = match params.num_terminals {
+            Some(t) => t,
+            None => params.rent(n as f32).round() as usize,
+        };
+        for t in 0..num_terms {
+            bc.add_cell(&format!("p{}", t), 1.0, 1.0, true);
+        }
+
+        // The blocks of the hierarchy, and the number of nets owned by each
+        let mut blocks = Vec::new();
+        let mut weights = Vec::new();
+        let mut stack = vec![Block { start: 0, len: n }];
+        while let Some(b) = stack.pop() {
+            if b.len < 2 {
+                continue;
+            }
+            let half = b.len / 2;
+            let own = params.rent(half as f32) - params.rent(b.len as f32) * 0.5;
+            if own > 0.0 {
+                blocks.push(Block { start: b.start, len: b.len });
+                weights.push(own);
+            }
+            stack.push(Block { start: b.start, len: half });
+            stack.push(Block {
+                start: b.start + half,
+                len: b.len - half,
+            });
+        }
+        if blocks.is_empty() {
+            // No block owns any nets (Rent exponent of 1 or more); spread
+            // them over the whole design
+            blocks.push(Block { start: 0, len: n });
+            weights.push(1.0);
+        }
+        let mut cumulative = Vec::with_capacity(weights.len());
+        let mut total = 0.0;
+        for w in &weights {
+            total = total + w;
+            cumulative.push(total);
+        }
+
+        // Degree distribution
+        let max_degree = params.max_degree.max(2);
+        let mut degree_cdf = Vec::new();
+        let mut dtotal = 0.0;
+        for d in 2..=max_degree {
+            dtotal = dtotal + (d as f32).powf(-params.degree_exponent);
+            degree_cdf.push(dtotal);
+        }
+        let mut avg_degree = 0.0;
+        for d in 2..=max_degree {
+            avg_degree = avg_degree + d as f32 * (d as f32).powf(-params.degree_exponent) / dtotal;
+        }
+
+        let num_nets = ((n as f32 * params.pins_per_cell - num_terms as f32) / avg_degree).max(1.0) as usize;
+        for _k in 0..num_nets {
+            let target = rng.uniform() * total;
+            let bi = cumulative.partition_point(|c| *c < target).min(blocks.len() - 1);
+            let b = &blocks[bi];
+            let r = rng.uniform() * dtotal;
+            let degree = (degree_cdf.partition_point(|c| *c < r) + 2).min(b.len);
+
+            // One cell from each half, and the rest from anywhere in the block
+            let half = b.len / 2;
+            let mut cells = vec![b.start + rng.range(half), b.start + half + rng.range(b.len - half)];
+            let mut tries = 0;
+            while cells.len() < degree && tries < 4 * degree {
+                let c = b.start + rng.range(b.len);
+                if !cells.contains(&c) {
+                    cells.push(c);
+                }
+                tries += 1;
+            }
+            let net = bc.add_net(&format!("n{}", bc.nets.len())).unwrap();
+            for (k, c) in cells.iter().enumerate() {
+                let (w, h) = (bc.cells[*c].w, bc.cells[*c].h);
+                let pr = bc.connect(net, *c, &"".to_string(), w / 2.0, h / 2.0).unwrap();
+                // The first cell drives the net
+                bc.cells[*c].pins[pr.index].direction = if k == 0 { PinDirection::Output } else { PinDirection::Input };
+            }
+        }
+
+        // Each terminal connects to a small cluster of cells
+        for t in 0..num_terms {
+            let r = rng.uniform() * dtotal;
+            let degree = (degree_cdf.partition_point(|c| *c < r) + 2).min(n + 1);
+            let span = (8 * degree).min(n);
+            let start = rng.range(n - span + 1);
+            let mut cells = vec![n + t];
+            while cells.len() < degree {
+                let c = start + rng.range(span);
+                if !cells.contains(&c) {
+                    cells.push(c);
+                }
+            }
+            let net = bc.add_net(&format!("n{}", bc.nets.len())).unwrap();
+            for (k, c) in cells.iter().enumerate() {
+                let (w, h) = (bc.cells[*c].w, bc.cells[*c].h);
+                let pr = bc.connect(net, *c, &"".to_string(), w / 2.0, h / 2.0).unwrap();
+                // Alternate primary inputs (the terminal drives the net)
+                // and primary outputs (the first cell drives it)
+                let driver = if t % 2 == 0 { 0 } else { 1 };
+                bc.cells[*c].pins[pr.index].direction = if k == driver { PinDirection::Output } else { PinDirection::Input };
+            }
+        }
+
+        let core = bc.core();
+        let mut pp = PadParams::new();
+        pp.mode = PadMode::Even;
+        bc.place_pads(&core, &pp);
+        bc.notes.clear();
+        bc.notes.push(format!(
+            "Generated: {} cells, {} macros, {} terminals, {} nets, Rent p {} k {}, utilization {}, seed {}",
+            bc.num_cells,
+            bc.num_macros,
+            bc.num_terminals,
+            bc.nets.len(),
+            params.rent_exponent,
+            params.pins_per_cell,
+            params.utilization,
+            params.seed
+        ));
+        bc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> GeneratorParams {
+        let mut params = GeneratorParams::new(400);
+        params.macro_fraction = 0.02;
+        params.macro_min_rows = 2;
+        params.macro_max_rows = 3;
+        params.num_terminals = Some(20);
+        params
+    }
+
+    #[test]
+    fn counts() {
+        let bc = BookshelfCircuit::generate(&params());
+        assert_eq!(bc.cells.len(), 420);
+        assert_eq!(bc.num_terminals, 20);
+        assert_eq!(bc.num_macros, 8);
+        assert_eq!(bc.cells.iter().filter(|c| c.name.starts_with('m')).count(), 8);
+        // Every terminal has a net, and every pin is on a net and a cell
+        let net_pins: usize = bc.nets.iter().map(|n| n.pins.len()).sum();
+        let cell_pins: usize = bc.cells.iter().map(|c| c.pins.len()).sum();
+        assert_eq!(net_pins, cell_pins);
+        assert!(bc.cells.iter().filter(|c| c.terminal).all(|c| c.pins.len() == 1));
+        assert!(bc.nets.iter().all(|n| n.pins.len() >= 2 && n.pins.len() <= 16));
+        let term_nets = bc.nets.iter().filter(|n| n.pins.iter().any(|pr| bc.cells[pr.parent_cell].terminal)).count();
+        assert_eq!(term_nets, 20);
+        assert!(bc.nets.len() > 300);
+    }
+
+    #[test]
+    fn same_seed_same_circuit() {
+        let (a, b) = (BookshelfCircuit::generate(&params()), BookshelfCircuit::generate(&params()));
+        assert_eq!(a.nets.len(), b.nets.len());
+        for (ca, cb) in a.cells.iter().zip(b.cells.iter()) {
+            assert_eq!((&ca.name, ca.w, ca.h), (&cb.name, cb.w, cb.h));
+        }
+        let cells = |bc: &BookshelfCircuit| -> Vec<Vec<usize>> {
+            bc.nets.iter().map(|n| n.pins.iter().map(|pr| pr.parent_cell).collect()).collect()
+        };
+        assert_eq!(cells(&a), cells(&b));
+        let mut other = params();
+        other.seed = 2;
+        assert!(cells(&a) != cells(&BookshelfCircuit::generate(&other)));
+    }
+}
@@ -0,0 +1,616 @@
+//! Standard cell legalization.
+//!
+//! Takes the current cell positions (typically from a global placer,
+//! so with overlaps and off-grid locations), and moves every movable
+//! standard cell into the rows, aligned to site boundaries, with no
+//! overlaps.  Rows are first broken into free segments, cutting out
+//! the space used by terminals and fixed macro blocks.
+//!
+//! Two approaches are supported.  Tetris is the classic greedy
+//! approach -- cells are sorted by X, and each goes to the leftmost
+//! open spot in the segment that gives the least movement.  Abacus
+//! (Spindler, Schlichtmann, Johannes, ISPD 2008) is similar, but
+//! cells already in a segment can be shifted, with clusters of
+//! abutting cells placed at their optimal (least movement) positions.
+//! Abacus is slower, but gives considerably less displacement.
+//!
+//! Rows carry the site orientation (typically alternating N and FS).
+//! The legalizer flips each cell to match the row it goes to, and
+//! `snap_row_orientations` does the same for cells already in rows.
+use crate::bookshelf::{BookshelfCircuit, Orientation};
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LegalizeMode {
+    Tetris,
+    Abacus,
+}
+
+pub struct LegalizerParams {
+    pub mode: LegalizeMode,
+    /// Treat macro blocks as fixed obstacles (they should have been
+    /// legalized already).  If false, macros are ignored completely.
+    pub fixed_macros: bool,
+    pub verbose: bool,
+}
+
+impl LegalizerParams {
+    pub fn new() -> LegalizerParams {
+        LegalizerParams {
+            mode: LegalizeMode::Abacus,
+            fixed_macros: true,
+            verbose: false,
+        }
+    }
+}
+
+/// Summary of a legalization run.  Movement is measured from the
+/// reference positions (the locations prior to legalization), using
+/// Manhattan distance.
+pub struct LegalizeResult {
+    pub total_movement: f32,
+    pub max_movement: f32,
+    pub max_cell: usize,
+    /// Cells that could not be fit into any row segment
+    pub unplaced: Vec<usize>,
+}
+
+/// A free span of a row, between fixed obstacles.  The ends of the
+/// segment are aligned to sites.
+pub struct RowSegment {
+    pub row: usize,
+    pub llx: f32,
+    pub urx: f32,
+    pub y: f32,
+    pub height: f32,
+    /// Origin and spacing of the sites for the row
+    pub origin: f32,
+    pub site_spacing: f32,
+    /// Device tier of the row (0 for 2D designs)
+    pub tier: usize,
+}
+
+impl RowSegment {
+    pub fn width(&self) -> f32 {
+        self.urx - self.llx
+    }
+    /// Round a position to the nearest site, keeping a cell of width w
+    /// inside the segment.
+    pub fn snap(&self, x: f32, w: f32) -> f32 {
+        let mut sx = x;
+        if self.site_spacing > 0.0 {
+            sx = self.origin + ((x - self.origin) / self.site_spacing).round() * self.site_spacing;
+        }
+        if sx + w > self.urx {
+            sx = self.urx - w;
+            if self.site_spacing > 0.0 {
+                sx = self.origin
+                    + ((sx - self.origin) / self.site_spacing).floor() * self.site_spacing;
+            }
+        }
+        if sx < self.llx {
+            sx = self.llx;
+        }
+        sx
+    }
+}
+
+// Abacus cluster: a run of abutting cells within a segment.  The cells
+// are seg_cells[first..=last]; e is the total weight, q the weighted
+// sum of desired positions (adjusted for cell offsets in the cluster).
+#[derive(Clone, Copy)]
+struct Cluster {
+    x: f32,
+    e: f32,
+    q: f32,
+    w: f32,
+    first: usize,
+    last: usize,
+}
+
+struct SegmentState {
+    cells: Vec<usize>,
+    clusters: Vec<Cluster>,
+    used: f32,
+    // Tetris frontier
+    frontier: f32,
+}
+
+impl BookshelfCircuit {
+    /// Breaks the rows into free segments, removing the spans that are
+    /// covered by terminals (and macros, if fixed_macros is set).
+    /// Segments are sorted by row Y, and then by X.
+    pub fn row_segments(&self, fixed_macros: bool) -> Vec<RowSegment> {
+        let mut segments = Vec::new();
+        let mut order: Vec<usize> = (0..self.rows.len()).collect();
+        order.sort_by(|a, b| {
+            let ra = &self.rows[*a].bounds;
+            let rb = &self.rows[*b].bounds;
+            ra.lly.partial_cmp(&rb.lly).unwrap().then(ra.llx.partial_cmp(&rb.llx).unwrap())
+        });
+
+        for r in order {
+            let row = &self.rows[r];
+            let mut blocked: Vec<(f32, f32)> = Vec::new();
+            for i in 0..self.cells.len() {
+                let c = &self.cells[i];
+                if !(c.terminal || (fixed_macros && c.is_macro)) || self.tier_of(i) != row.tier {
+                    continue;
+                }
+                let p = &self.cellpos[i];
+                if p.y < row.bounds.ury
+                    && p.y + c.h > row.bounds.lly
+                    && p.x < row.bounds.urx
+                    && p.x + c.w > row.bounds.llx
+                {
+                    blocked.push((p.x, p.x + c.w));
+                }
+            }
+            blocked.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+            let mut start = row.bounds.llx;
+            for (bl, br) in blocked {
+                if bl > start {
+                    self.add_segment(&mut segments, r, start, bl);
+                }
+                start = start.max(br);
+            }
+            if start < row.bounds.urx {
+                self.add_segment(&mut segments, r, start, row.bounds.urx);
+            }
+        }
+        segments
+    }
+
+    fn add_segment(&self, segments: &mut Vec<RowSegment>, r: usize, llx: f32, urx: f32) {
+        let row = &self.rows[r];
+        let site = row.site_spacing;
+        let mut l = llx;
+        let mut u = urx;
+        if site > 0.0 {
+            l = row.bounds.llx + ((llx - row.bounds.llx) / site).ceil() * site;
+            u = row.bounds.llx + ((urx - row.bounds.llx) / site).floor() * site;
+        }
+        if u - l <= 0.0 {
+            return;
+        }
+        segments.push(RowSegment {
+            row: r,
+            llx: l,
+            urx: u,
+            y: row.bounds.lly,
+            height: row.bounds.dy(),
+            origin: row.bounds.llx,
+            site_spacing: site,
+            tier: row.tier,
+        });
+    }
+
+    /// Legalizes the movable standard cells into the rows.  The current
+    /// positions are saved as the reference positions first, so that
+    /// ps_movement can show the displacement.  Each cell is given an
+    /// orientation that its new row allows.
+    pub fn legalize(&mut self, params: &LegalizerParams) -> LegalizeResult {
+        self.set_refpos();
+        let segments = self.row_segments(params.fixed_macros);
+
+        let mut state: Vec<SegmentState> = Vec::with_capacity(segments.len());
+        for s in &segments {
+            state.push(SegmentState {
+                cells: Vec::new(),
+                clusters: Vec::new(),
+                used: 0.0,
+                frontier: s.llx,
+            });
+        }
+
+        // Distinct row Y values, to find the rows near a cell quickly.
+        let mut row_ys: Vec<f32> = Vec::new();
+        let mut row_first: Vec<usize> = Vec::new();
+        for (i, s) in segments.iter().enumerate() {
+            if row_ys.is_empty() || *row_ys.last().unwrap() != s.y {
+                row_ys.push(s.y);
+                row_first.push(i);
+            }
+        }
+        row_first.push(segments.len());
+
+        let mut cells = Vec::new();
+        for i in 0..self.cells.len() {
+            let c = &self.cells[i];
+            if c.terminal || c.is_macro {
+                continue;
+            }
+            cells.push(i);
+        }
+        cells.sort_by(|a, b| self.cellpos[*a].x.partial_cmp(&self.cellpos[*b].x).unwrap());
+
+        let mut unplaced = Vec::new();
+        for c in cells {
+            let (tx, ty) = (self.cellpos[c].x, self.cellpos[c].y);
+            let w = self.cells[c].w;
+            let h = self.cells[c].h;
+            let tier = self.tier_of(c);
+
+            let nearest = match row_ys.binary_search_by(|y| y.partial_cmp(&ty).unwrap()) {
+                Ok(i) => i,
+                Err(i) => i.min(row_ys.len().saturating_sub(1)),
+            };
+
+            let mut best: Option<(usize, f32)> = None;
+            let mut best_cost = f32::MAX;
+            // Search outward from the nearest row, up and down, until the
+            // vertical displacement alone is worse than the best so far.
+            let mut down = nearest as i64;
+            let mut up = nearest as i64 + 1;
+            while down >= 0 || (up as usize) < row_ys.len() {
+                let mut progressed = false;
+                for r in [down, up] {
+                    if r < 0 || r as usize >= row_ys.len() {
+                        continue;
+                    }
+                    let dy = (row_ys[r as usize] - ty).abs();
+                    if dy >= best_cost {
+                        continue;
+                    }
+                    progressed = true;
+                    for s in row_first[r as usize]..row_first[r as usize + 1] {
+                        let seg = &segments[s];
+                        if seg.tier != tier || h > seg.height || state[s].used + w > seg.width() {
+                            continue;
+                        }
+                        let (x, extra) = match params.mode {
+                            LegalizeMode::Tetris => {
+                                // The first site at or after the frontier
+                                // (or the target), pulled back to fit
+                                let frontier = state[s].frontier;
+                                let mut x = tx.max(frontier);
+                                if seg.site_spacing > 0.0 {
+                                    x = seg.origin + ((x - seg.origin) / seg.site_spacing).ceil() * seg.site_spacing;
+                                }
+                                if x + w > seg.urx {
+                                    x = seg.snap(seg.urx - w, w);
+                                }
+                                if x < frontier {
+                                    continue;
+                                }
+                                (x, 0.0)
+                            }
+                            LegalizeMode::Abacus => self.abacus_trial(seg, &state[s], tx, w),
+                        };
+                        let cost = (x - tx).abs() + extra + dy;
+                        if cost < best_cost {
+                            best_cost = cost;
+                            best = Some((s, x));
+                        }
+                    }
+                }
+                if !progressed {
+                    break;
+                }
+                down = down - 1;
+                up = up + 1;
+            }
+
+            match best {
+                Some((s, x)) => {
+                    let seg = &segments[s];
+                    self.cellpos[c].y = seg.y;
+                    self.set_orientation(c, self.row_orientation(c, seg.row));
+                    let st = &mut state[s];
+                    st.used = st.used + w;
+                    match params.mode {
+                        LegalizeMode::Tetris => {
+                            self.cellpos[c].x = x;
+                            st.frontier = x + w;
+                            st.cells.push(c);
+                        }
+                        LegalizeMode::Abacus => {
+                            st.cells.push(c);
+                            BookshelfCircuit::abacus_add(seg, st, tx, w);
+                        }
+                    }
+                }
+                None => {
+                    unplaced.push(c);
+                }
+            }
+        }
+
+        // Abacus positions are only final once all cells are in.
+        if params.mode == LegalizeMode::Abacus {
+            for st in &state {
+                for cl in &st.clusters {
+                    let mut x = cl.x;
+                    for i in cl.first..=cl.last {
+                        let c = st.cells[i];
+                        self.cellpos[c].x = x;
+                        x = x + self.cells[c].w;
+                    }
+                }
+            }
+        }
+
+        let result = self.movement(unplaced);
+        // Only name the cell if something moved (there may be no cells)
+        let max_name = match self.cells.get(result.max_cell) {
+            Some(c) if result.max_movement > 0.0 => format!(" (cell {})", c.name),
+            _ => "".to_string(),
+        };
+        println!(
+            "Legalization: total movement {}, max movement {}{}, {} unplaced",
+            result.total_movement,
+            result.max_movement,
+            max_name,
+            result.unplaced.len()
+        );
+        self.notes.push(format!(
+            "Legalized: total movement {:.1}, max movement {:.1}, HPWL {}",
+            result.total_movement,
+            result.max_movement,
+            self.wl()
+        ));
+        result
+    }
+
+    /// Measures the displacement of every cell from the reference
+    /// positions.
+    pub fn movement(&self, unplaced: Vec<usize>) -> LegalizeResult {
+        let mut result = LegalizeResult {
+            total_movement: 0.0,
+            max_movement: 0.0,
+            max_cell: 0,
+            unplaced: unplaced,
+        };
+        if let Some(rp) = &self.refpos {
+            for i in 0..self.cells.len() {
+                let d = (self.cellpos[i].x - rp[i].x).abs() + (self.cellpos[i].y - rp[i].y).abs();
+                result.total_movement = result.total_movement + d;
+                if d > result.max_movement {
+                    result.max_movement = d;
+                    result.max_cell = i;
+                }
+            }
+        }
+        result
+    }
+
+    // Position the new cell would get if appended to the segment, without
+    // changing the clusters, and the change in the displacement of the
+    // cells already in the clusters that would move with it.  Until the
+    // end of legalize, the X position of each cell is still its target.
+    fn abacus_trial(&self, seg: &RowSegment, st: &SegmentState, tx: f32, w: f32) -> (f32, f32) {
+        let clusters = &st.clusters;
+        let mut idx = clusters.len();
+        let mut e = 1.0;
+        let mut q = tx;
+        let mut cw = w;
+        if idx > 0 {
+            let last = &clusters[idx - 1];
+            if last.x + last.w > tx {
+                e = last.e + 1.0;
+                q = last.q + (tx - last.w);
+                cw = last.w + w;
+                idx = idx - 1;
+            }
+        }
+        loop {
+            let x = seg.snap(q / e, cw);
+            if idx > 0 && clusters[idx - 1].x + clusters[idx - 1].w > x {
+                let prev = &clusters[idx - 1];
+                q = prev.q + q - e * prev.w;
+                e = prev.e + e;
+                cw = prev.w + cw;
+                idx = idx - 1;
+            } else {
+                let mut extra = 0.0;
+                let mut new_x = x;
+                for cl in &clusters[idx..] {
+                    let mut old_x = cl.x;
+                    for i in cl.first..=cl.last {
+                        let c = st.cells[i];
+                        let t = self.cellpos[c].x;
+                        extra = extra + (new_x - t).abs() - (old_x - t).abs();
+                        new_x = new_x + self.cells[c].w;
+                        old_x = old_x + self.cells[c].w;
+                    }
+                }
+                return (new_x, extra);
+            }
+        }
+    }
+
+    fn abacus_add(seg: &RowSegment, st: &mut SegmentState, tx: f32, w: f32) {
+        let n = st.cells.len() - 1;
+        let mut new_cluster = true;
+        if let Some(last) = st.clusters.last_mut() {
+            if last.x + last.w > tx {
+                last.e = last.e + 1.0;
+                last.q = last.q + (tx - last.w);
+                last.w = last.w + w;
+                last.last = n;
+                new_cluster = false;
+            }
+        }
+        if new_cluster {
+            st.clusters.push(Cluster {
+                x: tx,
+                e: 1.0,
+                q: tx,
+                w: w,
+                first: n,
+                last: n,
+            });
+        }
+        // Collapse -- place the last cluster, merging with its
+        // predecessor while they overlap.
+        loop {
+            let k = st.clusters.len() - 1;
+            let x = seg.snap(st.clusters[k].q / st.clusters[k].e, st.clusters[k].w);
+            st.clusters[k].x = x;
+            if k > 0 && st.clusters[k - 1].x + st.clusters[k - 1].w > x {
+                let c = st.clusters.pop().unwrap();
+                let prev = st.clusters.last_mut().unwrap();
+                prev.q = prev.q + c.q - c.e * prev.w;
+                prev.e = prev.e + c.e;
+                prev.w = prev.w + c.w;
+                prev.last = c.last;
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+impl BookshelfCircuit {
+    /// The row containing the point, if any.
+    pub fn row_at(&self, x: f32, y: f32) -> Option<usize> {
+        self.rows.iter().position(|r| {
+            y >= r.bounds.lly && y < r.bounds.ury && x >= r.bounds.llx && x < r.bounds.urx
+        })
+    }
+
+    // Orientation for a standard cell in a row: the current one if the
+    // row allows it, otherwise the row orientation (or its mirror in X,
+    // if the cell was mirrored, so that the pin order is kept).
+    pub(crate) fn row_orientation(&self, cell_id: usize, r: usize) -> Orientation {
+        let row = &self.rows[r];
+        let o = self.orient[cell_id];
+        let allowed = row.orientations();
+        if allowed.contains(&o) {
+            return o;
+        }
+        let mirrored = o.matrix()[0][0] < 0;
+        if mirrored && allowed.contains(&row.orient.flip_x()) {
+            row.orient.flip_x()
+        } else {
+            row.orient
+        }
+    }
+
+    /// Sets the orientation of every movable standard cell to one that
+    /// its row allows.  Returns the number of cells changed.
+    pub fn snap_row_orientations(&mut self) -> usize {
+        let mut changed = 0;
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal || self.cells[i].is_macro {
+                continue;
+            }
+            if let Some(r) = self.row_at(self.cellpos[i].x, self.cellpos[i].y) {
+                let o = self.row_orientation(i, r);
+                if o != self.orient[i] {
+                    self.set_orientation(i, o);
+                    changed += 1;
+                }
+            }
+        }
+        self.notes.push(format!("Row orientations: {} cells flipped", changed));
+        changed
+    }
+
+    /// Standard cells with an orientation that their row does not
+    /// allow.  Cells that are not in a row are skipped.
+    pub fn check_row_orientations(&self) -> Vec<usize> {
+        let mut bad = Vec::new();
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal || self.cells[i].is_macro {
+                continue;
+            }
+            if let Some(r) = self.row_at(self.cellpos[i].x, self.cellpos[i].y) {
+                if !self.rows[r].allows(self.orient[i]) {
+                    bad.push(i);
+                }
+            }
+        }
+        if !bad.is_empty() {
+            println!("{} cells with orientations that do not match the row", bad.len());
+            for c in bad.iter().take(10) {
+                let r = self.row_at(self.cellpos[*c].x, self.cellpos[*c].y).unwrap();
+                println!(
+                    "  {} is {}, row at {} is {}",
+                    self.cells[*c].name, self.orient[*c], self.rows[r].bounds.lly, self.rows[r].orient
+                );
+            }
+        }
+        bad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_legal, TestCircuit};
+
+    // Four rows of 100 sites, a fixed block across the lower two rows,
+    // and 24 cells piled up in the middle
+    fn crowded() -> TestCircuit {
+        let mut tc = TestCircuit::new().rows(4, 100, 10).terminal("block", 12.5, 20.0, 44.5, 0.0);
+        for i in 0..24 {
+            let x = 40.0 + (i % 7) as f32 * 1.3;
+            let y = 12.0 + (i % 3) as f32 * 2.1;
+            tc = tc.cell(&format!("c{i}"), (3 + i % 5) as f32, 10.0, x, y);
+        }
+        tc
+    }
+
+    #[test]
+    fn abacus_is_legal() {
+        let mut bc = crowded().build();
+        let res = bc.legalize(&LegalizerParams::new());
+        assert!(res.unplaced.is_empty());
+        assert!(res.max_movement > 0.0);
+        assert_legal(&bc);
+    }
+
+    #[test]
+    fn tetris_is_legal() {
+        let mut bc = crowded().build();
+        let mut params = LegalizerParams::new();
+        params.mode = LegalizeMode::Tetris;
+        let res = bc.legalize(&params);
+        assert!(res.unplaced.is_empty());
+        assert_legal(&bc);
+    }
+
+    #[test]
+    fn tetris_rounds_up_from_frontier() {
+        // The first cell ends at 5.4, off the site grid; the second
+        // belongs at the next site, not in another row
+        let mut bc = TestCircuit::new()
+            .rows(2, 20, 10)
+            .cell("a", 2.4, 10.0, 3.0, 0.0)
+            .cell("b", 2.0, 10.0, 4.0, 0.0)
+            .build();
+        let mut params = LegalizerParams::new();
+        params.mode = LegalizeMode::Tetris;
+        let res = bc.legalize(&params);
+        assert!(res.unplaced.is_empty());
+        let b = bc.cell_map["b"];
+        assert_eq!((bc.cellpos[b].x, bc.cellpos[b].y), (6.0, 0.0));
+    }
+
+    #[test]
+    fn abacus_counts_pushed_cells() {
+        // Three abutting cells at their targets.  A fourth that overlaps
+        // the last would move 2 in the lower row, but push the other
+        // three by 1 each; the next row up is only 4 away.
+        let mut bc = TestCircuit::new()
+            .rows(2, 40, 4)
+            .cell("a", 4.0, 4.0, 10.0, 0.0)
+            .cell("b", 4.0, 4.0, 14.0, 0.0)
+            .cell("c", 4.0, 4.0, 18.0, 0.0)
+            .cell("d", 4.0, 4.0, 19.0, 0.0)
+            .build();
+        let res = bc.legalize(&LegalizerParams::new());
+        assert_eq!(bc.cellpos[bc.cell_map["d"]].y, 4.0);
+        assert_eq!(res.total_movement, 4.0);
+        assert_legal(&bc);
+    }
+
+    #[test]
+    fn nothing_to_move() {
+        let mut bc = TestCircuit::new().rows(4, 100, 10).terminal("block", 12.5, 20.0, 44.5, 0.0).build();
+        let res = bc.legalize(&LegalizerParams::new());
+        assert_eq!(res.max_movement, 0.0);
+        assert!(res.unplaced.is_empty());
+    }
+}
@@ -17,11 +17,57 @@
 //! marklist helps make this process more efficient.
 //!
 //! The hypergraph structures are contained in the external
-//! `metapartition` crate.
+//! `metapartition` crate.  Hypergraphs can be built for two-way or
+//! k-way splits; *partition* does k-way and grid partitioning by
+//! recursive bisection.  *fm* is a built-in multilevel
+//! Fiduccia-Mattheyses bipartitioner that needs no external tools.
+//!
+//! The *placer* module is a recursive bisection (min-cut) placer,
+//! built on the hypergraph construction and metapartition.  The
+//! *legalizer* module snaps standard cells into rows (Tetris or Abacus),
+//! and *detail* has detailed placement passes that keep the rows legal.
+//! For mixed-size designs, *macrolegal* moves the macro blocks first.
+//!
+//! Block packing designs can be floorplanned with *floorplan*, a
+//! sequence pair annealer.  *random* is a small seedable random
+//! number generator for the annealing code.  After floorplanning,
+//! *pads* moves the terminals around the new outline.
+//!
+//! Synthetic test cases (with Rent's rule connectivity) can be created
+//! with *generator*, and written out with `write_aux`.  *stats* has
+//! a more complete set of circuit statistics, with JSON output.
+//! Windows of a design can be cut out with *extract*, placed on their
+//! own, and merged back in.  *edit* adds and removes cells, nets, and
+//! pins, keeping all of the indices consistent.  For multilevel
+//! placement, *cluster* builds coarser circuits (first-choice or
+//! best-choice), and spreads the cluster positions back out.
+//! *tiers* adds device tiers for 3D placement, with a via cost on the
+//! nets that cross them.  *timing* is a simple static timing analyzer
+//! (Elmore wire delays from the placed pins), with net criticality for
+//! timing-driven weights.
 pub mod bookshelf;
+pub mod cluster;
+pub mod detail;
+pub mod edit;
+pub mod extract;
+pub mod floorplan;
+pub mod fm;
+pub mod generator;
+pub mod legalizer;
+pub mod macrolegal;
 pub mod marklist;
+pub mod pads;
+pub mod partition;
+pub mod placer;
+pub mod random;
+pub mod stats;
+pub mod tiers;
+pub mod timing;
 // pub extern crate metapartition;
 
 // pub mod hypergraph;
 // pub mod bbox;  // Now in pstools
 // pub mod point;
+
+#[cfg(test)]
+mod testutil;
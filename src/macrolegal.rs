@@ -0,0 +1,351 @@
+//! Macro legalization for mixed-size designs.
+//!
+//! Macro blocks (cells taller than a row, tagged is_macro by read_aux)
+//! are moved to non-overlapping positions inside the core, aligned
+//! to the row boundaries and sites.  The macros are handled largest
+//! first; each one goes to the closest spot (by Manhattan distance to
+//! its current location) that does not overlap anything placed so far.
+//! Candidate spots are the abutting positions next to the nearby
+//! obstacles, so the search widens until a legal spot is found.
+//!
+//! After the macros are legal, each is tried in the different
+//! orientations, keeping whichever gives the lowest wire length (and
+//! stays legal).  With the macros done, the standard cells can be
+//! legalized around them with the legalizer.
+use crate::bookshelf::{BookshelfCircuit, Orientation, WlCalc};
+use crate::legalizer::LegalizeResult;
+use pstools::bbox::BBox;
+
+pub struct MacroParams {
+    /// Try the orientations of each macro to reduce wire length
+    pub orient: bool,
+    /// Align macros to row boundaries and sites
+    pub snap: bool,
+    pub verbose: bool,
+}
+
+impl MacroParams {
+    pub fn new() -> MacroParams {
+        MacroParams {
+            orient: true,
+            snap: true,
+            verbose: false,
+        }
+    }
+}
+
+impl BookshelfCircuit {
+    /// Moves all of the macro blocks to legal, non-overlapping positions.
+    /// The current positions become the reference positions, so that the
+    /// movement can be displayed.
+    pub fn legalize_macros(&mut self, params: &MacroParams) -> LegalizeResult {
+        self.set_refpos();
+        let core = self.core();
+
+        let mut row_ys: Vec<f32> = self.rows.iter().map(|r| r.bounds.lly).collect();
+        row_ys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        row_ys.dedup();
+
+        // Terminals inside the core are obstacles from the start.
+        let mut placed: Vec<usize> = Vec::new();
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal && self.overlaps_box(i, &core) {
+                placed.push(i);
+            }
+        }
+
+        let mut macros: Vec<usize> = (0..self.cells.len())
+            .filter(|i| self.cells[*i].is_macro && !self.cells[*i].terminal)
+            .collect();
+        macros.sort_by(|a, b| self.cells[*b].area().partial_cmp(&self.cells[*a].area()).unwrap());
+
+        let mut unplaced = Vec::new();
+        for m in &macros {
+            let m = *m;
+            let (x, y) = self.clamp_macro(m, &core, &row_ys, params.snap,
+                self.cellpos[m].x, self.cellpos[m].y);
+            self.cellpos[m].x = x;
+            self.cellpos[m].y = y;
+            if !self.macro_legal(m, &placed, &core) {
+                match self.nearest_legal(m, &placed, &core, &row_ys, params.snap) {
+                    Some((lx, ly)) => {
+                        self.cellpos[m].x = lx;
+                        self.cellpos[m].y = ly;
+                    }
+                    None => {
+                        // Not an obstacle for the others, and not reoriented
+                        unplaced.push(m);
+                        continue;
+                    }
+                }
+            }
+            placed.push(m);
+        }
+
+        if params.orient {
+            let legal: Vec<usize> = macros.iter().filter(|m| !unplaced.contains(*m)).cloned().collect();
+            let flips = self.orient_macros(&legal, &placed, &core);
+            if params.verbose {
+                println!("Reoriented {} macros", flips);
+            }
+        }
+
+        let result = self.movement(unplaced);
+        println!(
+            "Macro legalization: {} macros, total movement {}, max movement {}, {} unplaced",
+            macros.len(),
+            result.total_movement,
+            result.max_movement,
+            result.unplaced.len()
+        );
+        self.notes.push(format!(
+            "Macros legalized: total movement {:.1}, max movement {:.1}, HPWL {}",
+            result.total_movement,
+            result.max_movement,
+            self.wl()
+        ));
+        result
+    }
+
+    fn overlaps_box(&self, c: usize, b: &BBox) -> bool {
+        let p = &self.cellpos[c];
+        let cell = &self.cells[c];
+        p.x < b.urx && p.x + cell.w > b.llx && p.y < b.ury && p.y + cell.h > b.lly
+    }
+
+    fn cells_overlap(&self, a: usize, b: usize) -> bool {
+        let pa = &self.cellpos[a];
+        let pb = &self.cellpos[b];
+        self.tier_of(a) == self.tier_of(b)
+            && pa.x < pb.x + self.cells[b].w
+            && pb.x < pa.x + self.cells[a].w
+            && pa.y < pb.y + self.cells[b].h
+            && pb.y < pa.y + self.cells[a].h
+    }
+
+    // Inside the core, and no overlap with anything already placed.
+    fn macro_legal(&self, m: usize, placed: &Vec<usize>, core: &BBox) -> bool {
+        let p = &self.cellpos[m];
+        let c = &self.cells[m];
+        if p.x < core.llx || p.y < core.lly || p.x + c.w > core.urx || p.y + c.h > core.ury {
+            return false;
+        }
+        for o in placed {
+            if *o != m && self.cells_overlap(m, *o) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Keep a macro inside the core, and on the row and site grid.
+    fn clamp_macro(&self, m: usize, core: &BBox, row_ys: &Vec<f32>, snap: bool, x: f32, y: f32) -> (f32, f32) {
+        let w = self.cells[m].w;
+        let h = self.cells[m].h;
+        let mut x = x.max(core.llx).min(core.urx - w);
+        let mut y = y.max(core.lly).min(core.ury - h);
+        if snap {
+            if self.unit_x > 0.0 {
+                x = core.llx + ((x - core.llx) / self.unit_x).round() * self.unit_x;
+                if x + w > core.urx {
+                    x = x - self.unit_x;
+                }
+            }
+            // Closest row boundary that keeps the macro inside the core
+            let mut best = y;
+            let mut best_d = f32::MAX;
+            for ry in row_ys {
+                if *ry + h > core.ury {
+                    break;
+                }
+                let d = (*ry - y).abs();
+                if d < best_d {
+                    best_d = d;
+                    best = *ry;
+                }
+            }
+            y = best;
+        }
+        (x, y)
+    }
+
+    // Closest legal position for a macro.  Candidates abut the obstacles
+    // near the macro; if none of those work, look further away.
+    fn nearest_legal(
+        &mut self,
+        m: usize,
+        placed: &Vec<usize>,
+        core: &BBox,
+        row_ys: &Vec<f32>,
+        snap: bool,
+    ) -> Option<(f32, f32)> {
+        let (dx, dy) = (self.cellpos[m].x, self.cellpos[m].y);
+        let w = self.cells[m].w;
+        let h = self.cells[m].h;
+        let mut radius = 2.0 * w.max(h);
+        let max_radius = 2.0 * (core.dx() + core.dy());
+
+        loop {
+            let mut xs = vec![dx];
+            let mut ys = vec![dy];
+            for o in placed {
+                let p = &self.cellpos[*o];
+                let c = &self.cells[*o];
+                if p.x > dx + w + radius || p.x + c.w < dx - radius
+                    || p.y > dy + h + radius || p.y + c.h < dy - radius
+                {
+                    continue;
+                }
+                xs.push(p.x + c.w);
+                xs.push(p.x - w);
+                ys.push(p.y + c.h);
+                ys.push(p.y - h);
+            }
+            let mut candidates = Vec::new();
+            for x in &xs {
+                for y in &ys {
+                    let (cx, cy) = self.clamp_macro(m, core, row_ys, false, *x, *y);
+                    candidates.push((cx, cy));
+                    if snap {
+                        // Snapping can land on an obstacle, so also try the
+                        // snapped row above
+                        let (sx, sy) = self.clamp_macro(m, core, row_ys, true, cx, cy);
+                        candidates.push((sx, sy));
+                        let (ux, uy) = self.clamp_macro(m, core, row_ys, true, cx, cy + self.row_height);
+                        candidates.push((ux, uy));
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| {
+                let da = (a.0 - dx).abs() + (a.1 - dy).abs();
+                let db = (b.0 - dx).abs() + (b.1 - dy).abs();
+                da.partial_cmp(&db).unwrap()
+            });
+            for (x, y) in candidates {
+                if snap && !row_ys.is_empty() && !row_ys.contains(&y) {
+                    continue;
+                }
+                self.cellpos[m].x = x;
+                self.cellpos[m].y = y;
+                if self.macro_legal(m, placed, core) {
+                    return Some((x, y));
+                }
+            }
+            if radius > max_radius {
+                self.cellpos[m].x = dx;
+                self.cellpos[m].y = dy;
+                return None;
+            }
+            radius = radius * 2.0;
+        }
+    }
+
+    // Try each orientation of each macro, keeping the best legal one.
+    // Macros that cannot rotate only get the orientations that keep
+    // the footprint the same as the current one.
+    fn orient_macros(&mut self, macros: &Vec<usize>, placed: &Vec<usize>, core: &BBox) -> usize {
+        let mut wlc = WlCalc::new(self);
+        let mut changed = 0;
+        for m in macros {
+            let m = *m;
+            let original = self.orient[m];
+            wlc.clear();
+            wlc.add_cells(self, &vec![m]);
+            let mut best = wlc.wl(self);
+            let mut best_orient = original;
+            for o in Orientation::ALL {
+                if o == original {
+                    continue;
+                }
+                if o.swaps() != original.swaps() && !self.cells[m].can_rotate {
+                    continue;
+                }
+                self.set_orientation(m, o);
+                if !self.macro_legal(m, placed, core) {
+                    continue;
+                }
+                let wl = wlc.wl(self);
+                if wl < best {
+                    best = wl;
+                    best_orient = o;
+                }
+            }
+            self.set_orientation(m, best_orient);
+            if best_orient != original {
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{overlaps, TestCircuit};
+
+    #[test]
+    fn macros_separated_in_core() {
+        // Ten rows of 100 sites; three macros piled on top of each other,
+        // and one hanging off the upper right corner of the core
+        let mut bc = TestCircuit::new()
+            .rows(10, 100, 10)
+            .cell("m0", 30.0, 30.0, 20.0, 20.0)
+            .cell("m1", 25.0, 40.0, 25.0, 25.0)
+            .cell("m2", 20.0, 20.0, 30.5, 22.5)
+            .cell("m3", 30.0, 30.0, 85.0, 90.0)
+            .net("n0", &["m0", "m1", "m2", "m3"])
+            .build();
+        let res = bc.legalize_macros(&MacroParams::new());
+        assert!(res.unplaced.is_empty());
+        assert!(overlaps(&bc).is_empty());
+        let core = bc.core();
+        for i in 0..bc.cells.len() {
+            let (p, c) = (&bc.cellpos[i], &bc.cells[i]);
+            assert!(c.is_macro);
+            assert!(p.x >= core.llx && p.y >= core.lly, "{} is outside the core", c.name);
+            assert!(p.x + c.w <= core.urx && p.y + c.h <= core.ury, "{} is outside the core", c.name);
+            // On a row boundary and a site
+            assert_eq!(p.y % 10.0, 0.0);
+            assert_eq!(p.x, p.x.round());
+        }
+    }
+
+    #[test]
+    fn flip_toward_pad() {
+        // The pin is on the upper right of the macro, and the pad is to
+        // the left at the same height; mirroring in X is the only way to
+        // bring the pin closer
+        let mut bc = TestCircuit::new()
+            .rows(20, 100, 5)
+            .cell("m", 20.0, 20.0, 40.0, 40.0)
+            .terminal("p", 1.0, 1.0, -10.0, 57.5)
+            .pins("n", &[("m", "B", 8.0, 8.0), ("p", "B", 0.0, 0.0)])
+            .build();
+        let before = bc.wl();
+        bc.legalize_macros(&MacroParams::new());
+        assert!(bc.orient[0] == Orientation::FN, "{}", bc.orient[0]);
+        assert_eq!((bc.cellpos[0].x, bc.cellpos[0].y), (40.0, 40.0));
+        assert!((before - bc.wl() - 16.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rotation_only_when_allowed() {
+        // A wide macro with its pin on the right end, and a pad well
+        // above it; turning the macro on end would help the most
+        for can_rotate in [true, false] {
+            let mut bc = TestCircuit::new()
+                .rows(20, 100, 5)
+                .cell("m", 40.0, 10.0, 40.0, 40.0)
+                .terminal("p", 1.0, 1.0, 49.5, 129.5)
+                .pins("n", &[("m", "B", 18.0, 0.0), ("p", "B", 0.0, 0.0)])
+                .build();
+            bc.cells[0].can_rotate = can_rotate;
+            bc.legalize_macros(&MacroParams::new());
+            assert_eq!(bc.orient[0].swaps(), can_rotate, "{}", bc.orient[0]);
+            if !can_rotate {
+                assert_eq!((bc.cells[0].w, bc.cells[0].h), (40.0, 10.0));
+            }
+        }
+    }
+}
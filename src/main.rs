@@ -1,14 +1,16 @@
 //! Bookshelf sample reader
 //! Simple main program to demonstrate things.
 //!
-pub mod bookshelf;
-pub mod marklist;
+use bookshelf_r::{
+    bookshelf, cluster, detail, extract, floorplan, fm, generator, legalizer, macrolegal, pads,
+    placer, timing,
+};
 
 use std::path::Path;
 
 use argh::FromArgs;
 use bookshelf_r::bookshelf::HyperParams;
-use bookshelf_r::bookshelf::{PinDetail, PinInstance};
+use bookshelf_r::bookshelf::{PinDetail, PinDirection, PinInstance};
 use metapartition;
 
 #[derive(FromArgs)]
@@ -22,6 +24,10 @@ struct Args {
     #[argh(switch, short = 'b')]
     block: bool,
 
+    /// number of alternate shapes for each soft block (block packing, default 5)
+    #[argh(option)]
+    shapes: Option<usize>,
+
     /// cell wire length
     #[argh(option, short = 'c')]
     cell: Option<String>,
@@ -69,6 +75,106 @@ struct Args {
     /// partition the circuit
     #[argh(switch)]
     partition: bool,
+
+    /// min-cut placement of the circuit
+    #[argh(switch)]
+    place: bool,
+
+    /// legalize the macro blocks
+    #[argh(switch)]
+    macros: bool,
+
+    /// legalize the standard cells into rows
+    #[argh(switch)]
+    legalize: bool,
+
+    /// use Tetris rather than Abacus for legalization
+    #[argh(switch)]
+    tetris: bool,
+
+    /// detailed placement (after legalization)
+    #[argh(switch)]
+    detail: bool,
+
+    /// floorplan a block packing design
+    #[argh(switch)]
+    floorplan: bool,
+
+    /// fixed outline aspect ratio (height / width) for floorplanning
+    #[argh(option)]
+    aspect: Option<f32>,
+
+    /// move the terminals evenly around the block outline
+    #[argh(switch)]
+    pads: bool,
+
+    /// keep terminals on their original side when placing pads
+    #[argh(switch)]
+    keepside: bool,
+
+    /// generate a synthetic circuit with this many cells (instead of reading one)
+    #[argh(option)]
+    generate: Option<usize>,
+
+    /// write circuit statistics (JSON) to a file
+    #[argh(option)]
+    stats: Option<String>,
+
+    /// region to extract, as llx,lly,urx,ury
+    #[argh(option)]
+    window: Option<String>,
+
+    /// write the extracted window (AUX and associated files)
+    #[argh(option)]
+    extract: Option<String>,
+
+    /// merge the placement of a sub-circuit (AUX file) back into the design
+    #[argh(option)]
+    merge: Option<String>,
+
+    /// cluster the cells (best-choice), and color them by cluster
+    #[argh(switch)]
+    cluster: bool,
+
+    /// partition into a grid of n by n regions (2 for quadrisection), and color by partition
+    #[argh(option)]
+    grid: Option<usize>,
+
+    /// use the built-in FM partitioner (for --partition and --place)
+    #[argh(switch)]
+    fm: bool,
+
+    /// postscript of the partition (from --partition), with the cut nets highlighted
+    #[argh(option)]
+    cutnets: Option<String>,
+
+    /// flip the standard cells to match the orientation of their rows
+    #[argh(switch)]
+    roworient: bool,
+
+    /// fold the placement into n device tiers (3D), before legalization
+    #[argh(option)]
+    tiers: Option<usize>,
+
+    /// wire length added for each tier a net crosses
+    #[argh(option)]
+    viacost: Option<f32>,
+
+    /// postscript with the tiers side by side
+    #[argh(option)]
+    tierps: Option<String>,
+
+    /// static timing analysis against this clock period (0 for the longest path)
+    #[argh(option)]
+    period: Option<f32>,
+
+    /// timing-driven net weights, up to this maximum (for --place, and the net weights)
+    #[argh(option)]
+    critweight: Option<f32>,
+
+    /// report the wire length with each pin at its nearest access point
+    #[argh(switch)]
+    access: bool,
 }
 
 use metapartition::metapartitioner::Metapartitioner;
@@ -95,36 +201,47 @@ fn main() {
         return;
     }
 
-    let auxname;
-    match arguments.aux {
-        Some(b) => {
-            auxname = b;
-        }
-        _ => {
-            println!("Specify a Bookshelf file name");
-            return;
-        }
-    }
-
     let mut bc;
-    if !arguments.block {
-        println!("Bookshelf Standard Cell/Mixed Size reader");
-        bc = bookshelf::BookshelfCircuit::read_aux(&auxname.clone());
-        if arguments.plfile.is_some() {
-            let f = arguments.plfile.unwrap();
-            let path = Path::new(&f);
-            bc.read_pl(path, false);
-        }
+    if arguments.generate.is_some() {
+        let gp = generator::GeneratorParams::new(arguments.generate.unwrap());
+        bc = bookshelf::BookshelfCircuit::generate(&gp);
         bc.summarize();
     } else {
-        println!("Bookshelf Block Packing Reader");
-        bc = bookshelf::BookshelfCircuit::read_blockpacking(auxname);
-        bc.summarize();
+        let auxname;
+        match arguments.aux {
+            Some(b) => {
+                auxname = b;
+            }
+            _ => {
+                println!("Specify a Bookshelf file name");
+                return;
+            }
+        }
+
+        if !arguments.block {
+            println!("Bookshelf Standard Cell/Mixed Size reader");
+            bc = bookshelf::BookshelfCircuit::read_aux(&auxname.clone());
+            if arguments.plfile.is_some() {
+                let f = arguments.plfile.unwrap();
+                let path = Path::new(&f);
+                bc.read_pl(path, false);
+            }
+            bc.summarize();
+        } else {
+            println!("Bookshelf Block Packing Reader");
+            bc = bookshelf::BookshelfCircuit::read_blockpacking_shapes(auxname, arguments.shapes.unwrap_or(5), true);
+            bc.summarize();
+        }
     }
 
     if arguments.plxfile.is_some() {
         bc.read_plx(&arguments.plxfile.unwrap());
     }
+    if arguments.stats.is_some() {
+        let st = bc.stats();
+        st.report();
+        st.write_json(&arguments.stats.unwrap());
+    }
     if arguments.partition {
         let mut params = bookshelf::HyperParams::new(&bc);
         let mut cells = Vec::new();
@@ -133,9 +250,155 @@ fn main() {
         }
 
         let hg = bc.build_graph(&cells, &mut params);
-        let mp = Metapartitioner::new();
-        let (left, right, cut) = mp.hg_partition(&hg);
-        println!("Cut {cut}");
+        // Vertices past the cells are the fixed source and sink
+        let mut vertex_part = vec![-1; cells.len()];
+        if arguments.fm {
+            let mut fp = fm::FMParams::new();
+            fp.verbose = true;
+            let (part, cut) = fm::FMPartitioner::new(fp).bipartition(&hg, params.bias);
+            println!("Cut {cut}");
+            vertex_part.copy_from_slice(&part[0..cells.len()]);
+        } else {
+            let mp = Metapartitioner::new();
+            let (left, right, cut) = mp.hg_partition(&hg);
+            println!("Cut {cut}");
+            for &v in &left {
+                if v < cells.len() {
+                    vertex_part[v] = 0;
+                }
+            }
+            for &v in &right {
+                if v < cells.len() {
+                    vertex_part[v] = 1;
+                }
+            }
+        }
+        params.set_partition(&vertex_part);
+        let rep = bc.partition_report(&params);
+        rep.report();
+        bc.notes.push(format!(
+            "Partition: cut {}, SOED {}, imbalance {:.3}",
+            rep.cut, rep.soed, rep.imbalance
+        ));
+        bc.color_partition(&params);
+        if arguments.cutnets.is_some() {
+            bc.ps_partition(arguments.cutnets.unwrap(), &params);
+        }
+    }
+    if arguments.grid.is_some() {
+        let n = arguments.grid.unwrap();
+        let mut params = bookshelf::HyperParams::new(&bc);
+        params.set_grid(&bc.core(), n, n);
+        let cells: Vec<usize> = (0..bc.cells.len()).filter(|i| !bc.cells[*i].terminal).collect();
+        let mut fp = fm::FMParams::new();
+        fp.heavy_slack = false;
+        let cut = bc.kway_partition(&cells, &mut params, &fm::FMPartitioner::new(fp));
+        println!("{n} x {n} partitioning, total cut {cut}");
+        bc.partition_report(&params).report();
+        bc.color_partition(&params);
+    }
+    if arguments.cluster {
+        let mut cp = cluster::ClusterParams::new();
+        cp.verbose = true;
+        let cl = bc.cluster(&cp);
+        cl.coarse.summarize();
+        bc.color_clusters(&cl);
+    }
+    if arguments.place {
+        let mut pp = placer::PlacerParams::new();
+        pp.builtin_fm = arguments.fm;
+        if arguments.critweight.is_some() {
+            // Criticality from the starting placement
+            let mut tp = timing::TimingParams::new();
+            tp.clock_period = arguments.period.unwrap_or(0.0);
+            let rep = bc.sta(&bc.timing_graph(), &tp);
+            println!("Timing before placement: WNS {}, TNS {}", rep.wns, rep.tns);
+            pp.edgeweight = bookshelf::EdgeWeight::Criticality {
+                criticality: rep.criticality,
+                max_weight: arguments.critweight.unwrap(),
+            };
+        }
+        bc.set_refpos();
+        let cut = bc.mincut_place(&pp);
+        println!("Min-cut placement total cut {cut}, HPWL {}", bc.wl());
+    }
+    if arguments.viacost.is_some() {
+        bc.via_cost = arguments.viacost.unwrap();
+    }
+    if arguments.tiers.is_some() {
+        let moved = bc.fold_tiers(arguments.tiers.unwrap());
+        println!("Folded into {} tiers, {moved} cells moved, HPWL {}", bc.num_tiers, bc.wl());
+        bc.tier_report();
+    }
+    if arguments.floorplan {
+        let mut fp = floorplan::FloorplanParams::new();
+        if arguments.aspect.is_some() {
+            fp.set_outline(&bc, arguments.aspect.unwrap(), 0.15);
+        }
+        fp.verbose = true;
+        let result = bc.floorplan(&fp);
+        println!(
+            "Floorplan {} x {}, area {}, HPWL {}, fits outline {}",
+            result.width, result.height, result.area, result.wl, result.fits_outline
+        );
+    }
+    if arguments.merge.is_some() {
+        let sub = bookshelf::BookshelfCircuit::read_aux(&arguments.merge.unwrap());
+        bc.merge_placement(&sub);
+    }
+    if arguments.pads {
+        let mut pp = pads::PadParams::new();
+        if arguments.keepside {
+            pp.mode = pads::PadMode::KeepSide;
+        }
+        let outline = bc.cell_bounds();
+        let n = bc.place_pads(&outline, &pp);
+        println!("Placed {n} pads around {outline}, HPWL {}", bc.wl());
+    }
+    if arguments.macros {
+        let mp = macrolegal::MacroParams::new();
+        bc.legalize_macros(&mp);
+    }
+    if arguments.legalize {
+        let mut lp = legalizer::LegalizerParams::new();
+        if arguments.tetris {
+            lp.mode = legalizer::LegalizeMode::Tetris;
+        }
+        let result = bc.legalize(&lp);
+        if result.unplaced.len() > 0 {
+            println!("{} cells could not be legalized", result.unplaced.len());
+        }
+    }
+    if arguments.detail {
+        let mut dp = detail::DetailParams::new();
+        dp.verbose = true;
+        let gain = bc.detail_place(&dp);
+        println!("Detail placement reduced HPWL by {gain}, now {}", bc.wl());
+    }
+    if arguments.roworient {
+        let before = bc.check_row_orientations().len();
+        let changed = bc.snap_row_orientations();
+        println!("{before} cells did not match their rows, {changed} flipped, HPWL {}", bc.wl());
+    }
+    if arguments.period.is_some() || arguments.critweight.is_some() {
+        let mut tp = timing::TimingParams::new();
+        tp.clock_period = arguments.period.unwrap_or(0.0);
+        tp.verbose = true;
+        let g = bc.timing_graph();
+        let rep = bc.sta(&g, &tp);
+        rep.report();
+        bc.notes.push(format!("Timing: WNS {}, TNS {}", rep.wns, rep.tns));
+        if arguments.critweight.is_some() {
+            let n = bc.set_timing_weights(&rep, arguments.critweight.unwrap());
+            println!("{n} nets weighted, weighted HPWL {}", bc.weighted_wl());
+        }
+    }
+    if arguments.tierps.is_some() {
+        bc.tier_report();
+        bc.ps_tiers(arguments.tierps.unwrap());
+    }
+    if arguments.access {
+        println!("HPWL {}, at the nearest pin access points {}", bc.wl(), bc.wl_access());
     }
     if arguments.cell.is_some() {
         let mut wlc = bookshelf::WlCalc::new(&bc);
@@ -167,10 +430,11 @@ fn main() {
         let nidx = bc.net_index(&arguments.net.unwrap().clone()).unwrap();
 
         println!(
-            "Net {} {} wire length {}",
+            "Net {} {} wire length {}, at the nearest access points {}",
             nidx,
             bc.nets[nidx].name,
-            bc.net_wl(&bc.nets[nidx])
+            bc.net_wl(&bc.nets[nidx]),
+            bc.net_wl_access(&bc.nets[nidx])
         );
         for pr in &bc.nets[nidx].pins {
             let instance = &bc.cells[pr.parent_cell].pins[pr.index];
@@ -232,6 +496,24 @@ fn main() {
     if arguments.export.is_some() {
         bc.write_aux(&arguments.export.unwrap());
     }
+    if arguments.extract.is_some() {
+        let mut region = bc.core();
+        if arguments.window.is_some() {
+            let w = arguments.window.unwrap();
+            if let Ok((llx, lly, urx, ury)) = scan_fmt!(&w, "{},{},{},{}", f32, f32, f32, f32) {
+                region = pstools::bbox::BBox::new();
+                region.addpoint(llx, lly);
+                region.addpoint(urx, ury);
+            } else {
+                println!("Could not parse window {w}");
+                return;
+            }
+        }
+        let mut ep = extract::ExtractParams::new();
+        ep.verbose = true;
+        let ex = bc.extract_region(&region, &ep);
+        ex.circuit.write_aux(&arguments.extract.unwrap());
+    }
 }
 
 use bookshelf_r::bookshelf::Cell;
@@ -275,6 +557,7 @@ pub fn flipdemo() {
         is_macro: false,
         is_soft: false,
         can_rotate: true,
+        shape: None,
     };
     c.pins.push(PinInstance {
         name: "a".to_string(),
@@ -282,7 +565,12 @@ pub fn flipdemo() {
         dy: 6.0,
         parent_cell: 0,
         parent_net: 0,
-        details: vec![PinDetail { dx: 6.0, dy: 6.0 }],
+        details: vec![PinDetail {
+            dx: 6.0,
+            dy: 6.0,
+            rect: None,
+        }],
+        direction: PinDirection::Bidirectional,
     });
     c.pins.push(PinInstance {
         name: "b".to_string(),
@@ -293,7 +581,9 @@ pub fn flipdemo() {
         details: vec![PinDetail {
             dx: 36.0,
             dy: 145.0,
+            rect: None,
         }],
+        direction: PinDirection::Bidirectional,
     });
     c.pins.push(PinInstance {
         name: "c".to_string(),
@@ -304,7 +594,9 @@ pub fn flipdemo() {
         details: vec![PinDetail {
             dx: 95.0,
             dy: 195.0,
+            rect: None,
         }],
+        direction: PinDirection::Bidirectional,
     });
     let mut or = Vec::new();
     or.push(Orientation::N);
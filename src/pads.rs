@@ -0,0 +1,216 @@
+//! Pad (terminal) placement around an outline.
+//!
+//! The block packing benchmarks have the terminals at fixed locations,
+//! which only make sense for the original floorplan.  After the blocks
+//! are floorplanned into a new outline, the terminals can be spread
+//! evenly around the boundary of that outline, so that the wire length
+//! is meaningful again.
+//!
+//! The position of each terminal along the old boundary (the bounding
+//! box of the terminals) is found by projecting it to the closest side.
+//! The boundary is walked counter-clockwise from the lower left corner:
+//! bottom, right, top, then left.  Terminals can then be spaced evenly
+//! around the whole outline, in that order, or be kept on the same side
+//! they were on before.
+use crate::bookshelf::BookshelfCircuit;
+use pstools::bbox::BBox;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PadMode {
+    /// Space the pads evenly around the outline, in cell index order
+    Even,
+    /// Keep the relative order of the pads around the boundary
+    KeepOrder,
+    /// Keep each pad on the same side of the chip, and in order along it
+    KeepSide,
+}
+
+pub struct PadParams {
+    pub mode: PadMode,
+    pub verbose: bool,
+}
+
+impl PadParams {
+    pub fn new() -> PadParams {
+        PadParams {
+            mode: PadMode::KeepOrder,
+            verbose: false,
+        }
+    }
+}
+
+// Side of the boundary, and the distance along the whole perimeter
+// (counter-clockwise from the lower left).
+fn perimeter_position(b: &BBox, x: f32, y: f32) -> (usize, f32) {
+    let (w, h) = (b.dx(), b.dy());
+    let d = [y - b.lly, b.urx - x, b.ury - y, x - b.llx];
+    let mut side = 0;
+    for s in 1..4 {
+        if d[s].abs() < d[side].abs() {
+            side = s;
+        }
+    }
+    let x = x.max(b.llx).min(b.urx);
+    let y = y.max(b.lly).min(b.ury);
+    let t = match side {
+        0 => x - b.llx,
+        1 => w + (y - b.lly),
+        2 => w + h + (b.urx - x),
+        _ => 2.0 * w + h + (b.ury - y),
+    };
+    (side, t)
+}
+
+// Point on the boundary at distance t from the lower left corner.
+fn perimeter_point(b: &BBox, t: f32) -> (f32, f32) {
+    let (w, h) = (b.dx(), b.dy());
+    let t = t.rem_euclid(2.0 * (w + h));
+    if t < w {
+        (b.llx + t, b.lly)
+    } else if t < w + h {
+        (b.urx, b.lly + t - w)
+    } else if t < 2.0 * w + h {
+        (b.urx - (t - w - h), b.ury)
+    } else {
+        (b.llx, b.ury - (t - 2.0 * w - h))
+    }
+}
+
+impl BookshelfCircuit {
+    /// Moves all of the terminals onto the boundary of the outline, with
+    /// the pad centers on the edge.  Returns the number of terminals
+    /// moved.
+    pub fn place_pads(&mut self, outline: &BBox, params: &PadParams) -> usize {
+        let pads: Vec<usize> = (0..self.cells.len()).filter(|i| self.cells[*i].terminal).collect();
+        if pads.is_empty() {
+            return 0;
+        }
+
+        let mut old = BBox::new();
+        for p in &pads {
+            old.addpoint(self.cellpos[*p].x, self.cellpos[*p].y);
+        }
+        if old.dx() <= 0.0 || old.dy() <= 0.0 {
+            old = *outline;
+        }
+        let positions: Vec<(usize, f32)> = pads
+            .iter()
+            .map(|p| perimeter_position(&old, self.cellpos[*p].x, self.cellpos[*p].y))
+            .collect();
+
+        let (w, h) = (outline.dx(), outline.dy());
+        let side_start = [0.0, w, w + h, 2.0 * w + h];
+        let side_length = [w, h, w, h];
+        let mut targets = vec![0.0; pads.len()];
+
+        match params.mode {
+            PadMode::Even | PadMode::KeepOrder => {
+                let mut order: Vec<usize> = (0..pads.len()).collect();
+                if params.mode == PadMode::KeepOrder {
+                    order.sort_by(|a, b| positions[*a].1.partial_cmp(&positions[*b].1).unwrap());
+                }
+                let step = 2.0 * (w + h) / pads.len() as f32;
+                for k in 0..order.len() {
+                    targets[order[k]] = (k as f32 + 0.5) * step;
+                }
+            }
+            PadMode::KeepSide => {
+                for side in 0..4 {
+                    let mut on_side: Vec<usize> = (0..pads.len()).filter(|i| positions[*i].0 == side).collect();
+                    on_side.sort_by(|a, b| positions[*a].1.partial_cmp(&positions[*b].1).unwrap());
+                    let step = side_length[side] / on_side.len().max(1) as f32;
+                    for k in 0..on_side.len() {
+                        targets[on_side[k]] = side_start[side] + (k as f32 + 0.5) * step;
+                    }
+                }
+            }
+        }
+
+        for k in 0..pads.len() {
+            let p = pads[k];
+            let (x, y) = perimeter_point(outline, targets[k]);
+            self.cellpos[p].x = x - self.cells[p].w * 0.5;
+            self.cellpos[p].y = y - self.cells[p].h * 0.5;
+        }
+
+        if params.verbose {
+            println!("Placed {} pads around {}", pads.len(), outline);
+        }
+        self.notes.push(format!(
+            "Pads placed ({:?}) around {}, HPWL {}",
+            params.mode,
+            outline,
+            self.wl()
+        ));
+        pads.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    // Pads on each side of a 200 by 100 box (two on the bottom), and
+    // the side each one is on
+    fn pads() -> (BookshelfCircuit, Vec<(&'static str, usize)>) {
+        let sides = vec![("b0", 0), ("b1", 0), ("r0", 1), ("t0", 2), ("l0", 3)];
+        let bc = TestCircuit::new()
+            .cell("c0", 10.0, 10.0, 50.0, 50.0)
+            .terminal("b0", 2.0, 2.0, 10.0, 0.0)
+            .terminal("b1", 2.0, 2.0, 30.0, 0.0)
+            .terminal("r0", 2.0, 2.0, 200.0, 50.0)
+            .terminal("t0", 2.0, 2.0, 150.0, 100.0)
+            .terminal("l0", 2.0, 2.0, 0.0, 70.0)
+            .build();
+        (bc, sides)
+    }
+
+    fn outline() -> BBox {
+        let mut b = BBox::new();
+        b.addpoint(0.0, 0.0);
+        b.addpoint(100.0, 50.0);
+        b
+    }
+
+    // Side of the outline that a pad center is on, if any
+    fn side_of(bc: &BookshelfCircuit, name: &str) -> Option<usize> {
+        let b = outline();
+        let c = bc.cell_map[name];
+        let x = bc.cellpos[c].x + bc.cells[c].w * 0.5;
+        let y = bc.cellpos[c].y + bc.cells[c].h * 0.5;
+        let on = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        if x < b.llx - 1e-3 || x > b.urx + 1e-3 || y < b.lly - 1e-3 || y > b.ury + 1e-3 {
+            return None;
+        }
+        [on(y, b.lly), on(x, b.urx), on(y, b.ury), on(x, b.llx)].iter().position(|s| *s)
+    }
+
+    #[test]
+    fn pads_on_outline() {
+        for mode in [PadMode::Even, PadMode::KeepOrder, PadMode::KeepSide] {
+            let (mut bc, sides) = pads();
+            let mut params = PadParams::new();
+            params.mode = mode;
+            assert_eq!(bc.place_pads(&outline(), &params), 5);
+            for (name, _side) in &sides {
+                assert!(side_of(&bc, name).is_some(), "{name} is off the outline in {mode:?}");
+            }
+            // The movable cell stays put
+            assert_eq!(bc.cellpos[bc.cell_map["c0"]].x, 50.0);
+        }
+    }
+
+    #[test]
+    fn keep_side() {
+        let (mut bc, sides) = pads();
+        let mut params = PadParams::new();
+        params.mode = PadMode::KeepSide;
+        bc.place_pads(&outline(), &params);
+        for (name, side) in &sides {
+            assert_eq!(side_of(&bc, name), Some(*side), "{name}");
+        }
+        // In order along the bottom
+        assert!(bc.cellpos[bc.cell_map["b0"]].x < bc.cellpos[bc.cell_map["b1"]].x);
+    }
+}
@@ -0,0 +1,527 @@
+//! k-way partitioning.
+//!
+//! The partitions are the regions in the HyperParams (from `set_grid`
+//! or `set_regions`).  The cells are split by recursive bisection: the
+//! set of regions is cut across its longer side (between columns or
+//! rows of regions, with the bias set by the area targets), the cells are
+//! bisected with the FM partitioner (which honors the bias; turn off
+//! `heavy_slack` to hold each split to the tolerance), and each
+//! half is split again until every group is a single region.  The graph
+//! at each level has the k-way terminal propagation from `build_graph`,
+//! with each region's fixed vertex on the side of the cut it falls on.
+//! Between levels, the cells are moved to the center of the regions
+//! they were assigned to, so that deeper levels propagate from the
+//! assignment rather than the starting placement; the positions are
+//! restored at the end.  The result goes into `params.partition`, and
+//! can be shown with `color_partition` and `ps_color_cells`.
+//!
+//! The partition in the params (one entry per cell, -1 for cells that
+//! were not partitioned) can be checked with `partition_report`, and
+//! drawn with `ps_partition`, which highlights the cut nets.
+use crate::bookshelf::{BookshelfCircuit, HyperParams};
+use crate::fm::FMPartitioner;
+use pstools::bbox::BBox;
+use pstools::PSTool;
+use std::os::raw::c_int;
+
+impl BookshelfCircuit {
+    /// Partitions the cells into the regions of the params.  Returns the
+    /// sum of the cuts from the bisections.
+    pub fn kway_partition(&mut self, cells: &Vec<usize>, params: &mut HyperParams, fm: &FMPartitioner) -> usize {
+        let regions = params.regions.clone();
+        let targets = params.area_targets();
+        let saved_bias = params.bias;
+        let saved_pos = self.cellpos.clone();
+        params.propagated = 0;
+        for c in cells {
+            params.partition[*c] = -1;
+        }
+        let group: Vec<usize> = (0..regions.len()).collect();
+        let cut = self.kway_split(cells, &group, &regions, &targets, params, fm);
+        params.bias = saved_bias;
+        self.cellpos = saved_pos;
+        cut
+    }
+
+    // Moves the cells to the center of a group of regions.
+    fn move_to_group(&mut self, cells: &Vec<usize>, group: &Vec<usize>, regions: &Vec<BBox>) {
+        let mut bounds = BBox::new();
+        for r in group {
+            bounds.expand(&regions[*r]);
+        }
+        let cx = (bounds.llx + bounds.urx) * 0.5;
+        let cy = (bounds.lly + bounds.ury) * 0.5;
+        for c in cells {
+            self.cellpos[*c].x = cx;
+            self.cellpos[*c].y = cy;
+        }
+    }
+
+    fn kway_split(
+        &mut self,
+        cells: &Vec<usize>,
+        group: &Vec<usize>,
+        regions: &Vec<BBox>,
+        targets: &Vec<f32>,
+        params: &mut HyperParams,
+        fm: &FMPartitioner,
+    ) -> usize {
+        if group.len() == 1 || cells.is_empty() {
+            for c in cells {
+                params.partition[*c] = group[0] as c_int;
+            }
+            self.move_to_group(cells, group, regions);
+            return 0;
+        }
+
+        // Cut the regions across the longer side of their bounding box,
+        // between two columns (or rows) of regions
+        let mut bounds = BBox::new();
+        for r in group {
+            bounds.expand(&regions[*r]);
+        }
+        let mut horizontal = bounds.dy() > bounds.dx();
+        let centers = |r: &BBox, horizontal: bool| if horizontal { r.lly + r.ury } else { r.llx + r.urx };
+        let mut lines: Vec<f32> = group.iter().map(|r| centers(&regions[*r], horizontal)).collect();
+        lines.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        lines.dedup();
+        if lines.len() < 2 {
+            horizontal = !horizontal;
+            lines = group.iter().map(|r| centers(&regions[*r], horizontal)).collect();
+            lines.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            lines.dedup();
+        }
+        let cut_center = lines[lines.len() / 2];
+        let mut lo_group = Vec::new();
+        let mut hi_group = Vec::new();
+        for r in group {
+            if centers(&regions[*r], horizontal) < cut_center {
+                lo_group.push(*r);
+            } else {
+                hi_group.push(*r);
+            }
+        }
+        if lo_group.is_empty() {
+            // Regions all at the same spot; just split the list
+            hi_group = group.clone();
+            lo_group = hi_group.drain(0..group.len() / 2).collect();
+        }
+        let mut split = f32::MIN;
+        for r in &lo_group {
+            let b = &regions[*r];
+            split = split.max(if horizontal { b.ury } else { b.urx });
+        }
+
+        let lo_target: f32 = lo_group.iter().map(|r| targets[*r]).sum();
+        let hi_target: f32 = hi_group.iter().map(|r| targets[*r]).sum();
+        params.horizontal = horizontal;
+        params.split_point = split;
+        params.bias = lo_target / (lo_target + hi_target);
+
+        let mut hg = self.build_graph(cells, params);
+        // build_graph fixes the region vertices (after the cells) by
+        // their centers; the regions in this group go with their half of
+        // the group instead, which can differ if the regions overlap
+        if params.term_prop && regions.len() > 1 {
+            for r in &lo_group {
+                hg.part[cells.len() + *r] = 0;
+            }
+            for r in &hi_group {
+                hg.part[cells.len() + *r] = 1;
+            }
+        }
+        let (left, right, cut) = fm.hg_partition(&hg, params.bias);
+        let mut lo_cells = Vec::new();
+        let mut hi_cells = Vec::new();
+        for &v in &left {
+            if v < cells.len() {
+                lo_cells.push(params.cellmark.list[v]);
+            }
+        }
+        for &v in &right {
+            if v < cells.len() {
+                hi_cells.push(params.cellmark.list[v]);
+            }
+        }
+        if lo_cells.len() + hi_cells.len() != cells.len() {
+            // Something went wrong -- split by area, in the given order
+            let total = self.cellweights(cells);
+            lo_cells.clear();
+            hi_cells.clear();
+            let mut area = 0.0;
+            for c in cells {
+                if area < total * params.bias {
+                    lo_cells.push(*c);
+                } else {
+                    hi_cells.push(*c);
+                }
+                area = area + self.cells[*c].area();
+            }
+        }
+
+        self.move_to_group(&lo_cells, &lo_group, regions);
+        self.move_to_group(&hi_cells, &hi_group, regions);
+        cut
+            + self.kway_split(&lo_cells, &lo_group, regions, targets, params, fm)
+            + self.kway_split(&hi_cells, &hi_group, regions, targets, params, fm)
+    }
+
+    /// Colors each cell by its partition, for ps_color_cells.  Cells that
+    /// were not partitioned get color k.
+    pub fn color_partition(&mut self, params: &HyperParams) {
+        self.color_partition_vec(&params.partition, params.k as usize);
+    }
+
+    pub fn color_partition_vec(&mut self, partition: &Vec<c_int>, k: usize) {
+        self.cell_color = Some(
+            partition
+                .iter()
+                .map(|p| if *p < 0 { k } else { *p as usize })
+                .collect(),
+        );
+    }
+
+    /// Cut and balance of the partition in the params.  Nets are only
+    /// counted if they have at least one partitioned cell; the propagated
+    /// nets are the ones build_graph gave propagated terminals.  There are
+    /// params.k parts, even if the last ones are empty.
+    pub fn partition_report(&self, params: &HyperParams) -> PartitionReport {
+        let partition = &params.partition;
+        let used = partition.iter().map(|p| *p + 1).max().unwrap_or(0).max(0) as usize;
+        let k = (params.k as usize).max(used);
+        let mut cells = vec![0; k];
+        let mut area = vec![0.0; k];
+        for i in 0..partition.len() {
+            if partition[i] >= 0 {
+                cells[partition[i] as usize] += 1;
+                area[partition[i] as usize] += self.cells[i].area();
+            }
+        }
+        let total: f32 = area.iter().sum();
+        let mut area_fraction = vec![0.0; k];
+        let mut imbalance = 0.0;
+        if total > 0.0 {
+            area_fraction = area.iter().map(|a| a / total).collect();
+            imbalance = area_fraction.iter().cloned().fold(0.0, f32::max) * k as f32;
+        }
+
+        let mut cut_nets = Vec::new();
+        let mut soed = 0;
+        let mut weighted_cut = 0.0;
+        let mut parts = Vec::new();
+        for n in 0..self.nets.len() {
+            parts.clear();
+            for pr in &self.nets[n].pins {
+                let p = partition[pr.parent_cell];
+                if p >= 0 && !parts.contains(&p) {
+                    parts.push(p);
+                }
+            }
+            if parts.len() > 1 {
+                cut_nets.push(n);
+                soed += parts.len();
+                weighted_cut += self.nets[n].weight;
+            }
+        }
+
+        PartitionReport {
+            k: k,
+            cut: cut_nets.len(),
+            soed: soed,
+            weighted_cut: weighted_cut,
+            propagated: params.propagated,
+            cells: cells,
+            area: area,
+            area_fraction: area_fraction,
+            imbalance: imbalance,
+            cut_nets: cut_nets,
+        }
+    }
+
+    /// Draws the cut nets as lines from each pin to the net center.
+    pub fn ps_cut_nets(&self, pst: &mut PSTool, cut_nets: &Vec<usize>) {
+        pst.set_color(1.0, 0.0, 0.0, 1.0);
+        for n in cut_nets {
+            let net = &self.nets[*n];
+            if net.pins.is_empty() {
+                continue;
+            }
+            let mut cx = 0.0;
+            let mut cy = 0.0;
+            for pr in &net.pins {
+                let (px, py) = self.pinloc(pr);
+                cx = cx + px;
+                cy = cy + py;
+            }
+            cx = cx / net.pins.len() as f32;
+            cy = cy / net.pins.len() as f32;
+            for pr in &net.pins {
+                let (px, py) = self.pinloc(pr);
+                pst.add_line(px, py, cx, cy);
+            }
+        }
+    }
+
+    /// Writes a PostScript file with the cells colored by partition, and
+    /// the cut nets highlighted.  This sets cell_color.
+    pub fn ps_partition(&mut self, filename: String, params: &HyperParams) {
+        let rep = self.partition_report(params);
+        self.color_partition_vec(&params.partition, rep.k);
+        let mut pst = self.postscript_prep();
+        let mut display = self.bookshelf_display();
+        display.cells = false;
+        display.color_cells = true;
+        self.postscript_display(&mut pst, &display);
+        self.ps_cut_nets(&mut pst, &rep.cut_nets);
+        pst.set_border(40.0);
+        pst.generate(filename).unwrap();
+    }
+}
+
+pub struct PartitionReport {
+    pub k: usize,
+    /// Number of nets spanning two or more partitions
+    pub cut: usize,
+    /// Sum of external degrees: the number of partitions each cut net
+    /// spans, added up
+    pub soed: usize,
+    /// Cut, with the net weights
+    pub weighted_cut: f32,
+    /// Nets with propagated terminals in the partitioning graphs
+    pub propagated: usize,
+    pub cells: Vec<usize>,
+    pub area: Vec<f32>,
+    pub area_fraction: Vec<f32>,
+    /// Largest partition area over the average
+    pub imbalance: f32,
+    pub cut_nets: Vec<usize>,
+}
+
+impl PartitionReport {
+    pub fn report(&self) {
+        println!(
+            "Partition: {} parts, cut {}, SOED {}, weighted cut {}, {} propagated nets",
+            self.k, self.cut, self.soed, self.weighted_cut, self.propagated
+        );
+        for p in 0..self.k {
+            println!(
+                "  Part {}: {} cells, area {} ({:.1}%)",
+                p,
+                self.cells[p],
+                self.area[p],
+                self.area_fraction[p] * 100.0
+            );
+        }
+        println!("  Imbalance {:.3}", self.imbalance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fm::FMParams;
+    use crate::testutil::{scratch_dir, TestCircuit};
+    use hypergraph::hypergraph::HyperGraph;
+
+    // Four groups of four 2 by 2 cells, all piled up in the middle of a
+    // 40 by 40 core.  The cells of a group are chained together, and the
+    // first one is tied to a pad off one corner of the core (group 0 to
+    // the lower left, 1 the lower right, 2 the upper left, 3 the upper
+    // right).
+    fn circuit() -> BookshelfCircuit {
+        let pads = [(-5.0, -5.0), (45.0, -5.0), (-5.0, 45.0), (45.0, 45.0)];
+        let mut tc = TestCircuit::new().rows(20, 40, 2);
+        for i in 0..16 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 2.0, 19.0, 19.0);
+        }
+        for g in 0..4 {
+            tc = tc.terminal(&format!("p{g}"), 1.0, 1.0, pads[g].0, pads[g].1);
+            tc = tc.net(&format!("pad{g}"), &[&format!("p{g}"), &format!("c{}", 4 * g)]);
+            for i in 0..3 {
+                let (a, b) = (4 * g + i, 4 * g + i + 1);
+                tc = tc.net(&format!("n{a}"), &[&format!("c{a}"), &format!("c{b}")]);
+            }
+        }
+        tc.build()
+    }
+
+    // With the default 2% tolerance, no single cell could move
+    fn fm_params() -> FMParams {
+        let mut fp = FMParams::new();
+        fp.heavy_slack = false;
+        fp.tolerance = 0.15;
+        fp
+    }
+
+    fn quadrisect(bc: &mut BookshelfCircuit) -> HyperParams {
+        let mut params = HyperParams::new(bc);
+        params.set_grid(&bc.core(), 2, 2);
+        let cells: Vec<usize> = (0..16).collect();
+        bc.kway_partition(&cells, &mut params, &FMPartitioner::new(fm_params()));
+        params
+    }
+
+    #[test]
+    fn quadrisection() {
+        let mut bc = circuit();
+        let params = quadrisect(&mut bc);
+        assert_eq!(params.k, 4);
+        let targets = params.area_targets();
+        assert_eq!(targets, vec![0.25; 4]);
+
+        let mut area = vec![0.0; 4];
+        for c in 0..16 {
+            let p = params.partition[c];
+            assert!((0..4).contains(&p), "c{c} is in {p}");
+            area[p as usize] += bc.cells[c].area();
+        }
+        // Each of the two levels is held to the FM tolerance
+        let total = bc.cell_area();
+        for p in 0..4 {
+            assert!((area[p] / total - targets[p]).abs() <= 2.0 * fm_params().tolerance, "{:?}", area);
+        }
+        // Each group goes to the region next to its pad
+        for c in 0..16 {
+            assert_eq!(params.partition[c], (c / 4) as c_int, "c{c}");
+        }
+        // The positions are put back
+        assert!(bc.cellpos.iter().take(16).all(|p| p.x == 19.0 && p.y == 19.0));
+        // Terminals are not partitioned
+        assert!(params.partition.iter().skip(16).all(|p| *p == -1));
+    }
+
+    #[test]
+    fn region_vertices_fixed_by_side() {
+        let bc = circuit();
+        let mut params = HyperParams::new(&bc);
+        params.set_grid(&bc.core(), 2, 2);
+        assert_eq!(params.region_of(5.0, 35.0), 2);
+        assert_eq!(params.region_of(-5.0, -5.0), 0);
+        assert_eq!(params.region_of(45.0, 45.0), 3);
+
+        // Split between the columns, and then between the rows
+        let cells: Vec<usize> = (0..16).collect();
+        params.split_point = 20.0;
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(hg.part[16..].to_vec(), vec![0, 1, 0, 1]);
+        params.horizontal = true;
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(hg.part[16..].to_vec(), vec![0, 0, 1, 1]);
+        assert!(hg.part[..16].iter().all(|p| *p == -1));
+    }
+
+    // Four 2 by 2 cells and a pad, with nets of two and three cells.
+    // Net n1 runs over all three parts of the hand-built partition, and
+    // n2 over two; n0 is inside part 0, and the pad does not count.
+    fn report_circuit() -> (BookshelfCircuit, HyperParams) {
+        let mut bc = TestCircuit::new()
+            .rows(10, 20, 2)
+            .cell("a", 2.0, 2.0, 0.0, 0.0)
+            .cell("b", 2.0, 2.0, 4.0, 0.0)
+            .cell("c", 2.0, 2.0, 8.0, 0.0)
+            .cell("d", 2.0, 2.0, 12.0, 0.0)
+            .terminal("p", 1.0, 1.0, -5.0, 0.0)
+            .net("n0", &["a", "b"])
+            .net("n1", &["a", "c", "d"])
+            .net("n2", &["b", "c"])
+            .net("n3", &["p", "d"])
+            .build();
+        let dir = scratch_dir();
+        let wts = dir.join("r.wts");
+        std::fs::write(&wts, "UCLA wts 1.0\n\nn0 10\nn1 3\nn2 0.5\nn3 10\n").unwrap();
+        assert_eq!(bc.read_wts(&wts), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut params = HyperParams::new(&bc);
+        for (name, p) in [("a", 0), ("b", 0), ("c", 1), ("d", 2)] {
+            params.partition[bc.cell_map[name]] = p;
+        }
+        (bc, params)
+    }
+
+    #[test]
+    fn partition_report_cut() {
+        let (bc, params) = report_circuit();
+        let rep = bc.partition_report(&params);
+        assert_eq!(rep.k, 3);
+        assert_eq!(rep.cut_nets, vec![bc.net_map["n1"], bc.net_map["n2"]]);
+        assert_eq!(rep.cut, 2);
+        assert_eq!(rep.soed, 3 + 2);
+        assert_eq!(rep.weighted_cut, 3.5);
+        assert_eq!(rep.cells, vec![2, 1, 1]);
+        assert_eq!(rep.area, vec![8.0, 4.0, 4.0]);
+        assert_eq!(rep.area_fraction, vec![0.5, 0.25, 0.25]);
+        assert_eq!(rep.imbalance, 1.5);
+        assert_eq!(rep.propagated, 0);
+
+        // An empty last part is still a part
+        let mut params = params;
+        params.k = 4;
+        let rep = bc.partition_report(&params);
+        assert_eq!(rep.k, 4);
+        assert_eq!(rep.cells, vec![2, 1, 1, 0]);
+        assert_eq!(rep.area_fraction, vec![0.5, 0.25, 0.25, 0.0]);
+        assert_eq!(rep.imbalance, 2.0);
+        assert_eq!(rep.cut, 2);
+    }
+
+    #[test]
+    fn color_unpartitioned() {
+        let (mut bc, mut params) = report_circuit();
+        let (c, p) = (bc.cell_map["c"], bc.cell_map["p"]);
+        params.partition[c] = -1;
+        params.k = 3;
+        bc.color_partition(&params);
+        assert_eq!(bc.cell_color.as_ref().unwrap(), &vec![0, 0, 3, 2, 3]);
+        assert_eq!(bc.cell_color.as_ref().unwrap()[p], 3);
+
+        // Only parts 0 and 2 are left to cut n1 and n2
+        let rep = bc.partition_report(&params);
+        assert_eq!(rep.cut_nets, vec![bc.net_map["n1"]]);
+        assert_eq!(rep.soed, 2);
+        let dir = scratch_dir();
+        bc.ps_partition(dir.join("part.ps").to_str().unwrap().to_string(), &params);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(bc.cell_color.as_ref().unwrap()[c], rep.k);
+    }
+
+    // Nets of the last graph with an edge to a fixed vertex (the source
+    // and sink, or the regions, all numbered after the cells)
+    fn nets_with_terminals(hg: &HyperGraph, cells: usize) -> usize {
+        (0..hg.hewt.len())
+            .filter(|e| hg.eptr[hg.eind[*e] as usize..hg.eind[*e + 1] as usize].iter().any(|v| *v as usize >= cells))
+            .count()
+    }
+
+    #[test]
+    fn propagated_nets() {
+        let bc = circuit();
+        let mut params = HyperParams::new(&bc);
+        params.split_point = 20.0;
+
+        // Only the pad nets leave the cells, so the first graph has four
+        let cells: Vec<usize> = (0..16).collect();
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(nets_with_terminals(&hg, 16), 4);
+        assert_eq!(params.propagated, 4);
+
+        // Leaving out c0, the pad net of group 1 and the net from c0 to
+        // c1 go outside
+        let cells: Vec<usize> = (1..8).collect();
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(nets_with_terminals(&hg, 7), 2);
+        assert_eq!(params.propagated, 6);
+
+        // With regions, each outside cell ties its net to a region vertex
+        params.set_grid(&bc.core(), 2, 2);
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(nets_with_terminals(&hg, 7), 2);
+        assert_eq!(params.propagated, 8);
+        assert_eq!(bc.partition_report(&params).propagated, 8);
+
+        // Without terminal propagation, none are
+        params.term_prop = false;
+        let hg = bc.build_graph(&cells, &mut params);
+        assert_eq!(nets_with_terminals(&hg, 7), 0);
+        assert_eq!(params.propagated, 8);
+    }
+}
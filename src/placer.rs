@@ -0,0 +1,419 @@
+//! Min-cut placement by recursive bisection.
+//!
+//! The core area is split recursively, alternating between vertical
+//! and horizontal cuts.  At each level, the cells within a region are
+//! turned into a hypergraph with `build_graph`, with terminal propagation
+//! pulling in the cells (and pads) outside of the region, and the
+//! hypergraph is split with the metapartition built-in partitioner.
+//! The cut line is then placed so that the area on each side matches
+//! the cell area assigned to it, and the cells are moved to the center
+//! of their new region.  Once a region gets small enough, the cells are
+//! spread out inside of it, and the positions are left in `cellpos`.
+//!
+//! The result is a global placement -- cells will overlap a bit, and
+//! will not be aligned to rows or sites.  Run the legalizer after this.
+use crate::bookshelf::{BookshelfCircuit, EdgeWeight, HyperParams};
+use crate::fm::{FMParams, FMPartitioner};
+use metapartition::metapartitioner::Metapartitioner;
+use pstools::bbox::BBox;
+use pstools::point;
+use std::collections::VecDeque;
+
+pub struct PlacerParams {
+    /// Regions with this many cells (or fewer) are not split further
+    pub min_cells: usize,
+    /// Use terminal propagation for the cells outside of a region
+    pub term_prop: bool,
+    /// If true, the first cut is a horizontal line (splitting on Y)
+    pub horizontal_first: bool,
+    /// Edge weighting mode passed through to the HyperParams
+    pub edgeweight: EdgeWeight,
+    /// Use the built-in FM partitioner rather than metapartition
+    pub builtin_fm: bool,
+    /// Keep macro blocks where they are, rather than placing them
+    pub fixed_macros: bool,
+    pub verbose: bool,
+}
+
+impl PlacerParams {
+    pub fn new() -> PlacerParams {
+        PlacerParams {
+            min_cells: 6,
+            term_prop: true,
+            horizontal_first: false,
+            edgeweight: EdgeWeight::Uniform,
+            builtin_fm: false,
+            fixed_macros: false,
+            verbose: false,
+        }
+    }
+}
+
+/// A placement region -- the bounds, the cells assigned to it, and the
+/// direction of the next cut.
+pub struct Region {
+    pub bounds: BBox,
+    pub cells: Vec<usize>,
+    pub horizontal: bool,
+    pub level: usize,
+}
+
+impl BookshelfCircuit {
+    /// Runs recursive bisection placement on all of the movable cells,
+    /// inside of the core area.  Returns the sum of the cuts over all of
+    /// the partitioning calls.
+    pub fn mincut_place(&mut self, pp: &PlacerParams) -> usize {
+        let core = self.core();
+        let mut cells = Vec::new();
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal || (pp.fixed_macros && self.cells[i].is_macro) {
+                continue;
+            }
+            cells.push(i);
+        }
+        let center = point::Point {
+            x: (core.llx + core.urx) * 0.5,
+            y: (core.lly + core.ury) * 0.5,
+        };
+        self.set_cell_centers(&cells, &center);
+
+        let mut params = HyperParams::new(self);
+        params.term_prop = pp.term_prop;
+        params.edgeweight = pp.edgeweight.clone();
+        let mp = Metapartitioner::new();
+        let fm = if pp.builtin_fm {
+            Some(FMPartitioner::new(FMParams::new()))
+        } else {
+            None
+        };
+
+        let mut total_cut = 0;
+        let mut num_regions = 0;
+        let mut queue = VecDeque::new();
+        queue.push_back(Region {
+            bounds: core,
+            cells: cells,
+            horizontal: pp.horizontal_first,
+            level: 0,
+        });
+
+        // Breadth-first, so that terminal propagation at each level sees
+        // the cells of the sibling regions at their new centers.
+        while let Some(region) = queue.pop_front() {
+            if region.cells.len() <= pp.min_cells.max(1) {
+                self.place_leaf(&region);
+                num_regions += 1;
+                continue;
+            }
+            let (lo, hi, cut) = self.bisect(&region, &mut params, &mp, &fm);
+            total_cut += cut;
+            if pp.verbose {
+                println!(
+                    "Level {} region {} with {} cells: {} / {} cut {}",
+                    region.level,
+                    region.bounds,
+                    region.cells.len(),
+                    lo.cells.len(),
+                    hi.cells.len(),
+                    cut
+                );
+            }
+            queue.push_back(lo);
+            queue.push_back(hi);
+        }
+
+        self.notes.push(format!(
+            "Min-cut placement: {} regions, total cut {}, HPWL {}",
+            num_regions,
+            total_cut,
+            self.wl()
+        ));
+        total_cut
+    }
+
+    /// Splits a region into two, returning the low side (left or bottom)
+    /// first.  The cut line is positioned so that each side has space
+    /// in proportion to the area of the cells assigned to it.
+    fn bisect(
+        &mut self,
+        region: &Region,
+        params: &mut HyperParams,
+        mp: &Metapartitioner,
+        fm: &Option<FMPartitioner>,
+    ) -> (Region, Region, usize) {
+        let b = region.bounds;
+        params.horizontal = region.horizontal;
+        if region.horizontal {
+            params.split_point = (b.lly + b.ury) * 0.5;
+        } else {
+            params.split_point = (b.llx + b.urx) * 0.5;
+        }
+
+        let hg = self.build_graph(&region.cells, params);
+        let (left, right, cut) = match fm {
+            Some(fm) => fm.hg_partition(&hg, params.bias),
+            None => mp.hg_partition(&hg),
+        };
+
+        // Vertices past the end of the cell list are the fixed source and
+        // sink used for terminal propagation.
+        let mut lo_cells = Vec::new();
+        let mut hi_cells = Vec::new();
+        for &v in &left {
+            if v < region.cells.len() {
+                lo_cells.push(params.cellmark.list[v]);
+            }
+        }
+        for &v in &right {
+            if v < region.cells.len() {
+                hi_cells.push(params.cellmark.list[v]);
+            }
+        }
+        if lo_cells.is_empty() || hi_cells.is_empty()
+            || lo_cells.len() + hi_cells.len() != region.cells.len()
+        {
+            // Partitioner did not give us something useful -- fall back
+            // to a split by the current cell locations.
+            let (l, h) = self.split_by_position(&region.cells, region.horizontal, params.bias);
+            lo_cells = l;
+            hi_cells = h;
+        }
+
+        let lo_area = self.cellweights(&lo_cells);
+        let hi_area = self.cellweights(&hi_cells);
+        let mut ratio = 0.5;
+        if lo_area + hi_area > 0.0 {
+            ratio = lo_area / (lo_area + hi_area);
+        }
+
+        let mut lo_bounds = b;
+        let mut hi_bounds = b;
+        if region.horizontal {
+            let cut_line = b.lly + b.dy() * ratio;
+            lo_bounds.ury = cut_line;
+            hi_bounds.lly = cut_line;
+        } else {
+            let cut_line = b.llx + b.dx() * ratio;
+            lo_bounds.urx = cut_line;
+            hi_bounds.llx = cut_line;
+        }
+
+        let lo_center = point::Point {
+            x: (lo_bounds.llx + lo_bounds.urx) * 0.5,
+            y: (lo_bounds.lly + lo_bounds.ury) * 0.5,
+        };
+        let hi_center = point::Point {
+            x: (hi_bounds.llx + hi_bounds.urx) * 0.5,
+            y: (hi_bounds.lly + hi_bounds.ury) * 0.5,
+        };
+        self.set_cell_centers(&lo_cells, &lo_center);
+        self.set_cell_centers(&hi_cells, &hi_center);
+
+        (
+            Region {
+                bounds: lo_bounds,
+                cells: lo_cells,
+                horizontal: !region.horizontal,
+                level: region.level + 1,
+            },
+            Region {
+                bounds: hi_bounds,
+                cells: hi_cells,
+                horizontal: !region.horizontal,
+                level: region.level + 1,
+            },
+            cut,
+        )
+    }
+
+    /// Splits a list of cells by area, with the bias fraction on the low
+    /// side, based on the cell centers along the cut direction.
+    fn split_by_position(&self, cells: &Vec<usize>, horizontal: bool, bias: f32) -> (Vec<usize>, Vec<usize>) {
+        let mut sorted = cells.clone();
+        sorted.sort_by(|a, b| {
+            let va;
+            let vb;
+            if horizontal {
+                va = self.cellpos[*a].y + self.cells[*a].h * 0.5;
+                vb = self.cellpos[*b].y + self.cells[*b].h * 0.5;
+            } else {
+                va = self.cellpos[*a].x + self.cells[*a].w * 0.5;
+                vb = self.cellpos[*b].x + self.cells[*b].w * 0.5;
+            }
+            va.partial_cmp(&vb).unwrap()
+        });
+        // Pick the split closest to the biased area, keeping at least one
+        // cell on each side (a single macro can be more than that).
+        let target = self.cellweights(&sorted) * bias.max(0.0).min(1.0);
+        let mut area = 0.0;
+        let mut split = 1;
+        let mut best = f32::MAX;
+        for k in 1..sorted.len() {
+            area = area + self.cells[sorted[k - 1]].area();
+            if (area - target).abs() < best {
+                best = (area - target).abs();
+                split = k;
+            }
+        }
+        let hi = sorted.split_off(split);
+        (sorted, hi)
+    }
+
+    /// Final placement within a small region.  Cells are packed left to
+    /// right in lines, wrapping when a line is full, and the whole block
+    /// is centered vertically in the region.  If the lines are taller
+    /// than the region, they are squeezed together (overlapping a bit)
+    /// so that they stay inside of it.
+    fn place_leaf(&mut self, region: &Region) {
+        let b = region.bounds;
+        let mut lines: Vec<Vec<usize>> = vec![Vec::new()];
+        let mut heights = vec![0.0 as f32];
+        let mut x = 0.0;
+        for c in &region.cells {
+            let w = self.cells[*c].w;
+            if x + w > b.dx() && !lines.last().unwrap().is_empty() {
+                lines.push(Vec::new());
+                heights.push(0.0);
+                x = 0.0;
+            }
+            lines.last_mut().unwrap().push(*c);
+            let h = heights.last_mut().unwrap();
+            *h = h.max(self.cells[*c].h);
+            x = x + w;
+        }
+        let total_h: f32 = heights.iter().sum();
+        let mut y = b.lly + (b.dy() - total_h) * 0.5;
+        let mut pitch = 1.0;
+        if total_h > b.dy() {
+            y = b.lly;
+            pitch = b.dy() / total_h;
+        }
+        for l in 0..lines.len() {
+            let width = self.cellweights_w(&lines[l]);
+            let mut x = b.llx + (b.dx() - width).max(0.0) * 0.5;
+            let line_y = y.min(b.ury - heights[l]).max(b.lly);
+            for c in &lines[l] {
+                self.cellpos[*c].x = x;
+                self.cellpos[*c].y = line_y;
+                x = x + self.cells[*c].w;
+            }
+            y = y + heights[l] * pitch;
+        }
+    }
+
+    fn cellweights_w(&self, cells: &Vec<usize>) -> f32 {
+        let mut total = 0.0;
+        for c in cells {
+            total = total + self.cells[*c].w;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    // Two chains of six cells, joined by one net, on a 4 x 40 core
+    fn circuit() -> BookshelfCircuit {
+        let mut tc = TestCircuit::new().rows(4, 40, 1);
+        for i in 0..12 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 1.0, 3.0 * i as f32, 0.0);
+        }
+        for g in 0..2 {
+            for i in 0..5 {
+                let (a, b) = (6 * g + i, 6 * g + i + 1);
+                tc = tc.net(&format!("n{a}"), &[&format!("c{a}"), &format!("c{b}")]);
+            }
+        }
+        tc.net("bridge", &["c5", "c6"]).build()
+    }
+
+    #[test]
+    fn cells_in_core() {
+        for builtin_fm in [false, true] {
+            let mut bc = circuit();
+            let mut pp = PlacerParams::new();
+            pp.builtin_fm = builtin_fm;
+            let cut = bc.mincut_place(&pp);
+            let core = bc.core();
+            for i in 0..bc.cells.len() {
+                let p = &bc.cellpos[i];
+                assert!(p.x >= core.llx && p.x + bc.cells[i].w <= core.urx);
+                assert!(p.y >= core.lly && p.y + bc.cells[i].h <= core.ury);
+            }
+            // One cut splits the chains, and the halves are leaves
+            if builtin_fm {
+                assert_eq!(cut, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn metapartition_follows_fixed_sides() {
+        // The chains again, with a pad off each end of the core
+        let mut tc = TestCircuit::new().rows(4, 40, 1);
+        for i in 0..12 {
+            tc = tc.cell(&format!("c{i}"), 2.0, 1.0, 3.0 * i as f32, 0.0);
+        }
+        for i in 0..11 {
+            tc = tc.net(&format!("n{i}"), &[&format!("c{i}"), &format!("c{}", i + 1)]);
+        }
+        tc = tc.terminal("west", 1.0, 1.0, -2.0, 1.0).terminal("east", 1.0, 1.0, 41.0, 1.0);
+        let mut bc = tc.net("tw", &["west", "c0"]).net("te", &["east", "c11"]).build();
+
+        let cells: Vec<usize> = (0..12).collect();
+        let mut params = HyperParams::new(&bc);
+        params.split_point = 20.0;
+        let hg = bc.build_graph(&cells, &mut params);
+        let (left, right, _cut) = Metapartitioner::new().hg_partition(&hg);
+        let (src, sink) = (cells.len(), cells.len() + 1);
+        assert!(left.contains(&src));
+        assert!(right.contains(&sink));
+        let c0 = params.cellmark.index[0];
+        let c11 = params.cellmark.index[11];
+        assert!(left.contains(&c0));
+        assert!(right.contains(&c11));
+
+        // The placer keeps the chain ends next to their pads
+        bc.mincut_place(&PlacerParams::new());
+        assert!(bc.cellpos[0].x < 20.0 && bc.cellpos[11].x > 20.0);
+    }
+
+    #[test]
+    fn tall_leaf_stays_in_region() {
+        // Six cells of width 3 in a 4 by 4 region wrap into six lines
+        let mut tc = TestCircuit::new().rows(4, 4, 1);
+        for i in 0..6 {
+            tc = tc.cell(&format!("c{i}"), 3.0, 1.0, 0.0, 0.0);
+        }
+        let mut bc = tc.build();
+        let region = Region {
+            bounds: bc.core(),
+            cells: (0..6).collect(),
+            horizontal: false,
+            level: 0,
+        };
+        bc.place_leaf(&region);
+        let b = region.bounds;
+        for i in 0..6 {
+            let p = &bc.cellpos[i];
+            assert!(p.x >= b.llx && p.x + bc.cells[i].w <= b.urx);
+            assert!(p.y >= b.lly && p.y + bc.cells[i].h <= b.ury, "c{i} at {}", p.y);
+        }
+        assert_eq!(bc.cellpos[0].y, b.lly);
+        assert_eq!(bc.cellpos[5].y + 1.0, b.ury);
+    }
+
+    #[test]
+    fn split_by_bias() {
+        let bc = circuit();
+        let cells: Vec<usize> = (0..8).collect();
+        let (lo, hi) = bc.split_by_position(&cells, false, 0.25);
+        assert_eq!(lo, vec![0, 1]);
+        assert_eq!(hi.len(), 6);
+        let (lo, _hi) = bc.split_by_position(&cells, false, 0.5);
+        assert_eq!(lo.len(), 4);
+    }
+}
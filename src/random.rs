@@ -0,0 +1,56 @@
+//! Small, seedable random number generator (xorshift64*).  The
+//! annealing and generator code needs repeatable runs from a seed,
+//! and does not need anything fancier than this.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: usize) -> Random {
+        let mut r = Random {
+            state: (seed as u64) ^ 0x9E3779B97F4A7C15,
+        };
+        if r.state == 0 {
+            r.state = 0x9E3779B97F4A7C15;
+        }
+        // Mix the seed a little, so that nearby seeds diverge quickly
+        for _i in 0..4 {
+            r.next_u64();
+        }
+        r
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Uniform value in [0, 1)
+    pub fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform integer in 0..n
+    pub fn range(&mut self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Uniform value in [lo, hi)
+    pub fn between(&mut self, lo: f32, hi: f32) -> f32 {
+        lo + (hi - lo) * self.uniform()
+    }
+
+    pub fn shuffle<T>(&mut self, v: &mut Vec<T>) {
+        for i in (1..v.len()).rev() {
+            let j = self.range(i + 1);
+            v.swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Circuit statistics.
+//!
+//! `summarize` prints a few totals; this gathers a more complete set
+//! of numbers (net degrees, pins per cell, cell sizes, terminals on
+//! each side of the core, row whitespace, the largest nets, and cells
+//! that are not connected to anything), and can write them out as
+//! JSON for scripts that characterize benchmarks.
+use crate::bookshelf::BookshelfCircuit;
+use std::fs::File;
+use std::io::Write;
+
+pub struct CircuitStats {
+    pub name: String,
+    pub num_cells: usize,
+    pub num_macros: usize,
+    pub num_terminals: usize,
+    pub num_nets: usize,
+    pub num_pins: usize,
+    pub num_rows: usize,
+    pub hpwl: f32,
+    /// (degree, number of nets)
+    pub degree_histogram: Vec<(usize, usize)>,
+    /// (pins, number of cells), for the non-terminal cells
+    pub pins_histogram: Vec<(usize, usize)>,
+    pub avg_pins_per_cell: f32,
+    /// (size, number of cells), for the non-terminal cells
+    pub width_histogram: Vec<(f32, usize)>,
+    pub height_histogram: Vec<(f32, usize)>,
+    pub cell_area: f32,
+    pub macro_area: f32,
+    pub macro_area_fraction: f32,
+    /// Terminals below, right of, above, left of, and inside the core
+    pub terminals_per_side: [usize; 5],
+    /// Row area not covered by cells, for each row
+    pub row_whitespace: Vec<f32>,
+    /// (net name, degree), largest first
+    pub largest_nets: Vec<(String, usize)>,
+    /// Cells with no pins at all
+    pub isolated_cells: Vec<String>,
+    /// Cells with pins, but only on single-pin nets
+    pub unconnected_cells: Vec<String>,
+}
+
+const SIDE_NAMES: [&str; 5] = ["bottom", "right", "top", "left", "inside"];
+
+// Sorted (value, count) pairs from a list of values.
+fn histogram<T: PartialOrd + Copy>(values: &mut Vec<T>) -> Vec<(T, usize)> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut result: Vec<(T, usize)> = Vec::new();
+    for v in values.iter() {
+        match result.last_mut() {
+            Some((lv, count)) if *lv == *v => *count += 1,
+            _ => result.push((*v, 1)),
+        }
+    }
+    result
+}
+
+fn json_string(s: &String) -> String {
+    let mut result = "\"".to_string();
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// JSON has no NaN or infinity, so those are written as null.
+fn json_number(v: f32) -> String {
+    if v.is_finite() {
+        format!("{}", v)
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_pairs<T: std::fmt::Display>(pairs: &Vec<(T, usize)>) -> String {
+    let items: Vec<String> = pairs.iter().map(|(v, c)| format!("[{}, {}]", v, c)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn json_names(names: &Vec<String>) -> String {
+    let items: Vec<String> = names.iter().map(|n| json_string(n)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+impl CircuitStats {
+    pub fn to_json(&self) -> String {
+        let sizes = |h: &Vec<(f32, usize)>| json_pairs(&h.iter().map(|(v, c)| (json_number(*v), *c)).collect());
+        let mut fields = Vec::new();
+        fields.push(format!("\"name\": {}", json_string(&self.name)));
+        fields.push(format!("\"num_cells\": {}", self.num_cells));
+        fields.push(format!("\"num_macros\": {}", self.num_macros));
+        fields.push(format!("\"num_terminals\": {}", self.num_terminals));
+        fields.push(format!("\"num_nets\": {}", self.num_nets));
+        fields.push(format!("\"num_pins\": {}", self.num_pins));
+        fields.push(format!("\"num_rows\": {}", self.num_rows));
+        fields.push(format!("\"hpwl\": {}", json_number(self.hpwl)));
+        fields.push(format!("\"degree_histogram\": {}", json_pairs(&self.degree_histogram)));
+        fields.push(format!("\"pins_histogram\": {}", json_pairs(&self.pins_histogram)));
+        fields.push(format!("\"avg_pins_per_cell\": {}", json_number(self.avg_pins_per_cell)));
+        fields.push(format!("\"width_histogram\": {}", sizes(&self.width_histogram)));
+        fields.push(format!("\"height_histogram\": {}", sizes(&self.height_histogram)));
+        fields.push(format!("\"cell_area\": {}", json_number(self.cell_area)));
+        fields.push(format!("\"macro_area\": {}", json_number(self.macro_area)));
+        fields.push(format!("\"macro_area_fraction\": {}", json_number(self.macro_area_fraction)));
+        let sides: Vec<String> = (0..5)
+            .map(|s| format!("\"{}\": {}", SIDE_NAMES[s], self.terminals_per_side[s]))
+            .collect();
+        fields.push(format!("\"terminals_per_side\": {{{}}}", sides.join(", ")));
+        let ws: Vec<String> = self.row_whitespace.iter().map(|w| json_number(*w)).collect();
+        fields.push(format!("\"row_whitespace\": [{}]", ws.join(", ")));
+        let nets: Vec<String> = self
+            .largest_nets
+            .iter()
+            .map(|(n, d)| format!("[{}, {}]", json_string(n), d))
+            .collect();
+        fields.push(format!("\"largest_nets\": [{}]", nets.join(", ")));
+        fields.push(format!("\"isolated_cells\": {}", json_names(&self.isolated_cells)));
+        fields.push(format!("\"unconnected_cells\": {}", json_names(&self.unconnected_cells)));
+        format!("{{\n  {}\n}}\n", fields.join(",\n  "))
+    }
+
+    pub fn write_json(&self, filepath: &String) {
+        let mut f = File::create(filepath).unwrap();
+        write!(&mut f, "{}", self.to_json()).unwrap();
+    }
+
+    /// Prints the highlights; the JSON output has everything.
+    pub fn report(&self) {
+        println!("---- CIRCUIT STATISTICS ----");
+        println!(
+            "{} cells, {} macros, {} terminals, {} nets, {} pins, {} rows",
+            self.num_cells, self.num_macros, self.num_terminals, self.num_nets, self.num_pins, self.num_rows
+        );
+        println!("Net degrees:");
+        for (d, count) in &self.degree_histogram {
+            println!("  {:5} : {}", d, count);
+        }
+        println!("Average pins per cell: {}", self.avg_pins_per_cell);
+        println!("Macro area fraction: {}", self.macro_area_fraction);
+        print!("Terminals:");
+        for s in 0..5 {
+            print!(" {} {}", SIDE_NAMES[s], self.terminals_per_side[s]);
+        }
+        println!();
+        let ws: f32 = self.row_whitespace.iter().sum();
+        println!("Row whitespace: {}", ws);
+        if let Some((n, d)) = self.largest_nets.first() {
+            println!("Largest net: {} ({} pins)", n, d);
+        }
+        println!(
+            "{} isolated cells, {} unconnected cells",
+            self.isolated_cells.len(),
+            self.unconnected_cells.len()
+        );
+        println!("---------------");
+    }
+}
+
+impl BookshelfCircuit {
+    /// Gathers statistics for the circuit.  Terminal sides and row
+    /// whitespace use the current cell positions.
+    pub fn stats(&self) -> CircuitStats {
+        let mut degrees: Vec<usize> = self.nets.iter().map(|n| n.pins.len()).collect();
+        let num_pins = degrees.iter().sum();
+        let degree_histogram = histogram(&mut degrees);
+
+        let mut pins = Vec::new();
+        let mut widths = Vec::new();
+        let mut heights = Vec::new();
+        let mut cell_area = 0.0;
+        let mut macro_area = 0.0;
+        let mut isolated_cells = Vec::new();
+        let mut unconnected_cells = Vec::new();
+        let mut terminals_per_side = [0; 5];
+        let (mut num_cells, mut num_macros, mut num_terminals) = (0, 0, 0);
+        let core = self.core();
+
+        for i in 0..self.cells.len() {
+            let c = &self.cells[i];
+            if c.pins.is_empty() {
+                isolated_cells.push(c.name.clone());
+            } else if c.pins.iter().all(|p| self.nets[p.parent_net].pins.len() < 2) {
+                unconnected_cells.push(c.name.clone());
+            }
+            if c.terminal {
+                num_terminals += 1;
+                let x = self.cellpos[i].x + c.w * 0.5;
+                let y = self.cellpos[i].y + c.h * 0.5;
+                let side = if y < core.lly {
+                    0
+                } else if x > core.urx {
+                    1
+                } else if y > core.ury {
+                    2
+                } else if x < core.llx {
+                    3
+                } else {
+                    4
+                };
+                terminals_per_side[side] += 1;
+                continue;
+            }
+            if c.is_macro {
+                num_macros += 1;
+                macro_area = macro_area + c.area();
+            } else {
+                num_cells += 1;
+            }
+            cell_area = cell_area + c.area();
+            pins.push(c.pins.len());
+            widths.push(c.w);
+            heights.push(c.h);
+        }
+
+        let mut row_whitespace: Vec<f32> = self.rows.iter().map(|r| r.bounds.area()).collect();
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal {
+                continue;
+            }
+            let p = &self.cellpos[i];
+            let (w, h) = (self.cells[i].w, self.cells[i].h);
+            for r in 0..self.rows.len() {
+                let b = &self.rows[r].bounds;
+                let dx = (p.x + w).min(b.urx) - p.x.max(b.llx);
+                let dy = (p.y + h).min(b.ury) - p.y.max(b.lly);
+                if dx > 0.0 && dy > 0.0 {
+                    row_whitespace[r] = row_whitespace[r] - dx * dy;
+                }
+            }
+        }
+
+        let mut by_degree: Vec<usize> = (0..self.nets.len()).collect();
+        by_degree.sort_by(|a, b| self.nets[*b].pins.len().cmp(&self.nets[*a].pins.len()));
+        let largest_nets = by_degree
+            .iter()
+            .take(10)
+            .map(|n| (self.nets[*n].name.clone(), self.nets[*n].pins.len()))
+            .collect();
+
+        let mut avg_pins_per_cell = 0.0;
+        if !pins.is_empty() {
+            avg_pins_per_cell = pins.iter().sum::<usize>() as f32 / pins.len() as f32;
+        }
+        let mut macro_area_fraction = 0.0;
+        if cell_area > 0.0 {
+            macro_area_fraction = macro_area / cell_area;
+        }
+
+        CircuitStats {
+            name: self.name.clone(),
+            num_cells: num_cells,
+            num_macros: num_macros,
+            num_terminals: num_terminals,
+            num_nets: self.nets.len(),
+            num_pins: num_pins,
+            num_rows: self.rows.len(),
+            hpwl: self.wl(),
+            degree_histogram: degree_histogram,
+            pins_histogram: histogram(&mut pins),
+            avg_pins_per_cell: avg_pins_per_cell,
+            width_histogram: histogram(&mut widths),
+            height_histogram: histogram(&mut heights),
+            cell_area: cell_area,
+            macro_area: macro_area,
+            macro_area_fraction: macro_area_fraction,
+            terminals_per_side: terminals_per_side,
+            row_whitespace: row_whitespace,
+            largest_nets: largest_nets,
+            isolated_cells: isolated_cells,
+            unconnected_cells: unconnected_cells,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    // Two rows of 10 by 2.  b straddles the rows, iso has no pins, lone
+    // is only on a one-pin net, and there is a terminal on each side of
+    // the core and one inside
+    fn circuit() -> BookshelfCircuit {
+        TestCircuit::new()
+            .rows(2, 10, 2)
+            .cell("a", 2.0, 2.0, 0.0, 0.0)
+            .cell("b", 2.0, 2.0, 4.0, 1.0)
+            .cell("c", 1.0, 2.0, 8.0, 2.0)
+            .cell("iso", 1.0, 2.0, 6.0, 0.0)
+            .cell("lone", 1.0, 2.0, 9.0, 0.0)
+            .terminal("pb", 1.0, 1.0, 5.0, -3.0)
+            .terminal("pr", 1.0, 1.0, 12.0, 2.0)
+            .terminal("pt", 1.0, 1.0, 5.0, 6.0)
+            .terminal("pl", 1.0, 1.0, -3.0, 2.0)
+            .terminal("pi", 1.0, 1.0, 3.0, 3.0)
+            .net("n0", &["a", "b", "c", "pb"])
+            .net("n1", &["a", "pr"])
+            .net("n2", &["b", "pt", "pl", "pi"])
+            .net("n3", &["c", "b"])
+            .net("single", &["lone"])
+            .build()
+    }
+
+    #[test]
+    fn circuit_stats() {
+        let st = circuit().stats();
+        assert_eq!((st.num_cells, st.num_terminals, st.num_nets, st.num_pins), (5, 5, 5, 13));
+        assert_eq!(st.degree_histogram, vec![(1, 1), (2, 2), (4, 2)]);
+        assert_eq!(st.pins_histogram, vec![(0, 1), (1, 1), (2, 2), (3, 1)]);
+        assert_eq!(st.terminals_per_side, [1, 1, 1, 1, 1]);
+        // a, half of b, iso, and lone in the lower row; the other half
+        // of b, and c, in the upper one
+        assert_eq!(st.row_whitespace, vec![10.0, 16.0]);
+        assert_eq!(st.isolated_cells, vec!["iso".to_string()]);
+        assert_eq!(st.unconnected_cells, vec!["lone".to_string()]);
+        assert_eq!(st.largest_nets[0].1, 4);
+    }
+
+    #[test]
+    fn json_escapes_names() {
+        let mut st = circuit().stats();
+        st.name = "a \"quoted\" \\path\\ name".to_string();
+        st.isolated_cells.push("tab\there".to_string());
+        let json = st.to_json();
+        assert!(json.contains(r#""name": "a \"quoted\" \\path\\ name""#));
+        assert!(json.contains(r#""tab\u0009here""#));
+        assert!(json.contains(r#""terminals_per_side": {"bottom": 1, "right": 1, "top": 1, "left": 1, "inside": 1}"#));
+        assert!(json.contains(r#""degree_histogram": [[1, 1], [2, 2], [4, 2]]"#));
+        assert!(json.contains(r#""row_whitespace": [10, 16]"#));
+        assert!(json.contains(r#""isolated_cells": ["iso", "tab\u0009here"]"#));
+        assert!(json.starts_with("{\n") && json.ends_with("}\n"));
+    }
+
+    #[test]
+    fn json_non_finite() {
+        let mut st = circuit().stats();
+        st.hpwl = f32::NAN;
+        st.row_whitespace = vec![f32::INFINITY, 2.5];
+        st.width_histogram = vec![(f32::NEG_INFINITY, 1), (1.5, 2)];
+        let json = st.to_json();
+        assert!(json.contains(r#""hpwl": null"#));
+        assert!(json.contains(r#""row_whitespace": [null, 2.5]"#));
+        assert!(json.contains(r#""width_histogram": [[null, 1], [1.5, 2]]"#));
+        assert!(!json.contains("NaN") && !json.contains("inf"));
+    }
+}
@@ -0,0 +1,177 @@
+//! Small circuits for the unit tests.
+//!
+//! `TestCircuit` collects cells, terminals, nets, and rows, writes them
+//! out as a Bookshelf file set in a scratch directory, and reads them
+//! back with `read_aux`, so that the tests start from exactly what the
+//! readers would give for a real design.  Cells are placed at their
+//! lower left corners, pin offsets are from the cell center (as in the
+//! nets file), and rows are on a site grid of 1, starting at x = 0.
+use crate::bookshelf::BookshelfCircuit;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestCircuit {
+    nodes: Vec<String>,
+    num_terminals: usize,
+    nets: Vec<String>,
+    num_nets: usize,
+    num_pins: usize,
+    pl: Vec<String>,
+    rows: Vec<String>,
+    num_rows: usize,
+}
+
+impl TestCircuit {
+    pub fn new() -> TestCircuit {
+        TestCircuit {
+            nodes: Vec::new(),
+            num_terminals: 0,
+            nets: Vec::new(),
+            num_nets: 0,
+            num_pins: 0,
+            pl: Vec::new(),
+            rows: Vec::new(),
+            num_rows: 0,
+        }
+    }
+
+    /// Adds n rows of the given height, each with width sites, stacked
+    /// up from y = 0.
+    pub fn rows(mut self, n: usize, width: usize, height: usize) -> TestCircuit {
+        for r in 0..n {
+            self.rows.push(format!(
+                "CoreRow Horizontal\n Coordinate : {}\n Height : {}\n Sitewidth : 1\n Sitespacing : 1\n \
+                 Siteorient : N\n Sitesymmetry : Y\n SubrowOrigin : 0 NumSites : {}\nEnd",
+                r * height,
+                height,
+                width
+            ));
+        }
+        self.num_rows += n;
+        self
+    }
+
+    pub fn cell(mut self, name: &str, w: f32, h: f32, x: f32, y: f32) -> TestCircuit {
+        self.nodes.push(format!("{} {} {}", name, w, h));
+        self.pl.push(format!("{} {} {} : N", name, x, y));
+        self
+    }
+
+    pub fn terminal(mut self, name: &str, w: f32, h: f32, x: f32, y: f32) -> TestCircuit {
+        self.nodes.push(format!("{} {} {} terminal", name, w, h));
+        self.pl.push(format!("{} {} {} : N /FIXED", name, x, y));
+        self.num_terminals += 1;
+        self
+    }
+
+    /// A net with a pin at the center of each cell.
+    pub fn net(self, name: &str, cells: &[&str]) -> TestCircuit {
+        let pins: Vec<(&str, &str, f32, f32)> = cells.iter().map(|c| (*c, "B", 0.0, 0.0)).collect();
+        self.pins(name, &pins)
+    }
+
+    /// A net with (cell, direction, dx, dy) for each pin.
+    pub fn pins(self, name: &str, pins: &[(&str, &str, f32, f32)]) -> TestCircuit {
+        let pins: Vec<(&str, &str, f32, f32, &str)> = pins.iter().map(|(c, d, x, y)| (*c, *d, *x, *y, "")).collect();
+        self.named(name, &pins)
+    }
+
+    /// A net with (cell, direction, dx, dy, pin name) for each pin.
+    pub fn named(mut self, name: &str, pins: &[(&str, &str, f32, f32, &str)]) -> TestCircuit {
+        self.nets.push(format!("NetDegree : {} {}", pins.len(), name));
+        for (cell, dir, dx, dy, pin) in pins {
+            self.nets.push(format!(" {} {} : {} {} {}", cell, dir, dx, dy, pin));
+        }
+        self.num_nets += 1;
+        self.num_pins += pins.len();
+        self
+    }
+
+    /// Writes the files to a scratch directory, and reads them back.
+    pub fn build(self) -> BookshelfCircuit {
+        let dir = scratch_dir();
+        let files = [
+            ("t.aux", "RowBasedPlacement : t.nodes t.nets t.wts t.pl t.scl".to_string()),
+            (
+                "t.nodes",
+                format!(
+                    "UCLA nodes 1.0\nNumNodes : {}\nNumTerminals : {}\n{}\n",
+                    self.nodes.len(),
+                    self.num_terminals,
+                    self.nodes.join("\n")
+                ),
+            ),
+            (
+                "t.nets",
+                format!(
+                    "UCLA nets 1.0\nNumNets : {}\nNumPins : {}\n{}\n",
+                    self.num_nets,
+                    self.num_pins,
+                    self.nets.join("\n")
+                ),
+            ),
+            ("t.wts", "UCLA wts 1.0\n".to_string()),
+            ("t.pl", format!("UCLA pl 1.0\n{}\n", self.pl.join("\n"))),
+            (
+                "t.scl",
+                format!("UCLA scl 1.0\nNumRows : {}\n{}\n", self.num_rows, self.rows.join("\n")),
+            ),
+        ];
+        for (name, text) in &files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        let bc = BookshelfCircuit::read_aux(&dir.join("t.aux").to_string_lossy().to_string());
+        fs::remove_dir_all(&dir).unwrap();
+        bc
+    }
+}
+
+/// A new, empty directory for the files of one test.  The caller
+/// removes it when done.
+pub fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "bookshelf_r_test_{}_{}",
+        std::process::id(),
+        SCRATCH.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Pairs of cells that overlap (by more than a rounding error).
+pub fn overlaps(bc: &BookshelfCircuit) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    let n = bc.cells.len();
+    for i in 0..n {
+        let (p, c) = (&bc.cellpos[i], &bc.cells[i]);
+        for j in i + 1..n {
+            let (q, d) = (&bc.cellpos[j], &bc.cells[j]);
+            if p.x + c.w > q.x + 1e-3 && q.x + d.w > p.x + 1e-3 && p.y + c.h > q.y + 1e-3 && q.y + d.h > p.y + 1e-3 {
+                result.push((i, j));
+            }
+        }
+    }
+    result
+}
+
+/// Panics unless every movable standard cell sits in a row, on the
+/// site grid, without overlapping anything.
+pub fn assert_legal(bc: &BookshelfCircuit) {
+    for i in 0..bc.cells.len() {
+        let (p, c) = (&bc.cellpos[i], &bc.cells[i]);
+        if c.terminal || c.is_macro {
+            continue;
+        }
+        let in_row = bc
+            .rows
+            .iter()
+            .any(|r| r.bounds.lly == p.y && r.bounds.llx <= p.x && p.x + c.w <= r.bounds.urx);
+        assert!(in_row, "{} at {} {} is not in a row", c.name, p.x, p.y);
+        assert_eq!(p.x, p.x.round(), "{} is off the site grid", c.name);
+    }
+    let bad = overlaps(bc);
+    assert!(bad.is_empty(), "{} overlaps {}", bc.cells[bad[0].0].name, bc.cells[bad[0].1].name);
+}
@@ -0,0 +1,293 @@
+//! Device tiers, for 3D placement.
+//!
+//! A 3D design stacks several tiers of standard cells, each with the
+//! same core area and rows.  The tier of each cell is kept in `tier`
+//! (None for an ordinary 2D design, where everything is on tier 0), and
+//! each row has the tier it belongs to.  The PL files carry the tier as
+//! a Z coordinate after the Y, and the rows for tiers above 0 are copies
+//! of the tier 0 rows from the SCL file.
+//!
+//! A net that spans tiers needs vias; the wire length of a net adds
+//! `via_cost` for each tier between its lowest and highest cells, so
+//! `wl` and everything built on it (the detail placer, for example)
+//! see the cost.  The legalizer and detail placer keep cells in the
+//! rows of their own tier, and clusters do not mix tiers.
+//!
+//! `fold_tiers` turns a 2D placement into a 3D one by folding the core
+//! into vertical stripes, one per tier, and `ps_tiers` draws the tiers
+//! side by side.
+use crate::bookshelf::{BookshelfCircuit, Net, Row};
+
+impl BookshelfCircuit {
+    /// Tier of a cell (0 if the design has no tiers).
+    pub fn tier_of(&self, cell_id: usize) -> usize {
+        match &self.tier {
+            Some(tier) => tier[cell_id],
+            None => 0,
+        }
+    }
+
+    /// Moves a cell to a tier, adding the tier vector (and raising the
+    /// tier count) if needed.  Rows for new tiers are made by set_tiers.
+    pub fn set_tier(&mut self, cell_id: usize, t: usize) {
+        let n = self.cells.len();
+        let tier = self.tier.get_or_insert_with(|| vec![0; n]);
+        tier[cell_id] = t;
+        if t + 1 > self.num_tiers {
+            self.num_tiers = t + 1;
+        }
+    }
+
+    /// Sets the number of tiers.  Tiers without rows get copies of the
+    /// tier 0 rows; rows on tiers that no longer exist are removed, and
+    /// their cells moved to the top tier.
+    pub fn set_tiers(&mut self, n: usize) {
+        let n = n.max(1);
+        self.rows.retain(|r| r.tier < n);
+        for t in 1..n {
+            if self.rows.iter().any(|r| r.tier == t) {
+                continue;
+            }
+            let mut copies = Vec::new();
+            for r in self.rows.iter().filter(|r| r.tier == 0) {
+                copies.push(Row {
+                    name: r.name.clone(),
+                    bounds: r.bounds,
+                    site_spacing: r.site_spacing,
+                    orient: r.orient,
+                    symmetry: r.symmetry.clone(),
+                    tier: t,
+                });
+            }
+            self.rows.append(&mut copies);
+        }
+        if let Some(tier) = &mut self.tier {
+            for t in tier.iter_mut() {
+                *t = (*t).min(n - 1);
+            }
+        } else if n > 1 {
+            self.tier = Some(vec![0; self.cells.len()]);
+        }
+        self.num_tiers = n;
+    }
+
+    /// Lowest and highest tiers of the cells on a net.
+    pub fn net_tiers(&self, n: &Net) -> (usize, usize) {
+        let mut lo = usize::MAX;
+        let mut hi = 0;
+        for pr in &n.pins {
+            let t = self.tier_of(pr.parent_cell);
+            lo = lo.min(t);
+            hi = hi.max(t);
+        }
+        if lo > hi {
+            (0, 0)
+        } else {
+            (lo, hi)
+        }
+    }
+
+    /// Wire length added to a net for the tiers it crosses.
+    pub fn net_via_cost(&self, n: &Net) -> f32 {
+        if self.tier.is_none() || self.via_cost == 0.0 {
+            return 0.0;
+        }
+        let (lo, hi) = self.net_tiers(n);
+        self.via_cost * (hi - lo) as f32
+    }
+
+    /// The Z field for a cell in a PL file -- empty for 2D designs.
+    pub fn z_field(&self, cell_id: usize) -> String {
+        match &self.tier {
+            Some(tier) => format!(" {}", tier[cell_id]),
+            None => "".to_string(),
+        }
+    }
+
+    /// Spreads a 2D placement over n tiers.  The core is cut into n
+    /// vertical stripes; the cells of stripe k go to tier k, stretched
+    /// to the full core width.  Odd stripes are mirrored, so that cells
+    /// on either side of a fold stay close to each other.  Terminals
+    /// stay on tier 0, and are not moved.  The result should be
+    /// legalized.  Returns the number of cells moved to another tier.
+    pub fn fold_tiers(&mut self, n: usize) -> usize {
+        let n = n.max(1);
+        self.set_tiers(n);
+        let core = self.core();
+        let stripe = core.dx() / n as f32;
+        let mut moved = 0;
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal {
+                continue;
+            }
+            let w = self.cells[i].w;
+            let cx = self.cellpos[i].x + w * 0.5;
+            let k = (((cx - core.llx) / stripe).floor().max(0.0) as usize).min(n - 1);
+            let offset = (self.cellpos[i].x - (core.llx + stripe * k as f32)) * n as f32;
+            let x = if k % 2 == 0 { core.llx + offset } else { core.urx - offset - w };
+            self.cellpos[i].x = x.max(core.llx).min(core.urx - w);
+            if self.tier_of(i) != k {
+                moved += 1;
+            }
+            self.set_tier(i, k);
+        }
+        self.notes.push(format!("Folded into {} tiers, {} cells moved", n, moved));
+        moved
+    }
+
+    /// Cells, area, and tier-crossing nets for each tier.
+    pub fn tier_report(&self) {
+        let mut cells = vec![0; self.num_tiers];
+        let mut area = vec![0.0; self.num_tiers];
+        for i in 0..self.cells.len() {
+            if self.cells[i].terminal {
+                continue;
+            }
+            let t = self.tier_of(i);
+            cells[t] += 1;
+            area[t] += self.cells[i].area();
+        }
+        let mut crossing = 0;
+        let mut vias = 0;
+        for n in &self.nets {
+            let (lo, hi) = self.net_tiers(n);
+            if hi > lo {
+                crossing += 1;
+                vias += hi - lo;
+            }
+        }
+        println!(
+            "Tiers: {}, {} nets cross tiers, {} vias, via cost {}",
+            self.num_tiers, crossing, vias, self.via_cost
+        );
+        for t in 0..self.num_tiers {
+            println!("  Tier {}: {} cells, area {}", t, cells[t], area[t]);
+        }
+    }
+
+    /// Draws the tiers side by side, with the cells of each tier in its
+    /// own copy of the core.  Cells on nets that cross tiers are red.
+    pub fn ps_tiers(&self, filename: String) {
+        let mut crossing = vec![false; self.cells.len()];
+        for n in &self.nets {
+            let (lo, hi) = self.net_tiers(n);
+            if hi > lo {
+                for pr in &n.pins {
+                    crossing[pr.parent_cell] = true;
+                }
+            }
+        }
+
+        let mut pst = self.postscript_prep();
+        let display = self.bookshelf_display();
+        let core = self.core();
+        let height = 0.02 * core.dy();
+        pst.set_font(height, "Courier".to_string());
+        for t in 0..self.num_tiers {
+            pst.add_gsave();
+            pst.add_translate((core.urx - core.llx) * 1.05 * t as f32, 0.0);
+
+            pst.set_color(0.8, 0.8, 0.8, 1.0);
+            for r in self.rows.iter().filter(|r| r.tier == t) {
+                pst.add_box(r.bounds.llx, r.bounds.lly, r.bounds.urx, r.bounds.ury);
+            }
+            pst.set_color(0.0, 0.0, 0.0, 1.0);
+            pst.add_box(core.llx, core.lly, core.urx, core.ury);
+            pst.add_text(core.llx, core.ury + height, format!("Tier {}", t));
+            if t == 0 {
+                self.ps_terminals(&mut pst, &display);
+            }
+
+            for i in 0..self.cells.len() {
+                if self.cells[i].terminal || self.tier_of(i) != t {
+                    continue;
+                }
+                if crossing[i] {
+                    pst.set_color(1.0, 0.0, 0.0, 1.0);
+                } else {
+                    pst.set_color(0.4, 0.4, 1.0, 1.0);
+                }
+                let p = &self.cellpos[i];
+                pst.add_box(p.x + 0.25, p.y + 0.25, p.x + self.cells[i].w - 0.5, p.y + self.cells[i].h - 0.5);
+            }
+            pst.add_grestore();
+        }
+        pst.set_border(40.0);
+        pst.generate(filename).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bookshelf::BookshelfCircuit;
+    use crate::testutil::{scratch_dir, TestCircuit};
+
+    // Cells c0..c3 across a 40 wide core of two rows, and a pad
+    fn circuit() -> BookshelfCircuit {
+        TestCircuit::new()
+            .rows(2, 40, 1)
+            .cell("c0", 2.0, 1.0, 0.0, 0.0)
+            .cell("c1", 2.0, 1.0, 10.0, 0.0)
+            .cell("c2", 2.0, 1.0, 20.0, 1.0)
+            .cell("c3", 2.0, 1.0, 36.0, 1.0)
+            .terminal("p", 1.0, 1.0, 30.0, -2.0)
+            .net("n0", &["c0", "c2"])
+            .net("n1", &["c0", "c1", "p"])
+            .build()
+    }
+
+    #[test]
+    fn fold_into_stripes() {
+        let mut bc = circuit();
+        assert_eq!(bc.fold_tiers(2), 2);
+        assert_eq!(bc.num_tiers, 2);
+        assert_eq!(bc.rows.iter().filter(|r| r.tier == 1).count(), 2);
+        // Both stripes are stretched, and the right one mirrored
+        let got: Vec<(usize, f32)> = (0..5).map(|i| (bc.tier_of(i), bc.cellpos[i].x)).collect();
+        assert_eq!(got, vec![(0, 0.0), (0, 20.0), (1, 38.0), (1, 6.0), (0, 30.0)]);
+    }
+
+    #[test]
+    fn fewer_tiers() {
+        let mut bc = circuit();
+        bc.fold_tiers(4);
+        assert_eq!(bc.tier_of(3), 3);
+        bc.set_tiers(2);
+        assert_eq!(bc.num_tiers, 2);
+        assert_eq!(bc.rows.len(), 4);
+        assert!(bc.rows.iter().all(|r| r.tier < 2));
+        let tiers: Vec<usize> = (0..5).map(|i| bc.tier_of(i)).collect();
+        assert_eq!(tiers, vec![0, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn via_cost_in_wl() {
+        let mut bc = circuit();
+        bc.fold_tiers(2);
+        let wl = bc.wl();
+        bc.via_cost = 5.0;
+        // Only n0 crosses a tier
+        assert_eq!(bc.net_via_cost(&bc.nets[bc.net_map["n0"]]), 5.0);
+        assert_eq!(bc.net_via_cost(&bc.nets[bc.net_map["n1"]]), 0.0);
+        assert_eq!(bc.wl(), wl + 5.0);
+    }
+
+    #[test]
+    fn pl_round_trip_with_z() {
+        let mut bc = circuit();
+        bc.fold_tiers(3);
+        let dir = scratch_dir();
+        let path = dir.join("t.pl");
+        bc.write_pl(path.to_string_lossy().to_string(), &Vec::new());
+
+        let mut back = circuit();
+        back.read_pl(&path, false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(back.num_tiers, 3);
+        assert_eq!(back.rows.len(), bc.rows.len());
+        for i in 0..5 {
+            assert_eq!(back.tier_of(i), bc.tier_of(i));
+            assert_eq!((back.cellpos[i].x, back.cellpos[i].y), (bc.cellpos[i].x, bc.cellpos[i].y));
+        }
+    }
+}
@@ -0,0 +1,571 @@
+//! Static timing analysis.
+//!
+//! The timing graph has a node for every pin.  Net arcs go from the
+//! driver of each net (its O pin, or the first B pin if there is no
+//! output) to the other pins on the net; cell arcs go from each input
+//! of a cell to each of its outputs.  A net with more than one O pin
+//! gets a warning; only the first drives it, and the others are left
+//! out of the cell arcs.  Terminals have no cell arcs, so
+//! they are the primary inputs and outputs.  There are no registers in
+//! a Bookshelf netlist, so loops are broken where a depth first search
+//! finds them, and the number of broken arcs is reported.
+//!
+//! Delays are simple: every cell arc has the intrinsic cell delay, and
+//! every net arc has the Elmore delay of a star from the driver, using
+//! the placed pin locations (`pinloc`) -- the driver resistance times
+//! the whole net capacitance, plus the resistance of the wire to the
+//! sink times the capacitance beyond it.  Arrival times go forward from
+//! the start points, required times go back from the end points (all
+//! required at the clock period), and the report has the worst and
+//! total negative slack, and the most critical paths.
+//!
+//! Net criticality (from the slack at the driver) can be used for
+//! EdgeWeight::Criticality in build_graph, or applied to the net
+//! weights with `set_timing_weights`, for `weighted_wl` and the .wts
+//! file.
+use crate::bookshelf::{BookshelfCircuit, PinDirection, PinRef};
+
+pub struct TimingParams {
+    /// Every end point is required by this time.  Zero uses the
+    /// longest arrival time, so that the worst slack is zero.
+    pub clock_period: f32,
+    /// Intrinsic delay of a cell, from any input to any output
+    pub cell_delay: f32,
+    pub drive_resistance: f32,
+    /// Wire resistance and capacitance per unit length
+    pub wire_resistance: f32,
+    pub wire_capacitance: f32,
+    /// Capacitance of each sink pin
+    pub pin_capacitance: f32,
+    /// Number of critical paths to report
+    pub paths: usize,
+    pub verbose: bool,
+}
+
+impl TimingParams {
+    pub fn new() -> TimingParams {
+        TimingParams {
+            clock_period: 0.0,
+            cell_delay: 1.0,
+            drive_resistance: 1.0,
+            wire_resistance: 0.01,
+            wire_capacitance: 0.01,
+            pin_capacitance: 1.0,
+            paths: 3,
+            verbose: false,
+        }
+    }
+}
+
+/// An arc of the timing graph.  Net arcs have the net index, cell arcs
+/// have None.
+pub struct TimingArc {
+    pub from: usize,
+    pub to: usize,
+    pub net: Option<usize>,
+}
+
+/// Nodes are pins, numbered cell by cell: the node for pin k of cell c
+/// is pin_base[c] + k.
+pub struct TimingGraph {
+    pub pin_base: Vec<usize>,
+    pub pins: Vec<PinRef>,
+    /// Driver node of each net, if the net has one
+    pub driver: Vec<Option<usize>>,
+    pub arcs: Vec<TimingArc>,
+    /// Arc indices into and out of each node, without the broken arcs
+    pub fanin: Vec<Vec<usize>>,
+    pub fanout: Vec<Vec<usize>>,
+    /// Nodes in topological order
+    pub order: Vec<usize>,
+    /// Arcs removed to break loops
+    pub broken: usize,
+    /// Nets with more than one output pin
+    pub multi_driver: usize,
+}
+
+pub struct TimingPath {
+    pub slack: f32,
+    /// Cell/pin name and arrival time, from the start point to the end
+    pub points: Vec<(String, f32)>,
+}
+
+pub struct TimingReport {
+    pub period: f32,
+    pub arrival: Vec<f32>,
+    pub required: Vec<f32>,
+    /// Required minus arrival, for each node (f32::MAX for nodes that
+    /// do not reach an end point)
+    pub slack: Vec<f32>,
+    pub endpoints: Vec<usize>,
+    /// Worst end point slack (0 if there are no end points)
+    pub worst_slack: f32,
+    /// Worst negative slack (0 if timing is met) and the total of the
+    /// negative end point slacks
+    pub wns: f32,
+    pub tns: f32,
+    pub failing: usize,
+    pub paths: Vec<TimingPath>,
+    /// Slack at the driver of each net, and 1 - slack / period clamped
+    /// to 0..1 (0 for nets without a driver)
+    pub net_slack: Vec<f32>,
+    pub criticality: Vec<f32>,
+}
+
+impl TimingReport {
+    pub fn report(&self) {
+        if self.endpoints.is_empty() {
+            println!("Timing: no end points");
+            return;
+        }
+        println!(
+            "Timing: period {}, worst slack {}, WNS {}, TNS {}, {} of {} end points failing",
+            self.period,
+            self.worst_slack,
+            self.wns,
+            self.tns,
+            self.failing,
+            self.endpoints.len()
+        );
+        // Long paths show the first and last few points
+        for (i, p) in self.paths.iter().enumerate() {
+            println!("  Path {}: slack {}, {} points", i, p.slack, p.points.len());
+            let n = p.points.len();
+            for (k, (name, arrival)) in p.points.iter().enumerate() {
+                if n > 20 && k == 10 {
+                    println!("    ...");
+                }
+                if n <= 20 || k < 10 || k >= n - 10 {
+                    println!("    {:10.3}  {}", arrival, name);
+                }
+            }
+        }
+    }
+}
+
+impl BookshelfCircuit {
+    /// Builds the timing graph from the nets and pin directions.
+    pub fn timing_graph(&self) -> TimingGraph {
+        let mut pin_base = Vec::with_capacity(self.cells.len());
+        let mut pins = Vec::new();
+        for c in 0..self.cells.len() {
+            pin_base.push(pins.len());
+            for k in 0..self.cells[c].pins.len() {
+                pins.push(PinRef {
+                    parent_cell: c,
+                    index: k,
+                });
+            }
+        }
+        let node = |pr: &PinRef| pin_base[pr.parent_cell] + pr.index;
+
+        let mut driver = vec![None; self.nets.len()];
+        let mut is_driver = vec![false; pins.len()];
+        // Outputs that do not drive their net are not cell arc ends either
+        let mut extra_output = vec![false; pins.len()];
+        let mut multi_driver = 0;
+        for (ni, net) in self.nets.iter().enumerate() {
+            let dir = |pr: &&PinRef| self.cells[pr.parent_cell].pins[pr.index].direction;
+            let d = net
+                .pins
+                .iter()
+                .find(|pr| dir(pr) == PinDirection::Output)
+                .or_else(|| net.pins.iter().find(|pr| dir(pr) == PinDirection::Bidirectional));
+            if let Some(pr) = d {
+                driver[ni] = Some(node(pr));
+                is_driver[node(pr)] = true;
+            }
+            let outputs: Vec<&PinRef> = net.pins.iter().filter(|pr| dir(pr) == PinDirection::Output).collect();
+            if outputs.len() > 1 {
+                multi_driver += 1;
+                for pr in &outputs[1..] {
+                    extra_output[node(pr)] = true;
+                }
+            }
+        }
+        if multi_driver > 0 {
+            println!("Warning: {} nets with more than one output pin; the first one drives each", multi_driver);
+        }
+
+        let mut arcs = Vec::new();
+        for (ni, net) in self.nets.iter().enumerate() {
+            if let Some(d) = driver[ni] {
+                for pr in &net.pins {
+                    if node(pr) != d {
+                        arcs.push(TimingArc { from: d, to: node(pr), net: Some(ni) });
+                    }
+                }
+            }
+        }
+        for c in 0..self.cells.len() {
+            if self.cells[c].terminal {
+                continue;
+            }
+            let n = self.cells[c].pins.len();
+            for i in pin_base[c]..pin_base[c] + n {
+                if is_driver[i] || extra_output[i] {
+                    continue;
+                }
+                for o in pin_base[c]..pin_base[c] + n {
+                    if is_driver[o] {
+                        arcs.push(TimingArc { from: i, to: o, net: None });
+                    }
+                }
+            }
+        }
+
+        let mut fanout = vec![Vec::new(); pins.len()];
+        for (a, arc) in arcs.iter().enumerate() {
+            fanout[arc.from].push(a);
+        }
+
+        // Depth first search, without recursion.  An arc to a node that
+        // is still on the stack closes a loop, and is dropped.  The
+        // reverse of the finishing order is a topological order.
+        let mut state = vec![0u8; pins.len()];
+        let mut order = Vec::with_capacity(pins.len());
+        let mut broken = 0;
+        for root in 0..pins.len() {
+            if state[root] != 0 {
+                continue;
+            }
+            let mut stack: Vec<(usize, usize)> = vec![(root, 0)];
+            state[root] = 1;
+            while let Some((v, k)) = stack.pop() {
+                if k < fanout[v].len() {
+                    stack.push((v, k + 1));
+                    let a = fanout[v][k];
+                    let w = arcs[a].to;
+                    if state[w] == 0 {
+                        state[w] = 1;
+                        stack.push((w, 0));
+                    } else if state[w] == 1 {
+                        fanout[v][k] = usize::MAX;
+                        broken += 1;
+                    }
+                } else {
+                    state[v] = 2;
+                    order.push(v);
+                }
+            }
+        }
+        order.reverse();
+        for list in &mut fanout {
+            list.retain(|a| *a != usize::MAX);
+        }
+        let mut fanin = vec![Vec::new(); pins.len()];
+        for list in &fanout {
+            for a in list {
+                fanin[arcs[*a].to].push(*a);
+            }
+        }
+
+        TimingGraph {
+            pin_base: pin_base,
+            pins: pins,
+            driver: driver,
+            arcs: arcs,
+            fanin: fanin,
+            fanout: fanout,
+            order: order,
+            broken: broken,
+            multi_driver: multi_driver,
+        }
+    }
+
+    /// Delay of each arc of the graph, for the current placement.
+    pub fn arc_delays(&self, g: &TimingGraph, params: &TimingParams) -> Vec<f32> {
+        // Total capacitance driven on each net
+        let mut load = vec![0.0; self.nets.len()];
+        for (ni, net) in self.nets.iter().enumerate() {
+            if g.driver[ni].is_some() {
+                load[ni] = params.wire_capacitance * self.net_wl(net)
+                    + params.pin_capacitance * (net.pins.len() - 1) as f32;
+            }
+        }
+        let mut delay = Vec::with_capacity(g.arcs.len());
+        for arc in &g.arcs {
+            match arc.net {
+                Some(ni) => {
+                    let (dx, dy) = self.pinloc(&g.pins[arc.from]);
+                    let (sx, sy) = self.pinloc(&g.pins[arc.to]);
+                    let len = (sx - dx).abs() + (sy - dy).abs();
+                    let r = params.wire_resistance * len;
+                    let c = params.wire_capacitance * len;
+                    delay.push(params.drive_resistance * load[ni] + r * (c * 0.5 + params.pin_capacitance));
+                }
+                None => delay.push(params.cell_delay),
+            }
+        }
+        delay
+    }
+
+    fn pin_label(&self, pr: &PinRef) -> String {
+        let c = &self.cells[pr.parent_cell];
+        let pin = &c.pins[pr.index];
+        if pin.name.is_empty() {
+            format!("{}/{} ({})", c.name, pr.index, pin.direction)
+        } else {
+            format!("{}/{} ({})", c.name, pin.name, pin.direction)
+        }
+    }
+
+    /// Static timing analysis of the current placement.
+    pub fn sta(&self, g: &TimingGraph, params: &TimingParams) -> TimingReport {
+        let n = g.pins.len();
+        let delay = self.arc_delays(g, params);
+
+        let mut arrival = vec![0.0; n];
+        let mut pred: Vec<Option<usize>> = vec![None; n];
+        for v in &g.order {
+            for a in &g.fanin[*v] {
+                let t = arrival[g.arcs[*a].from] + delay[*a];
+                if pred[*v].is_none() || t > arrival[*v] {
+                    arrival[*v] = t;
+                    pred[*v] = Some(*a);
+                }
+            }
+        }
+
+        let endpoints: Vec<usize> = (0..n).filter(|v| g.fanout[*v].is_empty() && !g.fanin[*v].is_empty()).collect();
+        let mut period = params.clock_period;
+        if period <= 0.0 {
+            period = endpoints.iter().map(|v| arrival[*v]).fold(0.0, f32::max);
+        }
+
+        let mut required = vec![f32::MAX; n];
+        for v in &endpoints {
+            required[*v] = period;
+        }
+        for v in g.order.iter().rev() {
+            for a in &g.fanout[*v] {
+                let to = g.arcs[*a].to;
+                if required[to] < f32::MAX {
+                    required[*v] = required[*v].min(required[to] - delay[*a]);
+                }
+            }
+        }
+        let slack: Vec<f32> = (0..n)
+            .map(|v| if required[v] < f32::MAX { required[v] - arrival[v] } else { f32::MAX })
+            .collect();
+
+        let mut worst_slack = if endpoints.is_empty() { 0.0 } else { f32::MAX };
+        let mut tns = 0.0;
+        let mut failing = 0;
+        for v in &endpoints {
+            worst_slack = worst_slack.min(slack[*v]);
+            if slack[*v] < 0.0 {
+                tns = tns + slack[*v];
+                failing += 1;
+            }
+        }
+
+        let mut net_slack = vec![f32::MAX; self.nets.len()];
+        let mut criticality = vec![0.0; self.nets.len()];
+        for ni in 0..self.nets.len() {
+            if let Some(d) = g.driver[ni] {
+                net_slack[ni] = slack[d];
+                if slack[d] < f32::MAX && period > 0.0 {
+                    criticality[ni] = (1.0 - slack[d] / period).max(0.0).min(1.0);
+                }
+            }
+        }
+
+        // Trace back from the worst end points
+        let mut worst = endpoints.clone();
+        worst.sort_by(|a, b| slack[*a].partial_cmp(&slack[*b]).unwrap());
+        let mut paths = Vec::new();
+        for e in worst.iter().take(params.paths) {
+            let mut points = Vec::new();
+            let mut v = *e;
+            loop {
+                points.push((self.pin_label(&g.pins[v]), arrival[v]));
+                match pred[v] {
+                    Some(a) => v = g.arcs[a].from,
+                    None => break,
+                }
+            }
+            points.reverse();
+            paths.push(TimingPath {
+                slack: slack[*e],
+                points: points,
+            });
+        }
+
+        if params.verbose {
+            println!(
+                "Timing graph: {} nodes, {} arcs, {} broken for loops, {} end points",
+                n,
+                g.arcs.len(),
+                g.broken,
+                endpoints.len()
+            );
+        }
+
+        TimingReport {
+            period: period,
+            arrival: arrival,
+            required: required,
+            slack: slack,
+            endpoints: endpoints,
+            worst_slack: worst_slack,
+            wns: worst_slack.min(0.0),
+            tns: tns,
+            failing: failing,
+            paths: paths,
+            net_slack: net_slack,
+            criticality: criticality,
+        }
+    }
+
+    /// Scales the net weights by the criticality, with the same
+    /// 1 + (max_weight - 1) c^2 as EdgeWeight::Criticality, so that
+    /// weights from the .wts file are kept (and calling it again scales
+    /// them again).  Returns the number of nets that were scaled up.
+    pub fn set_timing_weights(&mut self, rep: &TimingReport, max_weight: f32) -> usize {
+        let mut weighted = 0;
+        for (ni, net) in self.nets.iter_mut().enumerate() {
+            let c = rep.criticality[ni];
+            let scale = 1.0 + (max_weight - 1.0) * c * c;
+            net.weight = net.weight * scale;
+            if scale > 1.0 {
+                weighted += 1;
+            }
+        }
+        self.notes.push(format!("Timing weights: {} nets, max weight {}", weighted, max_weight));
+        weighted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestCircuit;
+
+    // in -> a -> b -> out, all at the origin, so that every arc has a
+    // delay of 1 (the cell delay, or the pin load on the driver)
+    fn chain() -> TestCircuit {
+        TestCircuit::new()
+            .terminal("in", 1.0, 1.0, 0.0, 0.0)
+            .cell("a", 1.0, 1.0, 0.0, 0.0)
+            .cell("b", 1.0, 1.0, 0.0, 0.0)
+            .terminal("out", 1.0, 1.0, 0.0, 0.0)
+            .pins("n0", &[("in", "O", 0.0, 0.0), ("a", "I", 0.0, 0.0)])
+            .pins("n1", &[("a", "O", 0.0, 0.0), ("b", "I", 0.0, 0.0)])
+    }
+
+    fn unit_params() -> TimingParams {
+        let mut params = TimingParams::new();
+        params.wire_resistance = 0.0;
+        params.wire_capacitance = 0.0;
+        params
+    }
+
+    // The timing node of the k-th pin on a cell (in nets file order)
+    fn node(bc: &BookshelfCircuit, g: &TimingGraph, cell: &str, k: usize) -> usize {
+        g.pin_base[bc.cell_map[cell]] + k
+    }
+
+    #[test]
+    fn chain_arrival_and_slack() {
+        let bc = chain().pins("n2", &[("b", "O", 0.0, 0.0), ("out", "I", 0.0, 0.0)]).build();
+        let g = bc.timing_graph();
+        assert_eq!(g.broken, 0);
+        let mut params = unit_params();
+        params.clock_period = 10.0;
+        let rep = bc.sta(&g, &params);
+
+        let out = node(&bc, &g, "out", 0);
+        assert_eq!(rep.arrival[node(&bc, &g, "a", 0)], 1.0);
+        assert_eq!(rep.arrival[node(&bc, &g, "b", 1)], 4.0);
+        assert_eq!(rep.arrival[out], 5.0);
+        assert_eq!(rep.endpoints, vec![out]);
+        assert_eq!(rep.slack[out], 5.0);
+        assert_eq!(rep.slack[node(&bc, &g, "in", 0)], 5.0);
+        assert_eq!(rep.worst_slack, 5.0);
+        assert_eq!(rep.failing, 0);
+
+        // With no period, the longest path sets it
+        let rep = bc.sta(&g, &unit_params());
+        assert_eq!(rep.period, 5.0);
+        assert_eq!(rep.worst_slack, 0.0);
+        assert_eq!(rep.paths[0].points.len(), 6);
+    }
+
+    #[test]
+    fn loop_is_broken() {
+        // The output of b also feeds back into a
+        let bc = chain()
+            .pins("n2", &[("b", "O", 0.0, 0.0), ("out", "I", 0.0, 0.0), ("a", "I", 0.0, 0.0)])
+            .build();
+        let g = bc.timing_graph();
+        assert_eq!(g.broken, 1);
+        let mut position = vec![0; g.pins.len()];
+        for (i, v) in g.order.iter().enumerate() {
+            position[*v] = i;
+        }
+        for list in &g.fanout {
+            for a in list {
+                assert!(position[g.arcs[*a].from] < position[g.arcs[*a].to]);
+            }
+        }
+
+        let mut params = unit_params();
+        params.clock_period = 10.0;
+        let rep = bc.sta(&g, &params);
+        // The extra sink on n2 adds a pin load to its driver
+        let out = node(&bc, &g, "out", 0);
+        assert_eq!(rep.arrival[out], 6.0);
+        assert_eq!(rep.slack[out], 4.0);
+        assert!(rep.arrival.iter().all(|t| t.is_finite()));
+    }
+
+    #[test]
+    fn second_output_is_not_a_driver() {
+        // b's second output also drives n1, next to a's
+        let bc = TestCircuit::new()
+            .terminal("in", 1.0, 1.0, 0.0, 0.0)
+            .cell("a", 1.0, 1.0, 0.0, 0.0)
+            .cell("b", 1.0, 1.0, 0.0, 0.0)
+            .terminal("out", 1.0, 1.0, 0.0, 0.0)
+            .pins("n0", &[("in", "O", 0.0, 0.0), ("a", "I", 0.0, 0.0)])
+            .pins("n1", &[("a", "O", 0.0, 0.0), ("b", "I", 0.0, 0.0), ("b", "O", 0.0, 0.0)])
+            .pins("n2", &[("b", "O", 0.0, 0.0), ("out", "I", 0.0, 0.0)])
+            .build();
+        let g = bc.timing_graph();
+        assert_eq!(g.multi_driver, 1);
+        assert_eq!(g.broken, 0);
+        // b has arcs from its input to the output on n2 only
+        let b = bc.cell_map["b"];
+        let cell_arcs: Vec<(usize, usize)> = g
+            .arcs
+            .iter()
+            .filter(|a| a.net.is_none() && g.pins[a.from].parent_cell == b)
+            .map(|a| (g.pins[a.from].index, g.pins[a.to].index))
+            .collect();
+        assert_eq!(cell_arcs, vec![(0, 2)]);
+    }
+
+    #[test]
+    fn no_end_points() {
+        let bc = TestCircuit::new().cell("a", 1.0, 1.0, 0.0, 0.0).build();
+        let rep = bc.sta(&bc.timing_graph(), &unit_params());
+        assert!(rep.endpoints.is_empty());
+        assert_eq!(rep.worst_slack, 0.0);
+        assert_eq!(rep.wns, 0.0);
+        assert_eq!(rep.failing, 0);
+    }
+
+    #[test]
+    fn timing_weights_scale() {
+        let mut bc = chain().pins("n2", &[("b", "O", 0.0, 0.0), ("out", "I", 0.0, 0.0)]).build();
+        let n0 = bc.net_map["n0"];
+        bc.nets[n0].weight = 2.0;
+        let rep = bc.sta(&bc.timing_graph(), &unit_params());
+        // Every net is on the critical path, with criticality 1 - slack / period
+        let c = rep.criticality[n0];
+        assert!(c > 0.0);
+        assert_eq!(bc.set_timing_weights(&rep, 3.0), 3);
+        assert_eq!(bc.nets[n0].weight, 2.0 * (1.0 + 2.0 * c * c));
+    }
+}